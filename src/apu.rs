@@ -1,17 +1,46 @@
+mod blip;
 mod channel;
 mod filter;
+mod mixer;
 
 use std::io;
 use std::fmt;
 use std::fs::File;
 
+use crate::apu::blip::BlipBuffer;
 use crate::apu::channel::{DMC, Noise, SquareWave, TriangleWave, Voice};
 use crate::apu::filter::{Filter, HighPassFilter, LowPassFilter};
-use crate::console::NES_APU_CHANNELS;
+use crate::apu::mixer::Mixer;
 use crate::mem::Memory;
+use crate::region::Region;
 use crate::serde;
 
-#[derive(PartialEq)]
+pub use crate::apu::mixer::{CHANNELS, Channel, MIXER_CHANNELS, MixMode};
+
+// How many audio samples are produced per second, unless the audio device
+// asks for something else
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// The NES hardware follows the DACs with a surprisingly involved circuit that
+// adds several low-pass and high-pass filters:
+//
+// * A first-order high-pass filter at 90 Hz
+// * Another first-order high-pass filter at 440 Hz
+// * A first-order low-pass filter at 14 kHz
+//
+// At low sample rates, the low-pass filter is moved down to below half the
+// sample rate, since nothing above that can be played anyway.
+fn output_filters(sample_rate: u32) -> [Box<dyn Filter>; 3] {
+    let sample_rate = sample_rate as f32;
+
+    [
+        Box::new(HighPassFilter::new_filter(sample_rate, 90.0)),
+        Box::new(HighPassFilter::new_filter(sample_rate, 440.0)),
+        Box::new(LowPassFilter::new_filter(sample_rate, (sample_rate * 0.45).min(14_000.0))),
+    ]
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum SequencerMode {
     FourStep,
     FiveStep,
@@ -38,11 +67,44 @@ pub struct APU {
     cycles: u64,
 
     sequencer_mode:  SequencerMode,
-    sequencer_value: u8,
+
+    // CPU cycles since the start of the current sequence
+    sequencer_cycle: u64,
+
+    // Writes to $4017 take effect 3 or 4 cycles later. This counts down to
+    // when the sequencer is reset to the mode that was written.
+    sequencer_reset_delay: u8,
+    next_sequencer_mode:   SequencerMode,
 
     irq: bool, // true = generates IRQ on the last tick of a 4-step sequence
 
-    filters: [Box<dyn Filter>; 3],
+    // The frame interrupt flag, which stays set until $4015 is read or IRQs
+    // are disabled through $4017
+    frame_irq: bool,
+
+    // How many cycles have already been run for the current CPU instruction,
+    // and what happened during them
+    cycles_ahead: u64,
+    caught_up:    StepResult,
+
+    region: Region,
+    sample_rate: u32,
+
+    mixer: Mixer,
+
+    // The output of the cartridge's sound chip, if it has one
+    expansion_audio: f32,
+
+    // Turns the left and right outputs, which change at the CPU's clock rate,
+    // into samples
+    blips: [BlipBuffer; 2],
+
+    filters: [[Box<dyn Filter>; 3]; 2],
+
+    // Each channel's output on every cycle since the scope was last taken,
+    // for the APU viewer. Only recorded when record_scope is set.
+    record_scope: bool,
+    scope: [Vec<u8>; 5],
 }
 
 impl Memory for APU {
@@ -105,12 +167,16 @@ impl Memory for APU {
 
         serde::encode_u64(output, self.cycles)?;
 
-        match self.sequencer_mode {
-            SequencerMode::FourStep => serde::encode_u8(output, 4)?,
-            SequencerMode::FiveStep => serde::encode_u8(output, 5)?,
-        };
-        serde::encode_u8(output, self.sequencer_value)?;
+        for mode in [self.sequencer_mode, self.next_sequencer_mode].iter() {
+            match mode {
+                SequencerMode::FourStep => serde::encode_u8(output, 4)?,
+                SequencerMode::FiveStep => serde::encode_u8(output, 5)?,
+            };
+        }
+        serde::encode_u64(output, self.sequencer_cycle)?;
+        serde::encode_u8(output, self.sequencer_reset_delay)?;
         serde::encode_u8(output, self.irq as u8)?;
+        serde::encode_u8(output, self.frame_irq as u8)?;
 
         // TODO filters
 
@@ -132,8 +198,16 @@ impl Memory for APU {
             _ => { },
         };
 
-        self.sequencer_value = serde::decode_u8(input)?;
+        match serde::decode_u8(input)? {
+            4 => { self.next_sequencer_mode = SequencerMode::FourStep },
+            5 => { self.next_sequencer_mode = SequencerMode::FiveStep },
+            _ => { },
+        };
+
+        self.sequencer_cycle = serde::decode_u64(input)?;
+        self.sequencer_reset_delay = serde::decode_u8(input)?;
         self.irq = serde::decode_u8(input)? != 0;
+        self.frame_irq = serde::decode_u8(input)? != 0;
 
         // TODO filters
 
@@ -143,45 +217,170 @@ impl Memory for APU {
 
 pub struct StepResult {
     pub trigger_irq: bool,
-    pub signal:      Option<f32>,
+
+    // The left and right samples produced. A single step produces at most
+    // one, but catching up over several cycles can produce more at high
+    // sample rates.
+    pub signals:     Vec<(f32, f32)>,
+
+    // The address of the next byte of the DMC's sample, which needs to be
+    // read and passed to fill_dmc_buffer
+    pub dmc_read:    Option<u16>,
+}
+
+impl StepResult {
+    fn new_step_result() -> Self {
+        Self {
+            trigger_irq: false,
+            signals:     Vec::new(),
+            dmc_read:    None,
+        }
+    }
+
+    // The DMC reads a byte at most every 432 cycles (8 bits at its fastest
+    // rate), far longer than any instruction, so there's never more than one
+    // read to keep
+    fn merge(&mut self, other: StepResult) {
+        self.signals.extend(other.signals);
+        self.dmc_read = self.dmc_read.or(other.dmc_read);
+    }
 }
 
 impl APU {
-    pub fn new_nes_apu() -> Self {
+    pub fn new_nes_apu(region: Region) -> Self {
         Self {
             square1:  SquareWave::new_square_wave(1),
             square2:  SquareWave::new_square_wave(2),
             triangle: TriangleWave::new_triangle_wave(),
-            noise:    Noise::new_noise_channel(),
-            dmc:      DMC::new_dmc_channel(),
+            noise:    Noise::new_noise_channel(region),
+            dmc:      DMC::new_dmc_channel(region),
 
             cycles: 0,
 
             sequencer_mode:  SequencerMode::FourStep,
-            sequencer_value: 0,
-
-            irq: false,
-
-            // The NES hardware follows the DACs with a surprisingly involved
-            // circuit that adds several low-pass and high-pass filters:
-            //
-            // * A first-order high-pass filter at 90 Hz
-            // * Another first-order high-pass filter at 440 Hz
-            // * A first-order low-pass filter at 14 kHz
-            filters: [
-                Box::new(HighPassFilter::new_filter(44_100.0, 90.0)),
-                Box::new(HighPassFilter::new_filter(44_100.0, 440.0)),
-                Box::new(LowPassFilter::new_filter(44_100.0, 14_000.0)),
+            sequencer_cycle: 0,
+
+            sequencer_reset_delay: 0,
+            next_sequencer_mode:   SequencerMode::FourStep,
+
+            irq: true,
+            frame_irq: false,
+
+            cycles_ahead: 0,
+            caught_up:    StepResult::new_step_result(),
+
+            region: region,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+
+            mixer: Mixer::new_mixer(),
+            expansion_audio: 0.0,
+
+            blips: [
+                BlipBuffer::new_blip_buffer(region.cpu_clock_rate(), DEFAULT_SAMPLE_RATE as f64),
+                BlipBuffer::new_blip_buffer(region.cpu_clock_rate(), DEFAULT_SAMPLE_RATE as f64),
             ],
+            filters: [output_filters(DEFAULT_SAMPLE_RATE), output_filters(DEFAULT_SAMPLE_RATE)],
+
+            record_scope: false,
+            scope: Default::default(),
+        }
+    }
+
+    // Changes the rate that samples are produced at, to whatever the audio
+    // device ended up playing at
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.blips = [
+            BlipBuffer::new_blip_buffer(self.region.cpu_clock_rate(), sample_rate as f64),
+            BlipBuffer::new_blip_buffer(self.region.cpu_clock_rate(), sample_rate as f64),
+        ];
+        self.filters = [output_filters(sample_rate), output_filters(sample_rate)];
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // The volume, panning and muting of each channel
+    pub fn mixer(&mut self) -> &mut Mixer {
+        &mut self.mixer
+    }
+
+    // Sets the output of the cartridge's sound chip, which is mixed in with
+    // the APU's channels from the next step
+    pub fn set_expansion_audio(&mut self, level: f32) {
+        self.expansion_audio = level;
+    }
+
+    pub fn set_scope_recording(&mut self, record: bool) {
+        self.record_scope = record;
+
+        for levels in self.scope.iter_mut() {
+            levels.clear();
         }
     }
 
+    // Each channel's output on every cycle since this was last called, in
+    // the same order as CHANNELS
+    pub fn take_scope(&mut self) -> [Vec<u8>; 5] {
+        std::mem::take(&mut self.scope)
+    }
+
+    // A description of each channel's state, in the same order as CHANNELS
+    pub fn channel_registers(&self) -> [Vec<String>; 5] {
+        [
+            self.square1.registers(),
+            self.square2.registers(),
+            self.triangle.registers(),
+            self.noise.registers(),
+            self.dmc.registers(),
+        ]
+    }
+
     pub fn reset(&mut self) {
         self.square1.reset();
         self.square2.reset();
         self.triangle.reset();
         self.noise.reset();
         self.dmc.reset();
+
+        // The frame counter keeps its mode, but starts the sequence again
+        self.sequencer_cycle = 0;
+        self.sequencer_reset_delay = 0;
+        self.frame_irq = false;
+    }
+
+    // Like the PPU, the APU is normally stepped after the CPU has finished an
+    // instruction, but needs to catch up to the exact cycle when the CPU
+    // accesses one of its registers, for the frame IRQ and $4017 timing to
+    // be right.
+    pub fn catch_up(&mut self, cpu_cycles: u64) {
+        while self.cycles_ahead < cpu_cycles {
+            let res = self.step();
+            self.caught_up.merge(res);
+            self.cycles_ahead += 1;
+        }
+    }
+
+    // Returns the number of cycles already run for the current CPU
+    // instruction, and what happened during them, so the console can run the
+    // rest.
+    pub fn take_caught_up(&mut self) -> (u64, StepResult) {
+        let mut res = std::mem::replace(&mut self.caught_up, StepResult::new_step_result());
+        let cycles = self.cycles_ahead;
+
+        // The CPU may have acknowledged an interrupt since it was raised, so
+        // only the current state of the IRQ line counts
+        res.trigger_irq = self.frame_irq || self.dmc.irq_flag;
+
+        self.cycles_ahead = 0;
+
+        (cycles, res)
+    }
+
+    // Called with the byte the DMC asked for in the last step
+    pub fn fill_dmc_buffer(&mut self, val: u8) {
+        self.dmc.fill_buffer(val);
     }
 
     //  $4015   if-d nt21   DMC IRQ, frame IRQ, length counter statuses
@@ -204,13 +403,21 @@ impl APU {
             rv |= 8;
         }
 
-        if self.dmc.buffer != 0 {
+        if self.dmc.active() {
             rv |= 16;
         }
 
-        // TODO
-        // DMC IRQ
-        // frame IRQ
+        if self.frame_irq {
+            rv |= 64;
+        }
+
+        if self.dmc.irq_flag {
+            rv |= 128;
+        }
+
+        // Reading the status clears the frame interrupt flag, but not the
+        // DMC's
+        self.frame_irq = false;
 
         rv
     }
@@ -219,19 +426,33 @@ impl APU {
         // MI-- ----       mode, IRQ disable
 
         // Mode (0 = 4-step, 1 = 5-step)
-        self.sequencer_mode = if (val & 0b1000_0000) == 0 {
+        self.next_sequencer_mode = if (val & 0b1000_0000) == 0 {
             SequencerMode::FourStep
         } else {
             SequencerMode::FiveStep
         };
 
         // IRQ inhibit flag. If this is set, we DON'T want to generate an IRQ.
-        // Hello, double-negatives.
+        // Hello, double-negatives. Setting it also clears the flag straight
+        // away.
         self.irq = (val & 0b0100_0000) == 0;
+        if !self.irq {
+            self.frame_irq = false;
+        }
 
-        info!("sequencer mode: {}", self.sequencer_mode);
+        info!("sequencer mode: {}", self.next_sequencer_mode);
         info!("irq generation: {}", self.irq);
 
+        // The sequencer is reset 3 CPU cycles after the write if it happens
+        // on an APU cycle (every other CPU cycle), and 4 cycles after if it
+        // happens between APU cycles.
+        self.sequencer_reset_delay = if self.cycles % 2 == 0 { 3 } else { 4 };
+    }
+
+    fn reset_sequencer(&mut self) {
+        self.sequencer_mode = self.next_sequencer_mode;
+        self.sequencer_cycle = 0;
+
         // If the mode flag is clear, the 4-step sequence is selected,
         // otherwise the 5-step sequence is selected and the sequencer is
         // immediately clocked once.
@@ -242,19 +463,25 @@ impl APU {
         }
     }
 
+    fn set_frame_irq(&mut self) {
+        if self.irq {
+            self.frame_irq = true;
+        }
+    }
+
     fn write_control(&mut self, val: u8) {
         self.square1.enabled  = (val & 0b0000_0001) != 0;
         self.square2.enabled  = (val & 0b0000_0010) != 0;
         self.triangle.enabled = (val & 0b0000_0100) != 0;
         self.noise.enabled    = (val & 0b0000_1000) != 0;
-        self.dmc.enabled      = (val & 0b0001_0000) != 0;
+        self.dmc.set_enabled((val & 0b0001_0000) != 0);
 
         debug!("s1={}, s2={}, t={}, n={}, dmc={}",
                self.square1.enabled,
                self.square2.enabled,
                self.triangle.enabled,
                self.noise.enabled,
-               self.dmc.enabled);
+               self.dmc.active());
 
         if !self.square1.enabled {
             self.square1.length_value = 0;
@@ -271,59 +498,17 @@ impl APU {
         if !self.noise.enabled {
             self.noise.length_value = 0;
         }
-
-        if !self.dmc.enabled {
-            //self.dmc.length_value = 0;
-        }
     }
 
-    fn signal(&mut self) -> f32 {
-        // Digital-to-Analog conversion
-
-        let sq1 = if *NES_APU_CHANNELS & 1 != 0 {
-            self.square1.signal() as usize
-        } else {
-            0
-        };
-
-        let sq2 = if *NES_APU_CHANNELS & 2 != 0 {
-            self.square2.signal() as usize
-        } else {
-            0
-        };
-
-        let pulse_val = 95.88 / (100.0
-                                 + (8128.0 / (  sq1 as f32
-                                              + sq2 as f32)));
-
-        let tr = if *NES_APU_CHANNELS & 4 != 0 {
-            self.triangle.signal() as usize
-        } else {
-            0
-        };
-
-        let n = if *NES_APU_CHANNELS & 8 != 0 {
-            self.noise.signal() as usize
-        } else {
-            0
-        };
-
-        let dmc = if *NES_APU_CHANNELS & 16 != 0 {
-            self.dmc.signal() as usize
-        } else {
-            0
-        };
-
-        let tnd_val = 159.79 / (100.0
-                                + (1.0 / (  (tr as f32 / 8227.0)
-                                          + (n as f32 / 12241.0)
-                                          + (dmc as f32 / 22638.0))));
-
-        let signal = pulse_val + tnd_val;
-
-        self.filters
-            .iter_mut()
-            .fold(signal, |sig, filter| filter.process(sig))
+    // What each channel is sending to its DAC
+    fn levels(&self) -> [u8; 5] {
+        [
+            self.square1.signal(),
+            self.square2.signal(),
+            self.triangle.signal(),
+            self.noise.signal(),
+            self.dmc.signal(),
+        ]
     }
 
     fn step_envelopes(&mut self) {
@@ -346,10 +531,11 @@ impl APU {
     }
 
     fn step_timers(&mut self) {
-        // The triangle channel ticks on every cycle. The other channels tick on
-        // every other cycle.
+        // The triangle channel and DMC tick on every cycle. The other channels
+        // tick on every other cycle.
 
         self.triangle.step_timer();
+        self.dmc.step_timer();
 
         if self.cycles % 2 == 0 {
             self.square1.step_timer();
@@ -358,97 +544,327 @@ impl APU {
         }
     }
 
-    fn step_sequencer(&mut self, res: &mut StepResult) {
+    // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    //
+    // The sequencer is stepped on exact CPU cycles, roughly 240 times a
+    // second in the 4-step mode, or 192 in the 5-step mode (200 and 160 on
+    // PAL). The last step of the 4-step sequence sets the frame interrupt
+    // flag, on three cycles in a row, so clearing it by reading $4015 on the
+    // first two doesn't stick.
+    //
+    // mode 0: 4-step          mode 1: 5-step
+    // ---------------------   ---------------------
+    //     - - - f                 - - - - -   IRQ flag (never set)
+    //     - l - l                 l - l - -   length counter + sweep
+    //     e e e e                 e e e e -   envelope + linear counter
+    fn step_sequencer(&mut self) {
+        if self.sequencer_reset_delay > 0 {
+            self.sequencer_reset_delay -= 1;
+
+            if self.sequencer_reset_delay == 0 {
+                self.reset_sequencer();
+                return;
+            }
+        }
+
+        self.sequencer_cycle += 1;
+
+        let steps = self.region.frame_counter_steps();
+        let cycle = self.sequencer_cycle;
+
+        if cycle == steps[0] || cycle == steps[2] {
+            self.step_envelopes();
+            return;
+        }
+
+        if cycle == steps[1] {
+            self.step_envelopes();
+            self.step_sweeps();
+            self.step_lengths();
+            return;
+        }
+
         match self.sequencer_mode {
-            SequencerMode::FiveStep => {
-                self.sequencer_value = (self.sequencer_value + 1) % 5;
-
-                // mode 1: 5-step
-                // ---------------------------------------
-                //     - - - - -   IRQ flag (never set)
-                //     l - l - -   length counter + sweep
-                //     e e e e -   envelope + linear counter
-                match self.sequencer_value {
-                    1 | 3 => {
-                        self.step_envelopes();
-                        self.step_sweeps();
-                        self.step_lengths();
-                    },
-                    0 | 2 => {
-                        self.step_envelopes();
-                    },
-                    _ => { },
+            SequencerMode::FourStep => {
+                if cycle == steps[3] - 1 {
+                    self.set_frame_irq();
+                } else if cycle == steps[3] {
+                    self.step_envelopes();
+                    self.step_sweeps();
+                    self.step_lengths();
+                    self.set_frame_irq();
+                } else if cycle == steps[3] + 1 {
+                    self.set_frame_irq();
+                    self.sequencer_cycle = 0;
                 }
             },
-            SequencerMode::FourStep => {
-                self.sequencer_value = (self.sequencer_value + 1) % 4;
-
-                // mode 0: 4-step
-                // ---------------------------------------
-                //     - - - f     IRQ flag
-                //     - l - l     length counter + sweep
-                //     e e e e     envelope + linear counter
-                match self.sequencer_value {
-                    0 | 2 => {
-                        self.step_envelopes();
-                    },
-                    1 => {
-                        self.step_envelopes();
-                        self.step_sweeps();
-                        self.step_lengths();
-                    },
-                    3 => {
-                        self.step_envelopes();
-                        self.step_sweeps();
-                        self.step_lengths();
-
-                        if self.irq {
-                            res.trigger_irq = true;
-                        }
-                    },
-                    _ => { },
+            SequencerMode::FiveStep => {
+                if cycle == steps[4] {
+                    self.step_envelopes();
+                    self.step_sweeps();
+                    self.step_lengths();
+                } else if cycle == steps[4] + 1 {
+                    self.sequencer_cycle = 0;
                 }
-            }
+            },
         }
     }
 
     pub fn step(&mut self) -> StepResult {
-        let mut res = StepResult{
-            trigger_irq: false,
-            signal:      None,
-        };
+        let mut res = StepResult::new_step_result();
 
-        let cycle1 = self.cycles as f32;
         self.cycles += 1;
-        let cycle2 = self.cycles as f32;
 
         self.step_timers();
+        self.step_sequencer();
+
+        // Both interrupts hold the IRQ line until they're acknowledged
+        res.dmc_read = self.dmc.sample_request();
+        res.trigger_irq = self.frame_irq || self.dmc.irq_flag;
 
-        // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
-        //
-        // The sequencer is stepped at a rate of 240Hz or 192Hz, depending on
-        // the mode. The way this happens is that we check if the previous
-        // cycle and the current cycle crosses a multiple of 240. And if it
-        // does, the sequencer is clocked.
-        //
-        // The five-step sequence is clocked at 192Hz, but this is achieved by
-        // doing nothing on one of the steps, as 192 is 4/5 of 240.
-        let sequencer_rate = 1789773.0 / 240.0;
-        let f1 = (cycle1 / sequencer_rate) as u32;
-        let f2 = (cycle2 / sequencer_rate) as u32;
-        if f1 != f2 {
-            self.step_sequencer(&mut res);
+        let levels = self.levels();
+
+        if self.record_scope {
+            for (scope, level) in self.scope.iter_mut().zip(levels.iter()) {
+                scope.push(*level);
+            }
         }
 
-        // The sampling rate is 44.1kHz. The way we do this is the same as the
-        // sequencer (see explanation above).
-        let sample_rate = 1789773.0 / 44100.0;
-        let s1 = (cycle1 / sample_rate) as u32;
-        let s2 = (cycle2 / sample_rate) as u32;
-        if s1 != s2 {
-            res.signal = Some(self.signal());
+        // The output is band-limited down to the sample rate, and only then
+        // run through the filters
+        let (left, right) = self.mixer.mix(levels, self.expansion_audio);
+        let left = self.blips[0].clock(left);
+        let right = self.blips[1].clock(right);
+
+        if let (Some(left), Some(right)) = (left, right) {
+            let filter = |filters: &mut [Box<dyn Filter>; 3], sample: f32| {
+                filters.iter_mut().fold(sample, |sig, filter| filter.process(sig))
+            };
+
+            let left = filter(&mut self.filters[0], left);
+            let right = filter(&mut self.filters[1], right);
+
+            res.signals.push((left, right));
         }
 
         return res;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Which frame counter clocks happened on each cycle, worked out from the
+    // triangle channel, whose linear counter is clocked by every quarter
+    // frame and whose length counter is clocked by every half frame
+    #[derive(Debug, PartialEq)]
+    enum Clock {
+        Quarter,
+        Half,
+    }
+
+    fn new_apu() -> APU {
+        let mut apu = APU::new_nes_apu(Region::NTSC);
+
+        // Enable the triangle, with a long length and the biggest linear
+        // counter, so that neither runs out
+        apu.write(0x4015, 0b0000_0100);
+        apu.write(0x4008, 0x7f);
+        apu.write(0x400b, 0x08);
+        apu
+    }
+
+    // Steps the APU until `cycles' cycles have run since it was created,
+    // returning the cycles that frame counter clocks happened on
+    fn run(apu: &mut APU, cycles: u64) -> Vec<(u64, Clock)> {
+        let mut clocks = Vec::new();
+
+        while apu.cycles < cycles {
+            let linear = apu.triangle.counter_value;
+            let length = apu.triangle.length_value;
+
+            apu.step();
+
+            if apu.triangle.length_value != length {
+                clocks.push((apu.cycles, Clock::Half));
+            } else if apu.triangle.counter_value != linear {
+                clocks.push((apu.cycles, Clock::Quarter));
+            }
+        }
+
+        clocks
+    }
+
+    // The cycles that the frame interrupt flag is set on
+    fn run_irq(apu: &mut APU, cycles: u64) -> Vec<u64> {
+        let mut set = Vec::new();
+
+        while apu.cycles < cycles {
+            apu.step();
+
+            if apu.frame_irq {
+                set.push(apu.cycles);
+            }
+        }
+
+        set
+    }
+
+    #[test]
+    fn test_four_step_clocks() {
+        let mut apu = new_apu();
+
+        assert_eq!(run(&mut apu, 40_000), vec![
+            (7457,  Clock::Quarter),
+            (14913, Clock::Half),
+            (22371, Clock::Quarter),
+            (29829, Clock::Half),
+
+            // The sequence starts again after 29830 cycles
+            (29830 + 7457, Clock::Quarter),
+        ]);
+    }
+
+    #[test]
+    fn test_four_step_irq() {
+        let mut apu = new_apu();
+
+        assert_eq!(run_irq(&mut apu, 29827), vec![]);
+
+        // The flag is set on three cycles in a row, so reading $4015 on the
+        // first of them doesn't clear it for good
+        apu.step();
+        assert!(apu.frame_irq);
+        assert_eq!(apu.read(0x4015) & 0x40, 0x40);
+        assert!(!apu.frame_irq);
+
+        apu.step();
+        assert!(apu.frame_irq);
+        apu.read(0x4015);
+        apu.step();
+        assert!(apu.frame_irq);
+        apu.read(0x4015);
+
+        // And once it's cleared after the last of them, it stays cleared
+        // until the next sequence
+        assert_eq!(run_irq(&mut apu, 29830 + 29827), vec![]);
+        assert_eq!(run_irq(&mut apu, 29830 + 29828), vec![29830 + 29828]);
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut apu = new_apu();
+
+        run_irq(&mut apu, 29829);
+        assert!(apu.frame_irq);
+
+        // Setting the inhibit flag clears the flag straight away, and stops
+        // it being set again
+        apu.write(0x4017, 0b0100_0000);
+        assert!(!apu.frame_irq);
+        assert_eq!(run_irq(&mut apu, 100_000), vec![]);
+    }
+
+    // The write happens on an even cycle, so the sequencer is reset 3 cycles
+    // later, and switching to the 5-step sequence clocks everything straight
+    // away
+    #[test]
+    fn test_five_step_clocks() {
+        let mut apu = new_apu();
+
+        run(&mut apu, 100);
+        apu.write(0x4017, 0b1000_0000);
+
+        assert_eq!(run(&mut apu, 100 + 3 + 45_000), vec![
+            (103,         Clock::Half),
+            (103 + 7457,  Clock::Quarter),
+            (103 + 14913, Clock::Half),
+            (103 + 22371, Clock::Quarter),
+            (103 + 37281, Clock::Half),
+
+            // The sequence starts again after 37282 cycles
+            (103 + 37282 + 7457, Clock::Quarter),
+        ]);
+
+        assert!(!apu.frame_irq);
+    }
+
+    // On an odd cycle, the reset waits a cycle longer. Going back to the
+    // 4-step sequence doesn't clock anything.
+    #[test]
+    fn test_reset_delay_odd_cycle() {
+        let mut apu = new_apu();
+
+        run(&mut apu, 101);
+        apu.write(0x4017, 0b1000_0000);
+        assert_eq!(run(&mut apu, 110), vec![(105, Clock::Half)]);
+
+        run(&mut apu, 201);
+        apu.write(0x4017, 0);
+        assert_eq!(run(&mut apu, 205 + 7457), vec![(205 + 7457, Clock::Quarter)]);
+    }
+
+    // Catching up over several cycles keeps every sample, however high the
+    // sample rate is
+    #[test]
+    fn test_catch_up_keeps_samples() {
+        let mut apu = new_apu();
+        apu.set_sample_rate(1_000_000);
+
+        apu.catch_up(7);
+        let (cycles, res) = apu.take_caught_up();
+
+        assert_eq!(cycles, 7);
+        assert!(res.signals.len() >= 3);
+    }
+
+    // Runs the APU, reading 0x55 for every byte the DMC asks for, and
+    // returns whether it raised an IRQ
+    fn run_dmc(apu: &mut APU, cycles: u64) -> bool {
+        let mut irq = false;
+
+        for _ in 0 .. cycles {
+            let res = apu.step();
+
+            if res.dmc_read.is_some() {
+                apu.fill_dmc_buffer(0x55);
+            }
+
+            irq |= res.trigger_irq;
+        }
+
+        irq
+    }
+
+    #[test]
+    fn test_dmc_irq() {
+        let mut apu = APU::new_nes_apu(Region::NTSC);
+
+        // Inhibit the frame IRQ, and play a 17 byte sample at the fastest
+        // rate, with the IRQ on
+        apu.write(0x4017, 0x40);
+        apu.write(0x4010, 0x8f);
+        apu.write(0x4013, 0x01);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.read(0x4015), 0x10);
+
+        // Each byte takes 8 * 54 cycles to play, and the next is read as soon
+        // as the buffer empties
+        assert!(!run_dmc(&mut apu, 15 * 8 * 54));
+        assert_eq!(apu.read(0x4015) & 0x80, 0);
+
+        assert!(run_dmc(&mut apu, 2 * 8 * 54));
+        assert_eq!(apu.read(0x4015), 0x80);
+
+        // Reading $4015 doesn't clear it, but writing it does
+        assert_eq!(apu.read(0x4015), 0x80);
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.read(0x4015), 0x00);
+        assert!(!run_dmc(&mut apu, 1));
+
+        // Enabling the DMC starts the sample again
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.read(0x4015), 0x10);
+    }
+}
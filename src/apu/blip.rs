@@ -0,0 +1,210 @@
+// Band-limited synthesis, in the style of blip_buf.
+//
+// http://www.slack.net/~ant/bl-synth/
+//
+// The APU's output changes at up to 1.79MHz, and just picking out every 40th
+// or so value to get down to 44.1kHz folds all of the harmonics above half
+// the output rate back down as audible whistles. Instead, each change in the
+// output is treated as a step, and a band-limited step (a windowed sinc
+// impulse, summed up) is mixed into the output at exactly the right
+// fractional position between output samples. Steps only happen when the
+// output changes, so this is much cheaper than filtering at the full rate.
+//
+// Like blip_buf, the steps are added up in fixed point, with each one adding
+// exactly its height to the running sum. In floating point, the rounding
+// errors would slowly build up into a drifting offset over a long session.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// How many output samples each step is spread over
+const TAPS: usize = 16;
+
+// How many fractional positions between output samples the kernel is worked
+// out for
+const PHASES: usize = 64;
+
+// The kernel's cutoff, as a fraction of half the output rate. Leaving some
+// room below the Nyquist frequency lets the window roll off before it.
+const CUTOFF: f64 = 0.9;
+
+// Fractional bits in the kernel's taps, and in amplitudes. Amplitudes are
+// all small (the APU's mix is at most about 1.0), so this leaves plenty of
+// headroom.
+const KERNEL_BITS: u32 = 15;
+const AMPLITUDE_BITS: u32 = 20;
+
+fn to_fixed(amplitude: f32) -> i32 {
+    (amplitude as f64 * (1 << AMPLITUDE_BITS) as f64).round() as i32
+}
+
+pub struct BlipBuffer {
+    // The impulse for each phase, with each one adding up to 1
+    kernel: Vec<[i32; TAPS]>,
+
+    // How far the input moves along, in output samples, every clock
+    ratio: f64,
+
+    // Where the input is, in output samples, relative to the front of the
+    // buffer
+    time: f64,
+
+    // Impulses waiting to be summed into output samples
+    buffer: VecDeque<i32>,
+
+    // The running sum of every impulse that's been output, which is the
+    // output itself
+    integrator: i32,
+
+    // The last input value, to work out how big each step is
+    amplitude: i32,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn kernel() -> Vec<[i32; TAPS]> {
+    let half = TAPS as f64 / 2.0;
+
+    (0 ..= PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut taps = [0.0f64; TAPS];
+
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 - half - offset;
+
+                // Blackman window
+                let w = 0.42
+                      + 0.5 * (2.0 * PI * x / TAPS as f64).cos()
+                      + 0.08 * (4.0 * PI * x / TAPS as f64).cos();
+
+                *tap = CUTOFF * sinc(CUTOFF * x) * w;
+            }
+
+            let sum: f64 = taps.iter().sum();
+            let mut fixed = [0; TAPS];
+
+            for (tap, fixed) in taps.iter().zip(fixed.iter_mut()) {
+                *fixed = (tap / sum * (1 << KERNEL_BITS) as f64).round() as i32;
+            }
+
+            // Whatever the rounding lost goes in the middle
+            fixed[TAPS / 2] += (1 << KERNEL_BITS) - fixed.iter().sum::<i32>();
+
+            fixed
+        })
+        .collect()
+}
+
+impl BlipBuffer {
+    pub fn new_blip_buffer(clock_rate: f64, sample_rate: f64) -> Self {
+        Self {
+            kernel: kernel(),
+            ratio: sample_rate / clock_rate,
+            time: 0.0,
+            buffer: vec![0; TAPS + 1].into(),
+            integrator: 0,
+            amplitude: 0,
+        }
+    }
+
+    // Takes the input for one clock, and returns an output sample if one is
+    // due. The output lags TAPS / 2 samples behind the input.
+    pub fn clock(&mut self, amplitude: f32) -> Option<f32> {
+        let amplitude = to_fixed(amplitude);
+        let delta = amplitude - self.amplitude;
+
+        if delta != 0 {
+            self.amplitude = amplitude;
+
+            let phase = (self.time.fract() * PHASES as f64) as usize;
+            let start = self.time as usize;
+
+            // The taps are rounded again here, so the middle one makes up the
+            // difference, and the whole step adds up to exactly `delta'
+            let mut remaining = delta;
+
+            for (i, tap) in self.kernel[phase].iter().enumerate() {
+                if i != TAPS / 2 {
+                    let val = ((delta as i64 * *tap as i64) >> KERNEL_BITS) as i32;
+                    self.buffer[start + i] += val;
+                    remaining -= val;
+                }
+            }
+
+            self.buffer[start + TAPS / 2] += remaining;
+        }
+
+        self.time += self.ratio;
+
+        if self.time < 1.0 {
+            return None;
+        }
+
+        // Nothing can be added in front of where the input is now, so the
+        // sample at the front of the buffer is done
+        self.time -= 1.0;
+        self.integrator += self.buffer.pop_front().unwrap();
+        self.buffer.push_back(0);
+
+        Some(self.integrator as f32 / (1 << AMPLITUDE_BITS) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+    const SAMPLE_RATE: f64 = 44_100.0;
+
+    fn run(blip: &mut BlipBuffer, amplitude: f32, clocks: usize) -> Vec<f32> {
+        (0 .. clocks).filter_map(|_| blip.clock(amplitude)).collect()
+    }
+
+    #[test]
+    fn test_constant_settles() {
+        let mut blip = BlipBuffer::new_blip_buffer(CLOCK_RATE, SAMPLE_RATE);
+        let output = run(&mut blip, 0.375, 10_000);
+
+        assert!(output.len() > TAPS * 2);
+        assert_eq!(output[output.len() - TAPS ..], [0.375; TAPS]);
+    }
+
+    // However the step is spread out, the samples it adds up to come to the
+    // height of the step, at every fractional position
+    #[test]
+    fn test_step_sums_to_height() {
+        for offset in 0 .. 50 {
+            let mut blip = BlipBuffer::new_blip_buffer(CLOCK_RATE, SAMPLE_RATE);
+            run(&mut blip, 0.0, 1000 + offset);
+
+            let output = run(&mut blip, 0.6, 2000);
+            let sum: f32 = output.windows(2).map(|w| w[1] - w[0]).sum::<f32>() + output[0];
+
+            // Amplitudes are only kept to 20 bits of precision
+            assert!((sum - 0.6).abs() < 1e-6, "offset {}: {}", offset, sum);
+        }
+    }
+
+    // A long run of steps up and down, of awkward heights, comes back to
+    // exactly where it started
+    #[test]
+    fn test_no_drift() {
+        let mut blip = BlipBuffer::new_blip_buffer(CLOCK_RATE, SAMPLE_RATE);
+
+        for i in 0 .. 200_000 {
+            let amplitude = if i % 2 == 0 { 0.123_456_7 } else { 0.0 };
+            run(&mut blip, amplitude, 37);
+        }
+
+        let output = run(&mut blip, 0.0, 1000);
+        assert_eq!(*output.last().unwrap(), 0.0);
+    }
+}
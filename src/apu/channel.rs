@@ -10,4 +10,20 @@ pub use crate::apu::channel::dmc::DMC;
 
 pub trait Voice {
     fn signal(&self) -> u8;
+
+    // A line of text for each part of the channel's state, for the APU viewer
+    fn registers(&self) -> Vec<String>;
+}
+
+// How an envelope is set up, and the volume coming out of it
+fn describe_envelope(enabled: bool, looping: bool, period: u8, volume: u8, constant: u8) -> String {
+    if enabled {
+        format!("volume {} (envelope p{}{})", volume, period, if looping { " loop" } else { "" })
+    } else {
+        format!("volume {} (constant)", constant)
+    }
+}
+
+fn describe_length(enabled: bool, value: u8) -> String {
+    format!("length {}{}", value, if enabled { "" } else { " (halted)" })
 }
@@ -1,76 +1,395 @@
-// TODO
-// This is incomplete, because I need to figure out how to let this module
-// access VRAM from the mapper.
+// https://wiki.nesdev.com/w/index.php/APU_DMC
+//
+// The delta modulation channel plays 1-bit delta encoded samples, read from
+// the CPU's address space one byte at a time. Each bit moves the output level
+// up or down by 2.
+//
+// The DMC can't read memory itself, so whenever its sample buffer is empty
+// and there's more of the sample to play, it asks for the next byte, which
+// the console reads through the CPU bus, stalling the CPU while it does, and
+// hands back with fill_buffer.
 
 use std::io;
 use std::fs::File;
 
 use crate::apu::channel::Voice;
 use crate::mem::Memory;
-
-const TIMER_TABLE: [u16; 16] = [
-    0x01AC, 0x017C, 0x0154, 0x0140,
-    0x011E, 0x00FE, 0x00E2, 0x00D6,
-    0x00BE, 0x00A0, 0x008E, 0x0080,
-    0x006A, 0x0054, 0x0048, 0x0036
-];
+use crate::region::Region;
+use crate::serde;
 
 pub struct DMC {
-    pub enabled: bool,
-
-    pub buffer: u8,
+    irq_enabled: bool,
+    pub irq_flag: bool,
+    dmc_loop: bool,
 
     timer_period: u16,
-    //timer_value: u16,
+    timer_value: u16,
+    timer_table: &'static [u16; 16],
+
+    // The 7-bit output level, which is what the DAC receives
+    output_level: u8,
+
+    // Where the sample starts and how many bytes it is, from $4012 and $4013
+    sample_address: u16,
+    sample_length: u16,
+
+    // The memory reader's position in the sample that's currently playing
+    current_address: u16,
+    bytes_remaining: u16,
+
+    // The next byte of the sample, waiting to go into the shift register
+    buffer: Option<u8>,
+
+    // The output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
 }
 
 impl Voice for DMC {
     fn signal(&self) -> u8 {
-        if !self.enabled {
-            return 0;
+        self.output_level
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if self.dmc_loop {
+            flags.push("loop");
+        }
+        if self.irq_enabled {
+            flags.push("irq");
+        }
+        if self.irq_flag {
+            flags.push("irq pending");
         }
 
-        self.buffer
+        vec![
+            format!("period {}", self.timer_period),
+            format!("level {}", self.output_level),
+            format!("sample {:04x} {} bytes", self.sample_address, self.sample_length),
+            format!("reading {:04x} {} left", self.current_address, self.bytes_remaining),
+            flags.join(" "),
+        ]
     }
 }
 
 impl Memory for DMC {
-    fn save(&self, _output: &mut File) -> io::Result<()> { Ok(()) }
-    fn load(&mut self, _input: &mut File) -> io::Result<()> { Ok(()) }
+    fn save(&self, output: &mut File) -> io::Result<()> {
+        serde::encode_u8(output, self.irq_enabled as u8)?;
+        serde::encode_u8(output, self.irq_flag as u8)?;
+        serde::encode_u8(output, self.dmc_loop as u8)?;
+        serde::encode_u16(output, self.timer_period)?;
+        serde::encode_u16(output, self.timer_value)?;
+        serde::encode_u8(output, self.output_level)?;
+        serde::encode_u16(output, self.sample_address)?;
+        serde::encode_u16(output, self.sample_length)?;
+        serde::encode_u16(output, self.current_address)?;
+        serde::encode_u16(output, self.bytes_remaining)?;
+
+        // The buffer is saved as its value, plus one, with zero meaning that
+        // it's empty
+        serde::encode_u16(output, self.buffer.map_or(0, |b| b as u16 + 1))?;
+
+        serde::encode_u8(output, self.shift_register)?;
+        serde::encode_u8(output, self.bits_remaining)?;
+        serde::encode_u8(output, self.silence as u8)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut File) -> io::Result<()> {
+        self.irq_enabled = serde::decode_u8(input)? != 0;
+        self.irq_flag = serde::decode_u8(input)? != 0;
+        self.dmc_loop = serde::decode_u8(input)? != 0;
+        self.timer_period = serde::decode_u16(input)?;
+        self.timer_value = serde::decode_u16(input)?;
+        self.output_level = serde::decode_u8(input)?;
+        self.sample_address = serde::decode_u16(input)?;
+        self.sample_length = serde::decode_u16(input)?;
+        self.current_address = serde::decode_u16(input)?;
+        self.bytes_remaining = serde::decode_u16(input)?;
+
+        self.buffer = match serde::decode_u16(input)? {
+            0 => None,
+            b => Some((b - 1) as u8),
+        };
+
+        self.shift_register = serde::decode_u8(input)?;
+        self.bits_remaining = serde::decode_u8(input)?;
+        self.silence = serde::decode_u8(input)? != 0;
+        Ok(())
+    }
 }
 
 impl DMC {
-    pub fn new_dmc_channel() -> Self {
+    pub fn new_dmc_channel(region: Region) -> Self {
+        let timer_table = region.dmc_table();
+
         Self {
-            enabled: false,
+            irq_enabled: false,
+            irq_flag: false,
+            dmc_loop: false,
+
+            timer_period: timer_table[0],
+            timer_value: 0,
+            timer_table: timer_table,
+
+            output_level: 0,
+
+            sample_address: 0xc000,
+            sample_length: 1,
+
+            current_address: 0xc000,
+            bytes_remaining: 0,
 
-            buffer: 0,
+            buffer: None,
 
-            timer_period: 0,
-            //timer_value: 0,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
         }
     }
 
-    pub fn reset(&mut self) { }
+    // Resetting silences the DMC the same way a write of 0 to $4015 would,
+    // but leaves the output level alone
+    pub fn reset(&mut self) {
+        self.irq_enabled = false;
+        self.irq_flag = false;
+        self.bytes_remaining = 0;
+    }
+
+    // Whether there's any of the sample left to be read, for $4015
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // Called when bit 4 of $4015 is written. Disabling stops the sample once
+    // the byte that's currently being played is done, and enabling starts the
+    // sample again, but only if it had finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // The address of the next byte of the sample, if the buffer needs it
+    pub fn sample_request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // Takes the byte read from the address given by sample_request
+    pub fn fill_buffer(&mut self, val: u8) {
+        self.buffer = Some(val);
+
+        // The address wraps around to $8000, not $0000
+        self.current_address = match self.current_address {
+            0xffff => 0x8000,
+            addr   => addr + 1,
+        };
+
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.dmc_loop {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // The timer is clocked on every CPU cycle, and the periods in the rate
+    // table are in CPU cycles
+    pub fn step_timer(&mut self) {
+        if self.timer_value > 0 {
+            self.timer_value -= 1;
+            return;
+        }
+
+        self.timer_value = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        // When the output cycle ends, a new one starts with whatever's in the
+        // buffer, or silence if it's empty
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+
+            match self.buffer.take() {
+                Some(val) => {
+                    self.shift_register = val;
+                    self.silence = false;
+                },
+                None => {
+                    self.silence = true;
+                },
+            }
+        }
+    }
 
     // Register $4010 sets the interrupt enable, loop, and timer period. If the
     // new interrupt enabled status is clear, the interrupt flag is cleared.
     //
     //     il-- ffff       interrupt enabled, loop, frequency index
     pub fn write_control(&mut self, val: u8) {
-        let _irq      = (val & 0b1000_0000) != 0;
-        let _dmc_loop = (val & 0b0100_0000) != 0;
-        let f_index  =  val & 0b0000_1111;
+        self.irq_enabled = (val & 0b1000_0000) != 0;
+        self.dmc_loop    = (val & 0b0100_0000) != 0;
+        let f_index      =  val & 0b0000_1111;
 
-        self.timer_period = TIMER_TABLE[f_index as usize];
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+
+        self.timer_period = self.timer_table[f_index as usize];
     }
 
     // A write to $4011 sets the counter and DAC to a new value:
     //
     //     -ddd dddd       new DAC value
-    pub fn write_dac(&mut self, _val: u8) { }
+    pub fn write_dac(&mut self, val: u8) {
+        self.output_level = val & 0b0111_1111;
+    }
+
+    // $4012 sets the sample address to $C000 + (A * 64)
+    pub fn write_address(&mut self, val: u8) {
+        self.sample_address = 0xc000 | ((val as u16) << 6);
+    }
+
+    // $4013 sets the sample length to (L * 16) + 1 bytes
+    pub fn write_length(&mut self, val: u8) {
+        self.sample_length = ((val as u16) << 4) | 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_dmc(control: u8, address: u8, length: u8) -> DMC {
+        let mut dmc = DMC::new_dmc_channel(Region::NTSC);
+        dmc.write_control(control);
+        dmc.write_address(address);
+        dmc.write_length(length);
+        dmc.set_enabled(true);
+        dmc
+    }
+
+    // Answers up to `max' of the DMC's requests for sample bytes, emptying
+    // the buffer after each one as if it had been played, and returns the
+    // addresses that were read
+    fn read_sample(dmc: &mut DMC, max: usize) -> Vec<u16> {
+        let mut addresses = Vec::new();
+
+        while let Some(address) = dmc.sample_request() {
+            if addresses.len() == max {
+                break;
+            }
+
+            addresses.push(address);
+            dmc.fill_buffer(0x55);
+            dmc.buffer = None;
+        }
+
+        addresses
+    }
+
+    #[test]
+    fn test_sample_end() {
+        let mut dmc = new_dmc(0x00, 0x01, 0x01);
+        assert!(dmc.active());
+
+        let addresses = read_sample(&mut dmc, 100);
+        assert_eq!(addresses, (0xc040 .. 0xc051).collect::<Vec<u16>>());
+        assert!(!dmc.active());
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_sample_end_irq() {
+        let mut dmc = new_dmc(0x80, 0x01, 0x01);
+
+        read_sample(&mut dmc, 16);
+        assert!(!dmc.irq_flag);
+
+        read_sample(&mut dmc, 1);
+        assert!(!dmc.active());
+        assert!(dmc.irq_flag);
+
+        // Turning the IRQ off clears it
+        dmc.write_control(0x00);
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_sample_loop() {
+        // Looping starts the sample again straight away, without an IRQ
+        let mut dmc = new_dmc(0xc0, 0x01, 0x01);
+
+        let addresses = read_sample(&mut dmc, 40);
+        let expected: Vec<u16> = (0xc040 .. 0xc051).chain(0xc040 .. 0xc051).chain(0xc040 .. 0xc046).collect();
 
-    pub fn write_address(&mut self, _val: u8) { }
+        assert_eq!(addresses, expected);
+        assert!(dmc.active());
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_address_wrap() {
+        // 65 bytes from $FFC0 runs off the end of memory
+        let mut dmc = new_dmc(0x00, 0xff, 0x04);
+
+        let addresses = read_sample(&mut dmc, 100);
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[63], 0xffff);
+        assert_eq!(addresses[64], 0x8000);
+    }
+
+    #[test]
+    fn test_buffer_only_requested_when_empty() {
+        let mut dmc = new_dmc(0x00, 0x00, 0x01);
+        dmc.write_control(0x0f);
+
+        assert_eq!(dmc.sample_request(), Some(0xc000));
+        dmc.fill_buffer(0xff);
+        assert_eq!(dmc.sample_request(), None);
 
-    pub fn write_length(&mut self, _val: u8) { }
+        // The buffer is emptied into the shift register at the end of the
+        // current output cycle, which started out silent
+        for _ in 0 .. 7 * dmc.timer_period {
+            dmc.step_timer();
+        }
+        assert_eq!(dmc.sample_request(), None);
+
+        dmc.step_timer();
+        assert_eq!(dmc.sample_request(), Some(0xc001));
+
+        // Then each bit of it raises the level by 2
+        for _ in 0 .. 3 * dmc.timer_period {
+            dmc.step_timer();
+        }
+        assert_eq!(dmc.signal(), 6);
+    }
 }
@@ -1,8 +1,9 @@
 use std::io;
 use std::fs::File;
 
-use crate::apu::channel::Voice;
+use crate::apu::channel::{Voice, describe_envelope, describe_length};
 use crate::mem::Memory;
+use crate::region::Region;
 use crate::serde;
 
 const LENGTH_TABLE: [u8; 32] = [
@@ -12,13 +13,6 @@ const LENGTH_TABLE: [u8; 32] = [
     192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
-const TIMER_TABLE: [u16; 16] = [
-    0x004, 0x008, 0x010, 0x020,
-    0x040, 0x060, 0x080, 0x0a0,
-    0x0ca, 0x0fe, 0x17c, 0x1fc,
-    0x2fa, 0x3f8, 0x7f2, 0xfe4,
-];
-
 enum ShiftRegisterMode {
     One,
     Six,
@@ -41,6 +35,7 @@ pub struct Noise {
 
     timer_period: u16,
     timer_value: u16,
+    timer_table: &'static [u16; 16],
 
     shift_register: u16,
 }
@@ -66,6 +61,21 @@ impl Voice for Noise {
             self.constant_volume
         }
     }
+
+    fn registers(&self) -> Vec<String> {
+        let mode = match self.mode {
+            ShiftRegisterMode::One => "long",
+            ShiftRegisterMode::Six => "short",
+        };
+
+        vec![
+            format!("period {}", self.timer_period),
+            format!("mode {}", mode),
+            describe_envelope(self.envelope_enabled, self.envelope_loop, self.envelope_period,
+                              self.envelope_volume, self.constant_volume),
+            describe_length(self.length_enabled, self.length_value),
+        ]
+    }
 }
 
 impl Memory for Noise {
@@ -118,7 +128,7 @@ impl Memory for Noise {
 }
 
 impl Noise {
-    pub fn new_noise_channel() -> Self {
+    pub fn new_noise_channel(region: Region) -> Self {
         Self {
             enabled: false,
             mode: ShiftRegisterMode::One,
@@ -136,6 +146,7 @@ impl Noise {
 
             timer_period: 0,
             timer_value: 0,
+            timer_table: region.noise_table(),
 
             // On power-up, the shift register is loaded with the value 1.
             shift_register: 1,
@@ -232,14 +243,19 @@ impl Noise {
         };
 
         let period_index = val & 0b0000_1111;
-        self.timer_period = TIMER_TABLE[period_index as usize];
+        self.timer_period = self.timer_table[period_index as usize];
     }
 
     // $400f
     pub fn write_length_index(&mut self, val: u8) {
         // llll l---   length index
         let length_index = val >> 3;
-        self.length_value = LENGTH_TABLE[length_index as usize];
+
+        // The length counter can only be loaded while the channel is enabled
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[length_index as usize];
+        }
+
         debug!("length_index={}, length_value={}", length_index, self.length_value);
     }
 }
@@ -1,7 +1,7 @@
 use std::io;
 use std::fs::File;
 
-use crate::apu::channel::Voice;
+use crate::apu::channel::{Voice, describe_envelope, describe_length};
 use crate::mem::Memory;
 use crate::serde;
 
@@ -83,6 +83,24 @@ impl Voice for SquareWave {
             return self.constant_volume;
         }
     }
+
+    fn registers(&self) -> Vec<String> {
+        let sweep = if self.sweep_enabled {
+            format!("sweep p{} shift {}{}",
+                    self.sweep_period, self.sweep_shift, if self.sweep_negate { " negate" } else { "" })
+        } else {
+            "sweep off".to_string()
+        };
+
+        vec![
+            format!("period {}", self.timer_period),
+            format!("duty {}", ["12.5%", "25%", "50%", "75%"][self.duty_mode as usize]),
+            describe_envelope(self.envelope_enabled, self.envelope_loop, self.envelope_period,
+                              self.envelope_volume, self.constant_volume),
+            describe_length(self.length_enabled, self.length_value),
+            sweep,
+        ]
+    }
 }
 
 impl Memory for SquareWave {
@@ -324,7 +342,11 @@ impl SquareWave {
         let length_index = (val & 0b1111_1000) >> 3;
         let period_high  = (val & 0b0000_0111) as u16;
 
-        self.length_value = LENGTH_TABLE[length_index as usize];
+        // The length counter can only be loaded while the channel is enabled
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[length_index as usize];
+        }
+
         self.timer_period = (self.timer_period & 0x00ff) | (period_high << 8);
         self.envelope_start = true;
         self.duty_value = 0;
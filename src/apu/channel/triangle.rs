@@ -1,7 +1,7 @@
 use std::io;
 use std::fs::File;
 
-use crate::apu::channel::Voice;
+use crate::apu::channel::{Voice, describe_length};
 use crate::mem::Memory;
 use crate::serde;
 
@@ -28,7 +28,7 @@ pub struct TriangleWave {
 
     counter_reload: bool,
     counter_period: u8,
-    counter_value: u8,
+    pub counter_value: u8,
 
     timer_value: u16,
     timer_period: u16,
@@ -51,6 +51,16 @@ impl Voice for TriangleWave {
 
         TRIANGLE_WAVEFORM[self.duty_value as usize]
     }
+
+    fn registers(&self) -> Vec<String> {
+        vec![
+            format!("period {}", self.timer_period),
+            format!("step {}", self.duty_value),
+            describe_length(self.length_enabled, self.length_value),
+            format!("linear {}/{}{}",
+                    self.counter_value, self.counter_period, if self.counter_reload { " (reload)" } else { "" }),
+        ]
+    }
 }
 
 impl Memory for TriangleWave {
@@ -166,7 +176,11 @@ impl TriangleWave {
         let length_index = (val & 0b1111_1000) >> 3;
         let period_high  = (val & 0b0000_0111) as u16;
 
-        self.length_value = LENGTH_TABLE[length_index as usize];
+        // The length counter can only be loaded while the channel is enabled
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[length_index as usize];
+        }
+
         self.timer_period = (self.timer_period & 0x00ff) | (period_high << 8);
         self.timer_value = self.timer_period + 1;
         self.counter_reload = true;
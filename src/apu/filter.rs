@@ -1,3 +1,7 @@
+// First-order filters, made with the bilinear transform. The cutoff is
+// prewarped, so that the filters cut off at the right frequency even when
+// it's close to half the sample rate, like the 14kHz low-pass filter is.
+
 use std::f32::consts::PI;
 
 pub trait Filter {
@@ -23,7 +27,7 @@ impl Filter for LowPassFilter {
 
 impl LowPassFilter {
     pub fn new_filter(sample_rate: f32, cutoff: f32) -> Self {
-        let c = sample_rate / PI / cutoff;
+        let c = 1.0 / (PI * cutoff / sample_rate).tan();
         let a0i = 1.0 / (1.0 + c);
 
         Self {
@@ -55,7 +59,7 @@ impl Filter for HighPassFilter {
 
 impl HighPassFilter {
     pub fn new_filter(sample_rate: f32, cutoff: f32) -> Self {
-        let c = sample_rate / PI / cutoff;
+        let c = 1.0 / (PI * cutoff / sample_rate).tan();
         let a0i = 1.0 / (1.0 + c);
 
         Self {
@@ -0,0 +1,334 @@
+// https://wiki.nesdev.com/w/index.php/APU_Mixer
+//
+// Mixes the channels together, with a volume and stereo position for each
+// one, so that channels can be turned down or muted while debugging, or
+// spread out for stereo.
+//
+// The NES mixes its channels non-linearly: the two square waves go through
+// one DAC, and the triangle, noise and DMC through another, so how loud one
+// channel sounds depends on what the others are doing. That can't be split
+// into separate stereo positions, so the default non-linear mode is mono and
+// ignores panning, and the linear mode uses the linear approximation of each
+// channel's level instead, which sounds very close, and can be panned.
+//
+// Sound chips on the cartridge are mixed in linearly after the APU's own
+// channels, like the audio input on the cartridge connector, with their own
+// volume, and their own stereo position in the linear mode.
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Triangle,
+    Noise,
+    DMC,
+    Expansion,
+}
+
+pub const CHANNELS: [Channel; 5] = [
+    Channel::Square1,
+    Channel::Square2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::DMC,
+];
+
+// Every channel the mixer has settings for: the APU's own, in the same order
+// as CHANNELS, and then the cartridge's sound chip
+pub const MIXER_CHANNELS: [Channel; 6] = [
+    Channel::Square1,
+    Channel::Square2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::DMC,
+    Channel::Expansion,
+];
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Channel::Square1   => "square 1",
+            Channel::Square2   => "square 2",
+            Channel::Triangle  => "triangle",
+            Channel::Noise     => "noise",
+            Channel::DMC       => "DMC",
+            Channel::Expansion => "expansion",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MixMode {
+    NonLinear,
+    Linear,
+}
+
+impl MixMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "nonlinear" => Some(MixMode::NonLinear),
+            "linear"    => Some(MixMode::Linear),
+            _           => None,
+        }
+    }
+}
+
+impl fmt::Display for MixMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MixMode::NonLinear => write!(f, "non-linear (mono)"),
+            MixMode::Linear    => write!(f, "linear (stereo)"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ChannelSettings {
+    // From 0.0 to 1.0
+    volume: f32,
+
+    // From -1.0 (left) to 1.0 (right)
+    pan: f32,
+
+    muted: bool,
+}
+
+impl ChannelSettings {
+    fn gain(&self) -> f32 {
+        if self.muted { 0.0 } else { self.volume }
+    }
+
+    // How much of the channel goes to the left and right. Panning turns the
+    // other side down, rather than turning both sides down a bit, so centred
+    // channels are just as loud as in mono.
+    fn gains(&self) -> (f32, f32) {
+        let gain = self.gain();
+        let left = (1.0 - self.pan).min(1.0);
+        let right = (1.0 + self.pan).min(1.0);
+
+        (gain * left, gain * right)
+    }
+}
+
+pub struct Mixer {
+    mode: MixMode,
+    settings: [ChannelSettings; 6],
+}
+
+impl Mixer {
+    pub fn new_mixer() -> Self {
+        let settings = ChannelSettings {
+            volume: 1.0,
+            pan: 0.0,
+            muted: false,
+        };
+
+        Self {
+            mode: MixMode::NonLinear,
+            settings: [settings; 6],
+        }
+    }
+
+    pub fn mode(&self) -> MixMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MixMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.settings[channel as usize].volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn set_pan(&mut self, channel: Channel, pan: f32) {
+        self.settings[channel as usize].pan = pan.clamp(-1.0, 1.0);
+    }
+
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.settings[channel as usize].muted
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.settings[channel as usize].muted = muted;
+    }
+
+    // Mixes the output of each channel's DAC, in the same order as CHANNELS,
+    // and the cartridge's sound chip into a left and right signal
+    pub fn mix(&self, levels: [u8; 5], expansion: f32) -> (f32, f32) {
+        match self.mode {
+            MixMode::NonLinear => {
+                let level = |i: usize| levels[i] as f32 * self.settings[i].gain();
+
+                let pulse_val = 95.88 / (100.0
+                                         + (8128.0 / (level(0) + level(1))));
+
+                let tnd_val = 159.79 / (100.0
+                                        + (1.0 / (  (level(2) / 8227.0)
+                                                  + (level(3) / 12241.0)
+                                                  + (level(4) / 22638.0))));
+
+                let expansion = expansion * self.settings[Channel::Expansion as usize].gain();
+
+                let signal = pulse_val + tnd_val + expansion;
+                (signal, signal)
+            },
+            MixMode::Linear => {
+                const WEIGHTS: [f32; 5] = [0.00752, 0.00752, 0.00851, 0.00494, 0.00335];
+
+                let mut left = 0.0;
+                let mut right = 0.0;
+
+                for (i, settings) in self.settings[.. 5].iter().enumerate() {
+                    let (l, r) = settings.gains();
+                    let level = levels[i] as f32 * WEIGHTS[i];
+
+                    left += level * l;
+                    right += level * r;
+                }
+
+                let (l, r) = self.settings[Channel::Expansion as usize].gains();
+
+                (left + expansion * l, right + expansion * r)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6
+    }
+
+    // Each channel on its own, at full level
+    fn solo(channel: usize) -> [u8; 5] {
+        let mut levels = [0; 5];
+        levels[channel] = if channel == 4 { 127 } else { 15 };
+        levels
+    }
+
+    #[test]
+    fn test_linear_weights() {
+        let mut mixer = Mixer::new_mixer();
+        mixer.set_mode(MixMode::Linear);
+
+        assert_eq!(mixer.mix([0; 5], 0.0), (0.0, 0.0));
+
+        let expected = [15.0 * 0.00752, 15.0 * 0.00752, 15.0 * 0.00851, 15.0 * 0.00494, 127.0 * 0.00335];
+        for (i, level) in expected.iter().enumerate() {
+            assert!(close(mixer.mix(solo(i), 0.0), (*level, *level)));
+        }
+
+        // Channels add up
+        let total: f32 = expected.iter().sum();
+        assert!(close(mixer.mix([15, 15, 15, 15, 127], 0.0), (total, total)));
+    }
+
+    #[test]
+    fn test_linear_volume_and_mute() {
+        let mut mixer = Mixer::new_mixer();
+        mixer.set_mode(MixMode::Linear);
+
+        let full = mixer.mix(solo(2), 0.0);
+
+        mixer.set_volume(Channel::Triangle, 0.25);
+        assert!(close(mixer.mix(solo(2), 0.0), (full.0 * 0.25, full.1 * 0.25)));
+
+        // Volumes are clamped
+        mixer.set_volume(Channel::Triangle, 2.0);
+        assert!(close(mixer.mix(solo(2), 0.0), full));
+
+        // Muting leaves the other channels alone
+        mixer.set_muted(Channel::Triangle, true);
+        assert!(mixer.muted(Channel::Triangle));
+        assert_eq!(mixer.mix(solo(2), 0.0), (0.0, 0.0));
+        assert!(close(mixer.mix(solo(0), 0.0), (15.0 * 0.00752, 15.0 * 0.00752)));
+
+        // And unmuting brings back the volume it had
+        mixer.set_muted(Channel::Triangle, false);
+        assert!(close(mixer.mix(solo(2), 0.0), full));
+    }
+
+    #[test]
+    fn test_linear_pan() {
+        let mut mixer = Mixer::new_mixer();
+        mixer.set_mode(MixMode::Linear);
+
+        let (centre, _) = mixer.mix(solo(0), 0.0);
+
+        // Panning turns the other side down, and leaves the near side alone
+        mixer.set_pan(Channel::Square1, -1.0);
+        assert!(close(mixer.mix(solo(0), 0.0), (centre, 0.0)));
+
+        mixer.set_pan(Channel::Square1, 0.5);
+        assert!(close(mixer.mix(solo(0), 0.0), (centre * 0.5, centre)));
+
+        // Pans are clamped
+        mixer.set_pan(Channel::Square1, 3.0);
+        assert!(close(mixer.mix(solo(0), 0.0), (0.0, centre)));
+
+        // Each channel has its own position
+        mixer.set_pan(Channel::Square2, -1.0);
+        assert!(close(mixer.mix([15, 15, 0, 0, 0], 0.0), (centre, centre)));
+    }
+
+    #[test]
+    fn test_non_linear() {
+        let mut mixer = Mixer::new_mixer();
+
+        // The squares go through one DAC, so two at once are less than twice
+        // as loud as one
+        let (one, _) = mixer.mix(solo(0), 0.0);
+        let (two, _) = mixer.mix([15, 15, 0, 0, 0], 0.0);
+        assert!((one - 95.88 / (100.0 + 8128.0 / 15.0)).abs() < 1e-6);
+        assert!(two > one && two < one * 2.0);
+
+        // Panning is ignored, as it's mono...
+        let levels = [15, 8, 15, 4, 64];
+        let centre = mixer.mix(levels, 0.0);
+        assert_eq!(centre.0, centre.1);
+
+        for channel in CHANNELS.iter() {
+            mixer.set_pan(*channel, -1.0);
+        }
+        assert_eq!(mixer.mix(levels, 0.0), centre);
+
+        // ...but volume and muting are not
+        mixer.set_volume(Channel::Square2, 0.0);
+        assert_eq!(mixer.mix([15, 15, 0, 0, 0], 0.0), (one, one));
+
+        mixer.set_muted(Channel::Square1, true);
+        assert_eq!(mixer.mix([15, 15, 0, 0, 0], 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_expansion_settings() {
+        let mut mixer = Mixer::new_mixer();
+        let silent = [0; 5];
+
+        assert_eq!(mixer.mix(silent, 0.5), (0.5, 0.5));
+
+        mixer.set_volume(Channel::Expansion, 0.5);
+        assert_eq!(mixer.mix(silent, 0.5), (0.25, 0.25));
+
+        // Panning only works when mixing linearly
+        mixer.set_pan(Channel::Expansion, -1.0);
+        assert_eq!(mixer.mix(silent, 0.5), (0.25, 0.25));
+
+        mixer.set_mode(MixMode::Linear);
+        assert_eq!(mixer.mix(silent, 0.5), (0.25, 0.0));
+
+        mixer.set_muted(Channel::Expansion, true);
+        assert_eq!(mixer.mix(silent, 0.5), (0.0, 0.0));
+
+        mixer.set_mode(MixMode::NonLinear);
+        assert_eq!(mixer.mix(silent, 0.5), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,160 @@
+// Keeps the audio in sync with the video.
+//
+// https://docs.libretro.com/development/cores/dynamic-rate-control/
+//
+// The emulator is paced by the video, so it never produces samples at exactly
+// the rate the sound card plays them, and the queue of samples waiting to be
+// played slowly fills up or runs dry. Rather than dropping or repeating
+// samples when that happens, which crackles, the APU's output is resampled
+// by a ratio that's nudged up or down by a tiny amount (far too little to
+// hear as a change in pitch) depending on how full the queue is, so that it
+// hovers around the target latency.
+//
+// The same resampling also keeps the audio in step with the video when the
+// emulator is slowed down or sped up.
+
+// The most the resampling ratio is adjusted by, either way
+const MAX_ADJUSTMENT: f64 = 0.005;
+
+pub struct AudioSync {
+    // How many samples we want waiting in the queue
+    target: usize,
+
+    // How many input samples each output sample moves along by, before it's
+    // adjusted
+    speed: f64,
+
+    // How many input samples each output sample moves along by
+    step: f64,
+
+    // How far between the previous input sample and the next one the next
+    // output sample is
+    position: f64,
+    previous: (f32, f32),
+}
+
+impl AudioSync {
+    pub fn new_audio_sync(sample_rate: u32, latency_ms: u32) -> Self {
+        Self {
+            target: (sample_rate as usize * latency_ms as usize) / 1000,
+            speed: 1.0,
+            step: 1.0,
+            position: 0.0,
+            previous: (0.0, 0.0),
+        }
+    }
+
+    // The number of samples of latency we're aiming for
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    // Adjusts the resampling ratio, given how many samples are waiting in the
+    // queue. If the queue is emptier than the target, more samples are made
+    // for each one from the APU, and if it's fuller, fewer are.
+    pub fn update(&mut self, queued: usize) {
+        let error = (self.target as f64 - queued as f64) / self.target.max(1) as f64;
+        let adjustment = 1.0 + MAX_ADJUSTMENT * error.clamp(-1.0, 1.0);
+
+        self.step = self.speed / adjustment;
+    }
+
+    // Resamples one left and right sample from the APU, adding any output
+    // samples to `output'. Output samples are linearly interpolated between
+    // input samples, which is plenty for ratios this close to 1, and the
+    // APU's output is already band-limited.
+    pub fn push(&mut self, sample: (f32, f32), output: &mut Vec<(f32, f32)>) {
+        let (left, right) = sample;
+        let (prev_left, prev_right) = self.previous;
+
+        while self.position < 1.0 {
+            let t = self.position as f32;
+            output.push((prev_left + (left - prev_left) * t,
+                         prev_right + (right - prev_right) * t));
+            self.position += self.step;
+        }
+
+        self.position -= 1.0;
+        self.previous = sample;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: usize = 100_000;
+
+    // How many output samples there are for each input sample, once the
+    // resampling ratio has been adjusted for `queued' samples in the queue
+    fn ratio(queued: usize) -> f64 {
+        let mut sync = AudioSync::new_audio_sync(44_100, 50);
+        sync.update(queued);
+
+        let mut output = Vec::new();
+        for i in 0 .. INPUT {
+            sync.push((i as f32, 0.0), &mut output);
+        }
+
+        output.len() as f64 / INPUT as f64
+    }
+
+    #[test]
+    fn test_at_target() {
+        assert!((ratio(2205) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_queue_below_target() {
+        let half_empty = ratio(1000);
+        let empty = ratio(0);
+
+        assert!(half_empty > 1.0);
+        assert!(empty > half_empty);
+        assert!(empty <= 1.0 + MAX_ADJUSTMENT + 1e-4);
+    }
+
+    #[test]
+    fn test_queue_above_target() {
+        let full = ratio(3000);
+        let overflowing = ratio(100_000);
+
+        assert!(full < 1.0);
+        assert!(overflowing < full);
+        assert!(overflowing >= 1.0 - MAX_ADJUSTMENT - 1e-4);
+    }
+
+    // Without any adjustment, every sample comes out exactly once, one sample
+    // behind
+    #[test]
+    fn test_push_at_step_one() {
+        let mut sync = AudioSync::new_audio_sync(44_100, 50);
+        let mut output = Vec::new();
+
+        for i in 1 ..= 1000 {
+            sync.push((i as f32, -(i as f32)), &mut output);
+        }
+
+        let expected: Vec<(f32, f32)> = (0 .. 1000).map(|i| (i as f32, -(i as f32))).collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_push_interpolates() {
+        let mut sync = AudioSync::new_audio_sync(44_100, 50);
+        sync.set_speed(0.5);
+        sync.update(sync.target());
+
+        let mut output = Vec::new();
+        for i in 1 ..= 4 {
+            sync.push((i as f32 * 2.0, 0.0), &mut output);
+        }
+
+        let left: Vec<f32> = output.iter().map(|(l, _)| *l).collect();
+        assert_eq!(left, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+    }
+}
@@ -0,0 +1,175 @@
+// Converts CHR data to and from images, for romhacking.
+//
+// https://wiki.nesdev.com/w/index.php/PPU_pattern_tables
+//
+// Each 8x8 tile takes up 16 bytes: 8 bytes for the low bit of each pixel, one
+// byte per row, followed by 8 bytes for the high bit. Images are laid out with
+// 16 tiles per row, like most tile editors do, so the 4KB pattern tables come
+// out as 128x128 squares.
+//
+// PNGs are written as indexed images with a 4 colour palette, so that the
+// pixel values survive being edited, as long as the editor keeps the palette.
+// When importing an image that isn't indexed, or that uses more than 4
+// colours of its palette, each pixel is matched to the closest colour in the
+// palette instead.
+
+use std::fmt;
+
+use crate::png;
+use crate::png::PNGError;
+
+const TILES_PER_ROW: usize = 16;
+
+#[derive(Debug)]
+pub enum CHRError {
+    PNG(PNGError),
+
+    // The image isn't 128 pixels wide, with a whole number of rows of tiles
+    BadDimensions(usize, usize),
+
+    // The imported data isn't the same size as the cartridge's CHR
+    BadSize(usize, usize),
+}
+
+impl fmt::Display for CHRError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CHRError::PNG(ref e) => write!(f, "{}", e),
+            CHRError::BadDimensions(w, h) => {
+                write!(f, "image is {}x{}, expected {} pixels wide and a multiple of 8 high",
+                       w, h, TILES_PER_ROW * 8)
+            },
+            CHRError::BadSize(got, expected) => {
+                write!(f, "got {} bytes of CHR data, expected {}", got, expected)
+            },
+        }
+    }
+}
+
+// Turns CHR data into an image of 2-bit pixel values, returning the width and
+// height along with the pixels
+fn to_pixels(chr: &[u8]) -> (usize, usize, Vec<u8>) {
+    let tiles = chr.len() / 16;
+    let width = TILES_PER_ROW * 8;
+    let height = tiles.div_ceil(TILES_PER_ROW) * 8;
+
+    let mut pixels = vec![0; width * height];
+
+    for tile in 0 .. tiles {
+        let tile_x = (tile % TILES_PER_ROW) * 8;
+        let tile_y = (tile / TILES_PER_ROW) * 8;
+
+        for row in 0 .. 8 {
+            let low_byte = chr[tile * 16 + row];
+            let high_byte = chr[tile * 16 + row + 8];
+
+            for col in 0 .. 8 {
+                let p1 = (low_byte >> (7 - col)) & 1;
+                let p2 = ((high_byte >> (7 - col)) & 1) << 1;
+
+                pixels[(tile_y + row) * width + tile_x + col] = p1 | p2;
+            }
+        }
+    }
+
+    (width, height, pixels)
+}
+
+// The opposite of to_pixels
+fn from_pixels(width: usize, height: usize, pixels: &[u8]) -> Result<Vec<u8>, CHRError> {
+    if width != TILES_PER_ROW * 8 || !height.is_multiple_of(8) {
+        return Err(CHRError::BadDimensions(width, height));
+    }
+
+    let tiles = (height / 8) * TILES_PER_ROW;
+    let mut chr = vec![0; tiles * 16];
+
+    for tile in 0 .. tiles {
+        let tile_x = (tile % TILES_PER_ROW) * 8;
+        let tile_y = (tile / TILES_PER_ROW) * 8;
+
+        for row in 0 .. 8 {
+            let mut low_byte = 0;
+            let mut high_byte = 0;
+
+            for col in 0 .. 8 {
+                let p = pixels[(tile_y + row) * width + tile_x + col] & 3;
+
+                low_byte = (low_byte << 1) | (p & 1);
+                high_byte = (high_byte << 1) | (p >> 1);
+            }
+
+            chr[tile * 16 + row] = low_byte;
+            chr[tile * 16 + row + 8] = high_byte;
+        }
+    }
+
+    Ok(chr)
+}
+
+pub fn export_png(chr: &[u8], palette: &[[u8; 3]; 4]) -> Vec<u8> {
+    let (width, height, pixels) = to_pixels(chr);
+    png::encode_indexed(width, height, palette, &pixels)
+}
+
+pub fn import_png(data: &[u8], palette: &[[u8; 3]; 4]) -> Result<Vec<u8>, CHRError> {
+    let image = png::decode(data).map_err(CHRError::PNG)?;
+
+    let pixels = match image.indices {
+        Some(indices) if indices.iter().all(|i| *i < 4) => indices,
+        _ => {
+            image.rgb.chunks(3)
+                .map(|rgb| {
+                    let distance = |c: &[u8; 3]| -> i32 {
+                        (0 .. 3).map(|i| (rgb[i] as i32 - c[i] as i32).pow(2)).sum()
+                    };
+
+                    (0 .. 4).min_by_key(|i| distance(&palette[*i as usize])).unwrap()
+                })
+                .collect()
+        },
+    };
+
+    from_pixels(image.width, image.height, &pixels)
+}
+
+// Copies imported CHR data over the cartridge's CHR
+pub fn import(chr: &mut [u8], data: &[u8]) -> Result<(), CHRError> {
+    if data.len() != chr.len() {
+        return Err(CHRError::BadSize(data.len(), chr.len()));
+    }
+
+    chr.copy_from_slice(data);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [85, 85, 85], [170, 170, 170], [255, 255, 255]];
+
+    #[test]
+    fn test_png_round_trip() {
+        let chr: Vec<u8> = (0 .. 0x1000).map(|i| (i * 37 % 251) as u8).collect();
+        let image = export_png(&chr, &PALETTE);
+
+        assert_eq!(import_png(&image, &PALETTE).unwrap(), chr);
+    }
+
+    // An editor that saves with a bigger palette doesn't keep the indices, so
+    // the colours are matched instead
+    #[test]
+    fn test_import_large_palette() {
+        let palette = [[255, 255, 255], [0, 0, 0], [250, 250, 250], [90, 80, 85], [160, 170, 180]];
+        let indices: Vec<u8> = (0 .. 128 * 8).map(|i| (i % 5) as u8).collect();
+        let image = png::encode_indexed(128, 8, &palette, &indices);
+
+        let chr = import_png(&image, &PALETTE).unwrap();
+        let (_, _, pixels) = to_pixels(&chr);
+
+        let expected: Vec<u8> = indices.iter().map(|i| [3, 0, 3, 1, 2][*i as usize]).collect();
+        assert_eq!(pixels, expected);
+    }
+}
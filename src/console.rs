@@ -2,26 +2,38 @@ use std::cell::RefCell;
 use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom};
+use std::io;
+use std::path::Path;
 use std::process;
 use std::rc::Rc;
+use std::str::FromStr;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::apu::APU;
+use crate::apu::{APU, CHANNELS, Channel, DEFAULT_SAMPLE_RATE, MIXER_CHANNELS, MixMode};
+use crate::audio::AudioSync;
+use crate::chr;
 use crate::controller::Controller;
 use crate::cpu::CPU;
-use crate::mapper::{Mapper, MapperEvent};
+use crate::mapper::{Mapper, MapperEvent, NSFMapper};
 use crate::mem::{Memory, NESMemory};
+use crate::pacer::FramePacer;
 use crate::ppu::PPU;
 use crate::ines::CartridgeError;
 use crate::ines;
+use crate::nsf::NSFPlayer;
+use crate::nsf;
+use crate::recorder::{GIFRecorder, Recorder, VideoFormat, WAVWriter};
+use crate::region::Region;
+use crate::viewer::{APUViewer, EventViewer, NametableViewer, SpriteViewer};
+use crate::viewer;
 
 use sdl2::audio::AudioSpecDesired;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::rect::Rect;
 
 lazy_static!{
@@ -34,14 +46,138 @@ lazy_static!{
         Ok(val) => val.parse().expect("invalid NES_APU_CHANNELS value"),
         Err(_)  => std::u8::MAX,
     };
+
+    pub static ref NES_APU_MIX: MixMode = match env::var("NES_APU_MIX") {
+        Ok(val) => MixMode::from_name(&val).expect("invalid NES_APU_MIX value"),
+        Err(_)  => MixMode::NonLinear,
+    };
+
+    // Each channel's volume, from 0 to 100, and position, from -100 (left) to
+    // 100 (right), separated by commas in the same order as NES_APU_CHANNELS
+    pub static ref NES_APU_VOLUME: Vec<u8> = channel_list("NES_APU_VOLUME", 100);
+    pub static ref NES_APU_PAN: Vec<i8> = channel_list("NES_APU_PAN", 0);
+
+    pub static ref NES_NO_SPRITE_LIMIT: bool = match env::var("NES_NO_SPRITE_LIMIT") {
+        Ok(val) => val != "" && val != "0",
+        Err(_)  => false,
+    };
+
+    pub static ref NES_VSYNC: bool = match env::var("NES_VSYNC") {
+        Ok(val) => val != "" && val != "0",
+        Err(_)  => false,
+    };
+
+    // How much audio to keep queued up, in milliseconds. Less is more
+    // responsive, but too little will crackle.
+    pub static ref NES_AUDIO_LATENCY: u32 = match env::var("NES_AUDIO_LATENCY") {
+        Ok(val) => val.parse().expect("invalid NES_AUDIO_LATENCY value"),
+        Err(_)  => 80,
+    };
+
+    // The audio format to ask for. The audio device might not be able to do
+    // exactly this, in which case we go with whatever it can do.
+    pub static ref NES_AUDIO_RATE: u32 = match env::var("NES_AUDIO_RATE") {
+        Ok(val) => val.parse().expect("invalid NES_AUDIO_RATE value"),
+        Err(_)  => DEFAULT_SAMPLE_RATE,
+    };
+
+    pub static ref NES_AUDIO_CHANNELS: u8 = match env::var("NES_AUDIO_CHANNELS") {
+        Ok(val) => val.parse().expect("invalid NES_AUDIO_CHANNELS value"),
+        Err(_)  => 2,
+    };
+
+    pub static ref NES_AUDIO_BUFFER: u16 = match env::var("NES_AUDIO_BUFFER") {
+        Ok(val) => val.parse().expect("invalid NES_AUDIO_BUFFER value"),
+        Err(_)  => 1024,
+    };
+
+    // The starting volume, from 0 to 100
+    pub static ref NES_AUDIO_VOLUME: u8 = match env::var("NES_AUDIO_VOLUME") {
+        Ok(val) => val.parse::<u8>().expect("invalid NES_AUDIO_VOLUME value").min(100),
+        Err(_)  => 100,
+    };
+
+    pub static ref NES_REGION: Option<Region> = match env::var("NES_REGION") {
+        Ok(val) => Some(Region::from_name(&val).expect("invalid NES_REGION value")),
+        Err(_)  => None,
+    };
+
+    // Which palette CHR is exported with: "grey", or 0-7 for one of the PPU's
+    // background (0-3) or sprite (4-7) palettes
+    pub static ref NES_CHR_PALETTE: Option<u8> = match env::var("NES_CHR_PALETTE") {
+        Ok(ref val) if val == "grey" => None,
+        Ok(val) => match val.parse() {
+            Ok(n) if n < 8 => Some(n),
+            _              => panic!("invalid NES_CHR_PALETTE value"),
+        },
+        Err(_)  => None,
+    };
+
+    pub static ref NES_RECORD: bool = match env::var("NES_RECORD") {
+        Ok(val) => val != "" && val != "0",
+        Err(_)  => false,
+    };
+
+    pub static ref NES_RECORD_FORMAT: VideoFormat = match env::var("NES_RECORD_FORMAT") {
+        Ok(val) => VideoFormat::from_name(&val).expect("invalid NES_RECORD_FORMAT value"),
+        Err(_)  => VideoFormat::Y4M,
+    };
+
+    // The frame rate to record GIFs at, if not every frame
+    pub static ref NES_GIF_FPS: Option<f64> = match env::var("NES_GIF_FPS") {
+        Ok(val) => Some(val.parse().expect("invalid NES_GIF_FPS value")),
+        Err(_)  => None,
+    };
+
+    pub static ref NES_CHR_IMPORT: Option<String> = env::var("NES_CHR_IMPORT").ok();
+
+    // The NSF track to start on, counting from 1, instead of the file's
+    // starting track
+    pub static ref NES_NSF_TRACK: Option<u8> = match env::var("NES_NSF_TRACK") {
+        Ok(val) => Some(val.parse().expect("invalid NES_NSF_TRACK value")),
+        Err(_)  => None,
+    };
+
+    // When set, NSF tracks are rendered to WAV files without opening a
+    // window, and tracks without a length in their metadata are rendered for
+    // this many seconds
+    pub static ref NES_NSF_RENDER: Option<f64> = match env::var("NES_NSF_RENDER") {
+        Ok(val) => Some(val.parse().expect("invalid NES_NSF_RENDER value")),
+        Err(_)  => None,
+    };
+}
+
+// Parses a comma separated value for each of the mixer's channels, with any
+// channels that are left out getting the default
+fn channel_list<T: FromStr + Copy>(name: &str, default: T) -> Vec<T> {
+    let mut values = vec![default; MIXER_CHANNELS.len()];
+
+    if let Ok(val) = env::var(name) {
+        let parts: Vec<&str> = val.split(',').collect();
+        if parts.len() > MIXER_CHANNELS.len() {
+            panic!("invalid {} value", name);
+        }
+
+        for (value, part) in values.iter_mut().zip(parts) {
+            *value = part.trim().parse().unwrap_or_else(|_| panic!("invalid {} value", name));
+        }
+    }
+
+    values
 }
 
-const NES_FPS: f64 = 60.0;
-const FRAME_DURATION: Duration = Duration::from_millis(((1.0 / NES_FPS) * 1000.0) as u64);
+// How many times bigger than the NES picture the main window is
+const SCREEN_SCALE: usize = 3;
+
+const GREY_PALETTE: [[u8; 3]; 4] = [
+    [0, 0, 0],
+    [85, 85, 85],
+    [170, 170, 170],
+    [255, 255, 255],
+];
 
-// The queue is full of f32s, and we want to maintain roughly 16384 samples in
-// the queue at all times, so 4 * 16384 is the goal size.
-const AUDIO_QUEUE_HIGH_WATER_MARK: u32 = 4 * 16384;
+// How much the volume keys change the volume by
+const VOLUME_STEP: u8 = 10;
 
 pub struct Console {
     // NES components
@@ -51,8 +187,38 @@ pub struct Console {
     cartridge:  Rc<RefCell<Box<dyn Mapper>>>,
     controller: Rc<RefCell<Controller>>,
 
+    region: Region,
+
     // The absolute path on disk to save state to
     save_path:  String,
+
+    // The ROM's file name without its extension, which exported files are
+    // named after
+    rom_name:   String,
+
+    // Plays the music when an NSF file is loaded instead of a ROM
+    nsf:        Option<NSFPlayer>,
+}
+
+// The current UTC time as YYYYmmdd-HHMMSS
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let days = secs / 86400;
+    let secs = secs % 86400;
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+            year, month, day, secs / 3600, (secs / 60) % 60, secs % 60)
 }
 
 impl Console {
@@ -61,12 +227,44 @@ impl Console {
         info!("loading cartridge: {}", full_path.display());
         let basename_path = full_path.file_name().unwrap().to_str().unwrap();
         let save_path = format!("{:x}.data", md5::compute(basename_path)).into();
+        let rom_name = full_path.file_stem().unwrap().to_string_lossy().into_owned();
 
         let mut fh = File::open(full_path).map_err(CartridgeError::IO)?;
-        let cartridge = ines::load_file_into_memory(&mut fh)?;
 
-        let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(cartridge.clone())));
-        let apu = Rc::new(RefCell::new(APU::new_nes_apu()));
+        let mut magic = [0; 5];
+        fh.read_exact(&mut magic).map_err(CartridgeError::IO)?;
+        fh.seek(SeekFrom::Start(0)).map_err(CartridgeError::IO)?;
+
+        let (cartridge, region, nsf) = if nsf::is_nsf(&magic) {
+            let nsf = nsf::load_file(&mut fh)?;
+            let cartridge: Rc<RefCell<Box<dyn Mapper>>> = Rc::new(RefCell::new(Box::new(NSFMapper::new_mapper(&nsf))));
+            let region = nsf.region;
+
+            (cartridge, region, Some(nsf))
+        } else {
+            let (cartridge, region) = ines::load_file_into_memory(&mut fh)?;
+            (cartridge, region, None)
+        };
+
+        // The region in the header can be overridden, since plenty of ROMs
+        // out there have it set wrong
+        let region = NES_REGION.unwrap_or(region);
+        info!("region: {}", region);
+
+        let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(cartridge.clone(), region)));
+        ppu.borrow_mut().set_sprite_limit(!*NES_NO_SPRITE_LIMIT);
+        let apu = Rc::new(RefCell::new(APU::new_nes_apu(region)));
+        {
+            let mut apu = apu.borrow_mut();
+            let mixer = apu.mixer();
+            mixer.set_mode(*NES_APU_MIX);
+
+            for (i, channel) in MIXER_CHANNELS.iter().enumerate() {
+                mixer.set_muted(*channel, *NES_APU_CHANNELS & (1 << i) == 0);
+                mixer.set_volume(*channel, NES_APU_VOLUME[i].min(100) as f32 / 100.0);
+                mixer.set_pan(*channel, NES_APU_PAN[i] as f32 / 100.0);
+            }
+        }
         let controller = Rc::new(RefCell::new(Controller::new_controller()));
         let mem = NESMemory::new_nes_mem(
             ppu.clone(),
@@ -80,34 +278,200 @@ impl Console {
             apu:        apu,
             cartridge:  cartridge,
             controller: controller,
+            region:     region,
             save_path:  save_path,
+            rom_name:   rom_name,
+            nsf:        nsf.map(|nsf| NSFPlayer::new_nsf_player(nsf, region)),
         })
     }
 
-    // Dump the current CHR contents to disk, in a file named tileset.chr, but
-    // be warned, because if this file already exists, it will be overwritten.
-    //
-    // Only runnable if NES_PPU_DEBUG is non-zero.
-    fn dump_chr(&mut self) {
-        if !*NES_PPU_DEBUG {
-            println!("Sorry! This can only be done in PPU debug mode.");
+    // A path in the current directory to export something to, named after
+    // the ROM, what it is and when it was taken, so nothing gets overwritten.
+    fn output_path(&self, kind: &str, extension: &str) -> String {
+        let base = format!("{}-{}-{}", self.rom_name, kind, timestamp());
+        let mut path = format!("{}.{}", base, extension);
+        let mut n = 1;
+
+        while Path::new(&path).exists() {
+            n += 1;
+            path = format!("{}-{}.{}", base, n, extension);
+        }
+
+        path
+    }
+
+    // Saves the last frame to a PNG, either exactly as the PPU drew it, or
+    // scaled up as it's shown in the window.
+    fn screenshot(&mut self, scaled: bool) {
+        let scale = if scaled { SCREEN_SCALE } else { 1 };
+        let path = self.output_path("screenshot", "png");
+
+        match fs::write(&path, self.ppu.borrow().screenshot(scale)) {
+            Ok(_)  => println!("screenshot saved to {}", path),
+            Err(e) => println!("unable to save screenshot: {}", e),
+        }
+    }
+
+    // Starts recording video and audio, to files named after the ROM
+    fn start_recording(&self) -> Option<Recorder> {
+        let video_path = self.output_path("recording", NES_RECORD_FORMAT.extension());
+        let audio_path = Path::new(&video_path).with_extension("wav").to_string_lossy().into_owned();
+
+        let recorder = Recorder::new_recorder(&video_path,
+                                              &audio_path,
+                                              *NES_RECORD_FORMAT,
+                                              self.region.frame_rate(),
+                                              self.apu.borrow().sample_rate());
+
+        match recorder {
+            Ok(recorder) => {
+                println!("recording to {} and {}", video_path, audio_path);
+                Some(recorder)
+            },
+            Err(e) => {
+                println!("unable to start recording: {}", e);
+                None
+            },
+        }
+    }
+
+    fn stop_recording(&self, recorder: Recorder) {
+        let frames = recorder.frames();
+
+        match recorder.finish() {
+            Ok(_)  => println!("recording stopped after {} frames", frames),
+            Err(e) => println!("unable to finish recording: {}", e),
+        }
+    }
+
+    fn start_gif(&self) -> Option<GIFRecorder> {
+        let path = self.output_path("recording", "gif");
+
+        match GIFRecorder::new_gif_recorder(&path, self.region.frame_rate(), *NES_GIF_FPS) {
+            Ok(recorder) => {
+                println!("recording GIF to {}", path);
+                Some(recorder)
+            },
+            Err(e) => {
+                println!("unable to start recording GIF: {}", e);
+                None
+            },
+        }
+    }
+
+    fn stop_gif(&self, recorder: GIFRecorder) {
+        let frames = recorder.frames();
+
+        match recorder.finish() {
+            Ok(_)  => println!("GIF recording stopped after {} frames", frames),
+            Err(e) => println!("unable to finish GIF: {}", e),
+        }
+    }
+
+    fn chr_palette(&mut self) -> [[u8; 3]; 4] {
+        match *NES_CHR_PALETTE {
+            Some(palette) => self.ppu.borrow_mut().palette_colors(palette),
+            None          => GREY_PALETTE,
+        }
+    }
+
+    // Exports CHR to disk, both as raw data and as a PNG. Either all of the
+    // cartridge's CHR, every bank of it, or just the 8KB the PPU can currently
+    // see through the mapper.
+    fn export_chr(&mut self, all_banks: bool) {
+        let data = if all_banks {
+            self.cartridge.borrow_mut().chr().to_vec()
+        } else {
+            let mut mapper = self.cartridge.borrow_mut();
+            (0 ..= 0x1fff).map(|addr| mapper.read(addr)).collect::<Vec<u8>>()
+        };
+
+        if data.is_empty() {
+            println!("Sorry! This cartridge doesn't have any CHR to export.");
             return;
         }
 
-        if let Ok(mut fh) = File::create("tileset.chr") {
-            let mut chr = [0; 0x2000];
+        let kind = if all_banks { "chr" } else { "chr-view" };
+        let palette = self.chr_palette();
 
-            for x in 0 ..= 0x1fff {
-                let b = self.cartridge.borrow_mut().read(x);
-                chr[x as usize] = b;
-            }
+        let chr_path = self.output_path(kind, "chr");
+        let png_path = self.output_path(kind, "png");
 
-            fh.write(&chr).unwrap();
+        let res = fs::write(&chr_path, &data)
+            .and_then(|_| fs::write(&png_path, chr::export_png(&data, &palette)));
 
-            println!("CHR saved to tileset.chr");
+        match res {
+            Ok(_)  => println!("CHR saved to {} and {}", chr_path, png_path),
+            Err(e) => println!("unable to save CHR: {}", e),
         }
     }
 
+    // Replaces the cartridge's CHR with the file in NES_CHR_IMPORT, either
+    // raw CHR data or a PNG, to preview graphics changes without rebuilding
+    // the ROM. The file has to cover all of the cartridge's CHR.
+    fn import_chr(&mut self) {
+        let path = match *NES_CHR_IMPORT {
+            Some(ref path) => path,
+            None => {
+                println!("Sorry! Set NES_CHR_IMPORT to the file to import first.");
+                return;
+            },
+        };
+
+        if self.cartridge.borrow_mut().chr().is_empty() {
+            println!("Sorry! This cartridge doesn't have any CHR to import over.");
+            return;
+        }
+
+        let mut contents = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut fh| fh.read_to_end(&mut contents)) {
+            println!("unable to read {}: {}", path, e);
+            return;
+        }
+
+        let data = if path.to_lowercase().ends_with(".png") {
+            let palette = self.chr_palette();
+            chr::import_png(&contents, &palette)
+        } else {
+            Ok(contents)
+        };
+
+        let res = data.and_then(|data| chr::import(self.cartridge.borrow_mut().chr(), &data));
+
+        match res {
+            Ok(_)  => println!("CHR imported from {}", path),
+            Err(e) => println!("unable to import {}: {}", path, e),
+        }
+    }
+
+    // Dump the PPU events from the last frame to disk, one per line, in a
+    // file named after the ROM.
+    //
+    // Events are only recorded while the event viewer is open.
+    fn dump_events(&mut self) {
+        let path = self.output_path("events", "txt");
+
+        let text: String = self.ppu.borrow().frame_events().iter()
+            .map(|event| format!("{}\n", event))
+            .collect();
+
+        match fs::write(&path, text) {
+            Ok(_)  => println!("PPU events saved to {}", path),
+            Err(e) => println!("unable to save PPU events: {}", e),
+        }
+    }
+
+    // Removing the sprite limit gets rid of flickering in games that have
+    // lots of sprites on the screen at once, but some games deliberately hide
+    // sprites using the limit.
+    fn toggle_sprite_limit(&mut self) {
+        let mut ppu = self.ppu.borrow_mut();
+        let limit = !ppu.sprite_limit();
+        ppu.set_sprite_limit(limit);
+
+        println!("sprite limit {}", if limit { "enabled" } else { "disabled" });
+    }
+
     // Reads a null-terminated string starting at `addr'
     fn read_string(&mut self, addr: u16) -> String {
         let mut addr = addr;
@@ -167,14 +531,165 @@ impl Console {
         }
     }
 
+    // Runs the CPU for one instruction, and everything else for as long as
+    // it took. Any audio samples the APU produced are added to `signals', and
+    // true is returned if the PPU finished a frame.
+    fn step(&mut self, signals: &mut Vec<(f32, f32)>) -> bool {
+        let cpu_cycles = self.cpu.step();
+        let apu_cycles = cpu_cycles;
+
+        if let Some(nsf) = self.nsf.as_mut() {
+            nsf.tick(&mut self.cpu, cpu_cycles);
+        }
+
+        // NSF tracks fade out at the end
+        let gain = self.nsf.as_ref().map_or(1.0, |nsf| nsf.gain());
+
+        self.cartridge.borrow_mut()
+            .notify(MapperEvent::CPUTick(cpu_cycles));
+
+        // Any sound chip on the cartridge is mixed in with the APU's
+        // channels, at its level as of the end of the instruction
+        let expansion = self.cartridge.borrow().audio();
+        self.apu.borrow_mut().set_expansion_audio(expansion);
+
+        // The PPU may have already run some of these cycles, if the
+        // CPU accessed one of its registers.
+        let (dots_ahead, res) = self.ppu.borrow_mut().take_caught_up();
+        let ppu_cycles = self.ppu.borrow_mut().clock(cpu_cycles);
+
+        if self.cartridge.borrow().irq_flag() {
+            self.cpu.trigger_irq();
+        }
+
+        if res.trigger_nmi {
+            self.cpu.trigger_nmi();
+        }
+
+        let mut frame_finished = res.frame_finished;
+        for _ in dots_ahead .. ppu_cycles {
+            let res = self.ppu.borrow_mut().step();
+
+            if self.cartridge.borrow().irq_flag() {
+                self.cpu.trigger_irq();
+            }
+
+            if res.trigger_nmi {
+                self.cpu.trigger_nmi();
+            }
+
+            if res.frame_finished {
+                frame_finished = true;
+            }
+        }
+
+        // Like the PPU, the APU may have already run some of these
+        // cycles, if the CPU accessed one of its registers.
+        let apu = self.apu.clone();
+        let (apu_ahead, apu_res) = apu.borrow_mut().take_caught_up();
+        let apu_results = std::iter::once(apu_res)
+            .chain((apu_ahead .. apu_cycles).map(|_| apu.borrow_mut().step()));
+
+        for res in apu_results {
+            if res.trigger_irq {
+                self.cpu.trigger_irq();
+            }
+
+            // The DMC reads its samples through the CPU bus, which
+            // stalls the CPU for 4 cycles
+            if let Some(addr) = res.dmc_read {
+                let val = self.cpu.read(addr);
+                self.apu.borrow_mut().fill_dmc_buffer(val);
+                self.cpu.stall(4);
+            }
+
+            for (left, right) in res.signals {
+                signals.push((left * gain, right * gain));
+            }
+        }
+
+        frame_finished
+    }
+
+    // Renders NSF tracks to WAV files without opening a window, in playlist
+    // order. Either the track picked with NES_NSF_TRACK is rendered, or all
+    // of them are. Tracks that the metadata doesn't give a length for are
+    // `seconds' long.
+    pub fn render_nsf(&mut self, seconds: f64) {
+        let positions: Vec<usize> = match self.nsf.as_ref() {
+            Some(nsf) => (0 .. nsf.nsf().playlist.len()).collect(),
+            None      => {
+                println!("Only NSF files can be rendered.");
+                process::exit(1);
+            },
+        };
+
+        self.cpu.reset();
+
+        for position in positions {
+            self.apu.borrow_mut().reset();
+
+            let nsf = self.nsf.as_mut().unwrap();
+            match *NES_NSF_TRACK {
+                Some(track) => nsf.start_track(&mut self.cpu, track.saturating_sub(1)),
+                None        => nsf.start_position(&mut self.cpu, position),
+            }
+
+            // Files are numbered in playlist order, and named after the
+            // track's title if it has one
+            let mut name = format!("{:02}", nsf.position() + 1);
+            if let Some(title) = nsf.track_title() {
+                let words: Vec<&str> = title.split(|c: char| !c.is_ascii_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect();
+
+                name = format!("{}-{}", name, words.join("_").to_lowercase());
+            }
+
+            let track = nsf.track() + 1;
+            let path = self.output_path(&format!("track{}", name), "wav");
+
+            match self.render_track(&path, seconds) {
+                Ok(_)  => println!("track {} saved to {}", track, path),
+                Err(e) => println!("unable to save track {}: {}", track, e),
+            }
+
+            if NES_NSF_TRACK.is_some() {
+                break;
+            }
+        }
+    }
+
+    // Renders the current NSF track until it's finished fading out, or for
+    // `seconds' if it doesn't have a length
+    fn render_track(&mut self, path: &str, seconds: f64) -> io::Result<()> {
+        let mut wav = WAVWriter::new_wav_writer(path, self.apu.borrow().sample_rate())?;
+        let mut signals = Vec::new();
+
+        loop {
+            let nsf = self.nsf.as_ref().unwrap();
+            if nsf.finished() || (nsf.track_length().is_none() && nsf.elapsed() >= seconds) {
+                break;
+            }
+
+            self.step(&mut signals);
+
+            for signal in signals.drain(..) {
+                wav.write_sample(signal)?;
+            }
+        }
+
+        wav.finish()
+    }
+
     pub fn power_up(&mut self) {
         info!("powering up");
 
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
-        let mut width = 256 * 3;
-        let height = 240 * 3;
+        let mut width = 256 * SCREEN_SCALE as u32;
+        let height = 240 * SCREEN_SCALE as u32;
 
         if *NES_PPU_DEBUG {
             // Make room for the two pattern tables, side by side
@@ -186,11 +701,15 @@ impl Console {
             .build()
             .unwrap();
 
-        let mut canvas = window.into_canvas()
-            .target_texture()
-            .build()
-            .unwrap();
+        let mut canvas_builder = window.into_canvas().target_texture();
+
+        if *NES_VSYNC {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+
+        let mut canvas = canvas_builder.build().unwrap();
         debug!("canvas: {}", canvas.info().name);
+        let main_window_id = canvas.window().id();
         let texture_creator = canvas.texture_creator();
         let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height)
             .unwrap();
@@ -206,21 +725,55 @@ impl Console {
         debug!("audio driver: {}", audio_subsystem.current_audio_driver());
 
         let desired_spec = AudioSpecDesired {
-            freq:     Some(44_100),
-            channels: Some(2),
-            samples:  Some(1024),
+            freq:     Some(*NES_AUDIO_RATE as i32),
+            channels: Some(*NES_AUDIO_CHANNELS),
+            samples:  Some(*NES_AUDIO_BUFFER),
         };
-        let audio_device = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+        let audio_device = audio_subsystem.open_queue::<f32, _>(None, &desired_spec).unwrap();
         audio_device.resume();
+
+        let audio_spec = *audio_device.spec();
+        let sample_rate = audio_spec.freq as u32;
+        let channels = audio_spec.channels as usize;
+        debug!("audio: {}Hz, {} channels, {} samples", sample_rate, channels, audio_spec.samples);
+
+        self.apu.borrow_mut().set_sample_rate(sample_rate);
+
+        let mut signals = Vec::new();
         let mut samples = Vec::new();
-        let mut audio_sampling = true;
+        let mut output_samples = Vec::new();
+        let mut audio_sync = AudioSync::new_audio_sync(sample_rate, *NES_AUDIO_LATENCY);
+
+        // Muting doesn't stop the APU, so the sound carries on where it
+        // should be when unmuted
+        let mut volume = *NES_AUDIO_VOLUME;
+        let mut muted = false;
 
         self.cpu.reset();
 
+        // NSF music has no reset vector of its own, so the player takes over
+        // from here
+        if let Some(nsf) = self.nsf.as_mut() {
+            let track = NES_NSF_TRACK.map_or(nsf.track(), |track| track.saturating_sub(1));
+            nsf.start_track(&mut self.cpu, track);
+        }
+
+        let mut pacer = FramePacer::new_frame_pacer(self.region.frame_rate(), *NES_VSYNC);
+
         let mut event_pump = sdl_context.event_pump().unwrap();
-        let mut fps_start = Instant::now();
         let mut paused = false;
 
+        // Debug windows, opened and closed with the function keys
+        let mut nametable_viewer: Option<NametableViewer> = None;
+        let mut sprite_viewer: Option<SpriteViewer> = None;
+        let mut event_viewer: Option<EventViewer> = None;
+        let mut apu_viewer: Option<APUViewer> = None;
+
+        // Video and audio recording, started and stopped with F1, and GIF
+        // recording with Shift+F1
+        let mut recorder = if *NES_RECORD { self.start_recording() } else { None };
+        let mut gif_recorder: Option<GIFRecorder> = None;
+
         'running: loop {
             let mut poll_keyboard = false;
             self.debug_tests();
@@ -229,112 +782,146 @@ impl Console {
                 poll_keyboard = true;
                 thread::sleep(Duration::from_millis(200));
             } else {
-                let cpu_cycles = self.cpu.step();
-                let ppu_cycles = cpu_cycles * 3;
-                let apu_cycles = cpu_cycles;
-
-                self.cartridge.borrow_mut()
-                    .notify(MapperEvent::CPUTick(cpu_cycles));
-
-                let mut frame_finished = false;
-                for _ in 0 .. ppu_cycles {
-                    let res = self.ppu.borrow_mut().step();
-
-                    if self.cartridge.borrow().irq_flag() {
-                        self.cpu.trigger_irq();
+                let frame_finished = self.step(&mut signals);
+
+                for signal in signals.drain(..) {
+                    // Samples are recorded as the APU made them, before
+                    // they're resampled for the audio device below, so the
+                    // recording stays in sync with the video whatever the
+                    // speed or rate control does
+                    if let Some(Err(e)) = recorder.as_mut().map(|rec| rec.record_sample(signal)) {
+                        println!("unable to record audio: {}", e);
+                        self.stop_recording(recorder.take().unwrap());
                     }
 
-                    if res.trigger_nmi {
-                        self.cpu.trigger_nmi();
-                    }
+                    audio_sync.push(signal, &mut samples);
+                }
 
-                    if res.frame_finished {
-                        frame_finished = true;
+                if frame_finished {
+                    // NSF tracks that have a length move on to the next one in
+                    // the playlist when they finish
+                    if let Some(nsf) = self.nsf.as_mut() {
+                        if nsf.finished() {
+                            nsf.next_track(&mut self.cpu);
+                            println!("track {} of {}", nsf.track() + 1, nsf.nsf().songs);
+                        }
                     }
-                }
 
-                for _ in 0 .. apu_cycles {
-                    let res = self.apu.borrow_mut().step();
+                    let mut queued = audio_device.size() as usize / (4 * channels);
 
-                    if res.trigger_irq {
-                        self.cpu.trigger_irq();
+                    // If the queue ran dry (when starting up, or after being
+                    // paused), fill it back up to the target with silence,
+                    // rather than waiting for rate control to slowly fill it
+                    if queued == 0 {
+                        audio_device.queue(&vec![0.0; audio_sync.target() * channels]);
+                        queued = audio_sync.target();
                     }
 
-                    if let Some(signal) = res.signal {
-                        if audio_sampling {
-                            samples.push(signal);
-                            samples.push(signal);
+                    // Mono devices get both sides mixed together, and any
+                    // channels past the first two are left silent
+                    let gain = if muted { 0.0 } else { volume as f32 / 100.0 };
+
+                    output_samples.clear();
+                    for (left, right) in samples.iter() {
+                        if channels == 1 {
+                            output_samples.push((left + right) / 2.0 * gain);
+                            continue;
                         }
-                    }
-                }
 
-                // Super basic dynamic sampling implementation.
-                //
-                // If the number of samples is too low, we'll end up with
-                // crackling and popping because the audio backend is consuming
-                // the samples faster than we can produce them, but if we have
-                // too many samples, the audio will get more and more out of
-                // sync with the video.
-                //
-                // We want to keep the audio queue full of samples, and we want
-                // to maintain at roughly AUDIO_QUEUE_HIGH_WATER_MARK samples.
-                // So if we've got more than that many in the queue, we stop
-                // sampling, and if we drop below, we start sampling again.
-                //
-                // This is much better than past attempts, and only
-                // occasionally results in some cracking and popping. I can
-                // live with this for now :)
-                if audio_sampling && audio_device.size() > AUDIO_QUEUE_HIGH_WATER_MARK {
-                    audio_sampling = false;
-                }
+                        output_samples.push(left * gain);
+                        output_samples.push(right * gain);
 
-                if !audio_sampling && audio_device.size() < AUDIO_QUEUE_HIGH_WATER_MARK {
-                    audio_sampling = true;
-                }
+                        let len = output_samples.len();
+                        output_samples.resize(len + channels - 2, 0.0);
+                    }
 
-                if frame_finished {
-                    audio_device.queue(&samples);
+                    audio_device.queue(&output_samples);
                     samples.clear();
-                    audio_sampling = true;
 
-                    let mut ppu = self.ppu.borrow_mut();
-                    let pixels  = ppu.get_pixels();
+                    audio_sync.set_speed(pacer.speed());
+                    audio_sync.update(queued);
+
+                    // Anything recorded before an error is still finished
+                    // off, so that it can be played
+                    if let Some(Err(e)) = recorder.as_mut().map(|rec| rec.record_frame(self.ppu.borrow().get_pixels())) {
+                        println!("unable to record video: {}", e);
+                        self.stop_recording(recorder.take().unwrap());
+                    }
+
+                    if let Some(Err(e)) = gif_recorder.as_mut().map(|rec| rec.record_frame(self.ppu.borrow().get_pixels())) {
+                        println!("unable to record GIF: {}", e);
+                        self.stop_gif(gif_recorder.take().unwrap());
+                    }
+
+                    // When fast-forwarding, some frames may not be drawn
+                    if pacer.should_present() {
+                        let mut ppu = self.ppu.borrow_mut();
+                        let pixels  = ppu.get_pixels();
 
-                    texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                        for y in 0 .. 240 {
-                            for x in 0 .. 256 {
-                                let color  = pixels[y][x];
-                                let offset = 3*(y*pitch) + 3*(x*3);
+                        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                            for y in 0 .. 240 {
+                                for x in 0 .. 256 {
+                                    let color  = pixels[y][x];
+                                    let offset = SCREEN_SCALE*(y*pitch) + 3*(x*SCREEN_SCALE);
 
-                                for y2 in 0 .. 3 {
-                                    let offset = offset + (y2 * pitch);
+                                    for y2 in 0 .. SCREEN_SCALE {
+                                        let offset = offset + (y2 * pitch);
 
-                                    for x2 in 0 .. 3 {
-                                        let offset = offset + (x2 * 3);
+                                        for x2 in 0 .. SCREEN_SCALE {
+                                            let offset = offset + (x2 * 3);
 
-                                        buffer[offset]   = color.r;
-                                        buffer[offset+1] = color.g;
-                                        buffer[offset+2] = color.b;
+                                            buffer[offset]   = color.r;
+                                            buffer[offset+1] = color.g;
+                                            buffer[offset+2] = color.b;
+                                        }
                                     }
                                 }
                             }
+                        }).unwrap();
+
+                        canvas.clear();
+                        canvas.copy(&texture, None, None).unwrap();
+
+                        if let Some(nsf) = self.nsf.as_ref() {
+                            let mut lines = nsf.describe();
+                            lines.push(String::new());
+                            lines.push("left/right: change track".to_string());
+
+                            canvas.set_draw_color(Color::RGB(255, 255, 255));
+                            for (i, line) in lines.iter().enumerate() {
+                                viewer::draw_text(&mut canvas, line, 24, 24 + 32 * i as i32, 4);
+                            }
                         }
-                    }).unwrap();
 
-                    canvas.clear();
-                    canvas.copy(&texture, None, None).unwrap();
+                        if *NES_PPU_DEBUG {
+                            ppu.render_tile_data(&mut canvas);
+                            ppu.render_tile_borders(&mut canvas);
+                        }
 
-                    if *NES_PPU_DEBUG {
-                        ppu.render_tile_data(&mut canvas);
-                        ppu.render_tile_borders(&mut canvas);
-                    }
+                        if let Some(viewer) = sprite_viewer.as_ref() {
+                            viewer.highlight_sprites(&mut canvas, &mut ppu, SCREEN_SCALE as u32);
+                        }
+
+                        canvas.present();
+
+                        if let Some(viewer) = nametable_viewer.as_mut() {
+                            viewer.render(&mut ppu);
+                        }
+
+                        if let Some(viewer) = sprite_viewer.as_mut() {
+                            viewer.render(&mut ppu);
+                        }
+
+                        if let Some(viewer) = event_viewer.as_mut() {
+                            viewer.render(&mut ppu);
+                        }
 
-                    canvas.present();
-                    if let Some(delay) = FRAME_DURATION.checked_sub(fps_start.elapsed()) {
-                        thread::sleep(delay);
+                        if let Some(viewer) = apu_viewer.as_mut() {
+                            viewer.render(&mut self.apu.borrow_mut());
+                        }
                     }
 
-                    fps_start = Instant::now();
+                    pacer.wait();
 
                     // Polling for events once per loop slows the emulator
                     // right the fuck down, so I've moved to when a frame has
@@ -349,7 +936,73 @@ impl Console {
                     match event {
                         Event::Quit { .. } => { break 'running },
 
-                        Event::KeyDown { keycode: Some(key), .. } => {
+                        // With more than one window open, closing one of them
+                        // doesn't quit, so we have to work out which it was.
+                        Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                            if window_id == main_window_id {
+                                break 'running;
+                            }
+
+                            if nametable_viewer.as_ref().map(|v| v.window_id()) == Some(window_id) {
+                                nametable_viewer = None;
+                            }
+
+                            if sprite_viewer.as_ref().map(|v| v.window_id()) == Some(window_id) {
+                                sprite_viewer = None;
+                            }
+
+                            if apu_viewer.as_ref().map(|v| v.window_id()) == Some(window_id) {
+                                apu_viewer = None;
+                                self.apu.borrow_mut().set_scope_recording(false);
+                            }
+
+                            if event_viewer.as_ref().map(|v| v.window_id()) == Some(window_id) {
+                                event_viewer = None;
+                                self.ppu.borrow_mut().set_event_recording(false);
+                            }
+                        },
+
+                        Event::Window { window_id, win_event: WindowEvent::Leave, .. } => {
+                            if let Some(viewer) = nametable_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_left();
+                                }
+                            }
+
+                            if let Some(viewer) = sprite_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_left();
+                                }
+                            }
+
+                            if let Some(viewer) = event_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_left();
+                                }
+                            }
+                        },
+
+                        Event::MouseMotion { window_id, x, y, .. } => {
+                            if let Some(viewer) = nametable_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_moved(x, y);
+                                }
+                            }
+
+                            if let Some(viewer) = sprite_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_moved(x, y);
+                                }
+                            }
+
+                            if let Some(viewer) = event_viewer.as_mut() {
+                                if viewer.window_id() == window_id {
+                                    viewer.mouse_moved(x, y);
+                                }
+                            }
+                        },
+
+                        Event::KeyDown { keycode: Some(key), keymod, .. } => {
                             match key {
                                 Keycode::W => { self.controller.borrow_mut().up(true) },
                                 Keycode::A => { self.controller.borrow_mut().left(true) },
@@ -362,16 +1015,146 @@ impl Console {
                                 Keycode::N => { self.controller.borrow_mut().a(true) },
                                 Keycode::M => { self.controller.borrow_mut().b(true) },
 
-                                Keycode::P => { paused = ! paused },
+                                Keycode::Left | Keycode::Right if self.nsf.is_some() => {
+                                    let nsf = self.nsf.as_mut().unwrap();
+
+                                    if key == Keycode::Left {
+                                        nsf.previous_track(&mut self.cpu);
+                                    } else {
+                                        nsf.next_track(&mut self.cpu);
+                                    }
+
+                                    println!("track {} of {}", nsf.track() + 1, nsf.nsf().songs);
+                                },
+
+                                Keycode::P => {
+                                    paused = ! paused;
+                                    pacer.reset();
+                                },
+
+                                Keycode::Minus  => { pacer.slower(); println!("speed: {}x", pacer.speed()) },
+                                Keycode::Equals => { pacer.faster(); println!("speed: {}x", pacer.speed()) },
+                                Keycode::Num0   => { pacer.normal_speed(); println!("speed: {}x", pacer.speed()) },
+
+                                Keycode::Comma => {
+                                    volume = volume.saturating_sub(VOLUME_STEP);
+                                    println!("volume: {}%", volume);
+                                },
+
+                                Keycode::Period => {
+                                    volume = (volume + VOLUME_STEP).min(100);
+                                    println!("volume: {}%", volume);
+                                },
+
+                                Keycode::Slash => {
+                                    muted = !muted;
+                                    println!("sound {}", if muted { "muted" } else { "unmuted" });
+                                },
+
+                                Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4 | Keycode::Num5 | Keycode::Num7 => {
+                                    let channel = match key {
+                                        Keycode::Num7 => Channel::Expansion,
+                                        _             => CHANNELS[key as usize - Keycode::Num1 as usize],
+                                    };
+                                    let mut apu = self.apu.borrow_mut();
+                                    let mixer = apu.mixer();
+                                    let channel_muted = !mixer.muted(channel);
+
+                                    mixer.set_muted(channel, channel_muted);
+                                    println!("{} {}", channel, if channel_muted { "muted" } else { "unmuted" });
+                                },
+
+                                Keycode::Num6 => {
+                                    let mut apu = self.apu.borrow_mut();
+                                    let mixer = apu.mixer();
+                                    let mode = match mixer.mode() {
+                                        MixMode::NonLinear => MixMode::Linear,
+                                        MixMode::Linear    => MixMode::NonLinear,
+                                    };
+
+                                    mixer.set_mode(mode);
+                                    println!("mixing: {}", mode);
+                                },
+
+                                Keycode::F1 if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                                    gif_recorder = match gif_recorder.take() {
+                                        Some(rec) => { self.stop_gif(rec); None },
+                                        None      => self.start_gif(),
+                                    };
+                                },
+
+                                Keycode::F1 => {
+                                    recorder = match recorder.take() {
+                                        Some(rec) => { self.stop_recording(rec); None },
+                                        None      => self.start_recording(),
+                                    };
+                                },
 
                                 Keycode::F2 => { self.save() },
                                 Keycode::F3 => { self.load() },
 
-                                Keycode::F9 => { self.dump_chr() },
+                                Keycode::F4 => {
+                                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                                    self.screenshot(shift);
+                                },
+
+                                Keycode::F5 => {
+                                    nametable_viewer = match nametable_viewer {
+                                        Some(_) => None,
+                                        None    => Some(NametableViewer::new_nametable_viewer(&video_subsystem)),
+                                    };
+                                },
+
+                                Keycode::F6 => {
+                                    sprite_viewer = match sprite_viewer {
+                                        Some(_) => None,
+                                        None    => Some(SpriteViewer::new_sprite_viewer(&video_subsystem)),
+                                    };
+                                },
+
+                                Keycode::F7 if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                                    apu_viewer = match apu_viewer {
+                                        Some(_) => None,
+                                        None    => Some(APUViewer::new_apu_viewer(&video_subsystem)),
+                                    };
+
+                                    self.apu.borrow_mut().set_scope_recording(apu_viewer.is_some());
+                                },
+
+                                Keycode::F7 => {
+                                    let region = self.ppu.borrow().region();
+                                    event_viewer = match event_viewer {
+                                        Some(_) => None,
+                                        None    => Some(EventViewer::new_event_viewer(&video_subsystem, region)),
+                                    };
+
+                                    self.ppu.borrow_mut().set_event_recording(event_viewer.is_some());
+                                },
+
+                                Keycode::F8 => { self.toggle_sprite_limit() },
+                                Keycode::F9 => {
+                                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                                    self.export_chr(!shift);
+                                },
+
+                                Keycode::F10 => {
+                                    if event_viewer.is_some() {
+                                        self.dump_events();
+                                    } else {
+                                        println!("Sorry! This can only be done with the event viewer open.");
+                                    }
+                                },
+
+                                Keycode::F11 => { self.import_chr() },
 
                                 Keycode::F12 => {
                                     self.cpu.reset();
                                     self.apu.borrow_mut().reset();
+
+                                    if let Some(nsf) = self.nsf.as_mut() {
+                                        let track = nsf.track();
+                                        nsf.start_track(&mut self.cpu, track);
+                                    }
                                 },
 
                                 _ => {},
@@ -402,6 +1185,14 @@ impl Console {
             }
         }
 
+        if let Some(rec) = recorder {
+            self.stop_recording(rec);
+        }
+
+        if let Some(rec) = gif_recorder {
+            self.stop_gif(rec);
+        }
+
         info!("powering down");
     }
 }
@@ -64,10 +64,14 @@ pub struct CPU {
 
     // Total number of cycles executed
     cycles: u64,
+
+    // The value of `cycles' at the start of the current step
+    step_cycles: u64,
 }
 
 impl Memory for CPU {
     fn read(&mut self, addr: u16) -> u8 {
+        self.sync_mem();
         self.mem.read(addr)
     }
 
@@ -75,6 +79,7 @@ impl Memory for CPU {
         if addr == 0x4014 {
             self.dma(val);
         } else {
+            self.sync_mem();
             self.mem.write(addr, val);
         }
     }
@@ -154,6 +159,7 @@ impl CPU {
 
             stall: None,
             cycles: 0,
+            step_cycles: 0,
         }
     }
 
@@ -178,13 +184,16 @@ impl CPU {
 
     fn dma(&mut self, val: u8) {
         let addr_base = (val as u16) << 8;
+        let mut data = [0; 256];
 
         for lo_nyb in 0x00 ..= 0xff {
             let addr = addr_base | lo_nyb;
-            let val = self.read(addr);
-            self.mem.write(0x2004, val);
+            data[lo_nyb as usize] = self.read(addr);
         }
 
+        self.sync_mem();
+        self.mem.oam_dma(val, &data);
+
         if self.cycles % 2 == 1 {
             self.stall = Some(514);
         } else {
@@ -192,6 +201,15 @@ impl CPU {
         }
     }
 
+    // Tells memory how far into the current instruction we are. Accesses
+    // (at least the ones that matter) happen on the last cycle of an
+    // instruction, and the cycles for the instruction have already been
+    // counted by the time it runs.
+    fn sync_mem(&mut self) {
+        let cycles = (self.cycles - self.step_cycles).saturating_sub(1);
+        self.mem.sync(cycles);
+    }
+
     fn flags(&self) -> u8 {
            (self.c as u8)
         | ((self.z as u8) << 1)
@@ -238,6 +256,21 @@ impl CPU {
                  ppu_dots);
     }
 
+    // Calls the subroutine at `addr' with an empty stack, as if from a JSR
+    // just before `return_addr', so that it returns there. Used by the NSF
+    // player, which has no program of its own to call the music's routines.
+    pub fn call(&mut self, addr: u16, return_addr: u16) {
+        self.sp = STACK_INIT;
+        self.pc = return_addr;
+        self.jsr(addr);
+    }
+
+    // Stops the CPU for a number of cycles, while something else (like the
+    // DMC) uses the bus
+    pub fn stall(&mut self, cycles: u64) {
+        self.stall = Some(self.stall.unwrap_or(0) + cycles);
+    }
+
     pub fn trigger_nmi(&mut self) {
         self.interrupt = Some(Interrupt::NMI);
     }
@@ -339,6 +372,7 @@ impl CPU {
         }
 
         let start_cycles = self.cycles;
+        self.step_cycles = start_cycles;
 
         // Process pending interrupts.
         match self.interrupt {
@@ -362,13 +396,15 @@ impl CPU {
         self.pc += bytes as u16;
         self.cycles += cycles as u64;
 
+        // The extra cycle for crossing a page is spent before the access
+        // itself, so it's counted before the instruction runs.
         let (addr, page_crossed) = addr_mode.get_data(self);
-        inst.run(self, addr, addr_mode);
-
         if page_crossed {
             self.cycles += extra_cycles as u64;
         }
 
+        inst.run(self, addr, addr_mode);
+
         self.cycles - start_cycles
     }
 
@@ -938,15 +974,43 @@ impl CPU {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ppu::PPU;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::apu::APU;
     use crate::controller::Controller;
+    use crate::mapper::{Mapper, Mapper0};
+    use crate::mem::NESMemory;
+    use crate::ppu::PPU;
+    use crate::region::Region;
+
+    // A console running `program' from $8000, with the vectors all pointing
+    // at $DEAD
+    fn new_nes(program: &[u8], region: Region) -> (CPU, Rc<RefCell<PPU>>) {
+        let mut prg = vec![0xea; 0x8000];
+        prg[.. program.len()].copy_from_slice(program);
+        prg[0x7ffa .. 0x8000].copy_from_slice(&[0xad, 0xde, 0x00, 0x80, 0xad, 0xde]);
+
+        let mapper: Box<dyn Mapper> = Box::new(Mapper0::new_mapper(prg, vec![0; 0x2000], 0));
+        let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(Rc::new(RefCell::new(mapper)), region)));
+        let apu = Rc::new(RefCell::new(APU::new_nes_apu(region)));
+        let controller = Rc::new(RefCell::new(Controller::new_controller()));
+
+        let mem = NESMemory::new_nes_mem(ppu.clone(), apu, controller);
+        let mut cpu = CPU::new_cpu(Box::new(mem));
+        cpu.pc = 0x8000;
+
+        (cpu, ppu)
+    }
+
+    fn new_test_cpu() -> CPU {
+        new_nes(&[], Region::NTSC).0
+    }
 
     #[test]
     fn test_stack_pop_empty() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu();
         let _ = cpu.stack_pop8();
         assert_eq!(cpu.sp, STACK_INIT + 1);
 
@@ -960,10 +1024,7 @@ mod tests {
 
     #[test]
     fn test_stack_push_full() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu();
 
         for _ in 0 .. STACK_INIT {
             cpu.stack_push8(0xff);
@@ -978,19 +1039,16 @@ mod tests {
 
     #[test]
     fn test_stack() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu();
 
         cpu.stack_push8(0xff);
         assert_eq!(cpu.sp, 0xfc);
-        assert_eq!(cpu.mem.ram[0x0100 + (cpu.sp as usize) + 1], 0xff);
+        assert_eq!(cpu.read(0x0100 + (cpu.sp as u16) + 1), 0xff);
 
         cpu.stack_push16(0xdead);
         assert_eq!(cpu.sp, 0xfa);
-        assert_eq!(cpu.mem.ram[0x100 + (cpu.sp as usize) + 1], 0xad);
-        assert_eq!(cpu.mem.ram[0x100 + (cpu.sp as usize) + 2], 0xde);
+        assert_eq!(cpu.read(0x100 + (cpu.sp as u16) + 1), 0xad);
+        assert_eq!(cpu.read(0x100 + (cpu.sp as u16) + 2), 0xde);
 
         let rv = cpu.stack_pop16();
         assert_eq!(cpu.sp, 0xfc);
@@ -1003,10 +1061,7 @@ mod tests {
 
     #[test]
     fn test_flags() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu();
 
         assert_eq!(cpu.flags(), 0x00);
 
@@ -1022,17 +1077,119 @@ mod tests {
 
     #[test]
     fn test_nmi() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
-
-        let mut rom = vec![0; 0xffff];
-        rom[0xfffa] = 0xad;
-        rom[0xfffb] = 0xde;
-        cpu.mem.load_rom(&rom);
+        let mut cpu = new_test_cpu();
+
         cpu.nmi();
         assert_eq!(cpu.pc, 0xdead);
         assert!(cpu.i);
     }
+
+    // The number of dots the PPU had been caught up by during the last
+    // instruction
+    fn dots_ahead(ppu: &Rc<RefCell<PPU>>) -> u64 {
+        ppu.borrow_mut().take_caught_up().0
+    }
+
+    #[test]
+    fn test_register_write_catches_up() {
+        // STA $2003 writes on its 4th cycle, which starts 3 cycles in
+        let (mut cpu, ppu) = new_nes(&[0x8d, 0x03, 0x20], Region::NTSC);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(dots_ahead(&ppu), 9);
+
+        // 3.2 dots per cycle on PAL
+        let (mut cpu, ppu) = new_nes(&[0x8d, 0x03, 0x20], Region::PAL);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(dots_ahead(&ppu), 9);
+
+        // STA $2003,X always takes the extra cycle, and writes on its 5th
+        let (mut cpu, ppu) = new_nes(&[0x9d, 0x03, 0x20], Region::PAL);
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(dots_ahead(&ppu), 12);
+
+        // Anything else leaves the PPU to the console
+        let (mut cpu, ppu) = new_nes(&[0x8d, 0x00, 0x03], Region::NTSC);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(dots_ahead(&ppu), 0);
+    }
+
+    #[test]
+    fn test_page_cross_read_timing() {
+        // LDA $2002,X without crossing a page reads on the 4th cycle...
+        let (mut cpu, ppu) = new_nes(&[0xbd, 0x02, 0x20], Region::NTSC);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(dots_ahead(&ppu), 9);
+
+        // ...and on the 5th when it does, as the extra cycle comes first
+        let (mut cpu, ppu) = new_nes(&[0xbd, 0xf2, 0x1f], Region::NTSC);
+        cpu.x = 0x10;
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(dots_ahead(&ppu), 12);
+
+        // Same for (indirect),Y, which reads on the 5th or 6th cycle
+        let (mut cpu, ppu) = new_nes(&[0xb1, 0x00], Region::NTSC);
+        cpu.write(0x0000, 0xf2);
+        cpu.write(0x0001, 0x1f);
+        cpu.y = 0x10;
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(dots_ahead(&ppu), 15);
+    }
+
+    // The number of cycles the CPU is stalled for after an instruction,
+    // before it runs the next one
+    fn stalled_cycles(cpu: &mut CPU) -> u64 {
+        let mut cycles = 0;
+
+        while cpu.step() == 1 {
+            cycles += 1;
+        }
+
+        cycles
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$02; STA $4014, starting on an even cycle
+        let (mut cpu, ppu) = new_nes(&[0xa9, 0x02, 0x8d, 0x14, 0x40], Region::NTSC);
+
+        for i in 0 .. 256 {
+            cpu.write(0x0200 + i, i as u8 ^ 0x5a);
+        }
+
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(stalled_cycles(&mut cpu), 513);
+
+        // Bits 2-4 of each sprite's attributes don't exist
+        let mut ppu = ppu.borrow_mut();
+        for i in 0 .. 256 {
+            let mask = if i % 4 == 2 { 0xe3 } else { 0xff };
+
+            ppu.write(0x2003, i as u8);
+            assert_eq!(ppu.read(0x2004), (i as u8 ^ 0x5a) & mask);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_odd_cycle() {
+        // LDA $00; STA $4014, starting on an odd cycle
+        let (mut cpu, _) = new_nes(&[0xa5, 0x00, 0x8d, 0x14, 0x40], Region::NTSC);
+
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(stalled_cycles(&mut cpu), 514);
+    }
+
+    #[test]
+    fn test_stall() {
+        // The DMC's reads stall the CPU for 4 cycles each, on top of any
+        // stall that's already going
+        let (mut cpu, _) = new_nes(&[0xa9, 0x02, 0x8d, 0x14, 0x40], Region::NTSC);
+        cpu.stall(4);
+        assert_eq!(stalled_cycles(&mut cpu), 4);
+
+        assert_eq!(cpu.step(), 4);
+        cpu.stall(4);
+        assert_eq!(stalled_cycles(&mut cpu), 517);
+    }
 }
@@ -0,0 +1,289 @@
+// A minimal animated GIF encoder.
+//
+// https://www.w3.org/Graphics/GIF/spec-gif89a.txt
+//
+// Every frame shares one global colour table, and is written out in full,
+// compressed with LZW. The NES only has 64 colours, so frames never need to be
+// quantised or dithered, and nothing is lost.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+// LZW codes can't be longer than this many bits
+const MAX_CODE_SIZE: u8 = 12;
+
+// Packs variable length codes into bytes, least significant bit first, and
+// then into sub-blocks of up to 255 bytes
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.bits |= (code as u32) << self.bit_count;
+        self.bit_count += size;
+
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+
+        self.bytes
+    }
+}
+
+fn compress(min_code_size: u8, indices: &[u8]) -> Vec<u8> {
+    let clear_code = 1u16 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut output = BitWriter { bytes: Vec::new(), bits: 0, bit_count: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut code_size = min_code_size + 1;
+    let mut next_code = end_code + 1;
+
+    output.write(clear_code, code_size);
+
+    let mut prefix = match indices.first() {
+        Some(index) => *index as u16,
+        None        => {
+            output.write(end_code, code_size);
+            return output.finish();
+        },
+    };
+
+    for index in indices[1 ..].iter() {
+        if let Some(code) = table.get(&(prefix, *index)) {
+            prefix = *code;
+            continue;
+        }
+
+        output.write(prefix, code_size);
+
+        // The decoder is always one code behind, so the code size goes up as
+        // soon as the next code needs an extra bit, rather than when it's used
+        if next_code == (1 << code_size) && code_size < MAX_CODE_SIZE {
+            code_size += 1;
+        }
+
+        if next_code < (1 << MAX_CODE_SIZE) - 1 {
+            table.insert((prefix, *index), next_code);
+            next_code += 1;
+        } else {
+            // The table is full, so start again
+            output.write(clear_code, code_size);
+            table.clear();
+            code_size = min_code_size + 1;
+            next_code = end_code + 1;
+        }
+
+        prefix = *index as u16;
+    }
+
+    output.write(prefix, code_size);
+    output.write(end_code, code_size);
+
+    output.finish()
+}
+
+pub struct GIFWriter<W: Write> {
+    w: W,
+
+    width: u16,
+    height: u16,
+
+    // How many bits each index in the colour table takes up
+    bits_per_pixel: u8,
+}
+
+impl<W: Write> GIFWriter<W> {
+    // Writes out the header. The palette is padded out to a power of 2, with
+    // at most 256 colours, and the animation loops forever.
+    pub fn new_gif_writer(mut w: W, width: u16, height: u16, palette: &[[u8; 3]]) -> io::Result<Self> {
+        let mut bits_per_pixel = 1;
+        while (1 << bits_per_pixel) < palette.len() && bits_per_pixel < 8 {
+            bits_per_pixel += 1;
+        }
+
+        w.write_all(b"GIF89a")?;
+
+        // Logical screen descriptor, with a global colour table
+        w.write_all(&width.to_le_bytes())?;
+        w.write_all(&height.to_le_bytes())?;
+        w.write_all(&[0x80 | ((bits_per_pixel - 1) << 4) | (bits_per_pixel - 1), 0, 0])?;
+
+        for i in 0 .. (1 << bits_per_pixel) {
+            w.write_all(&palette.get(i).cloned().unwrap_or([0, 0, 0]))?;
+        }
+
+        // Netscape application extension, to loop forever
+        w.write_all(&[0x21, 0xff, 11])?;
+        w.write_all(b"NETSCAPE2.0")?;
+        w.write_all(&[3, 1, 0, 0, 0])?;
+
+        Ok(Self {
+            w: w,
+            width: width,
+            height: height,
+            bits_per_pixel: bits_per_pixel,
+        })
+    }
+
+    // Writes a frame of palette indices, shown for `delay' hundredths of a
+    // second
+    pub fn write_frame(&mut self, indices: &[u8], delay: u16) -> io::Result<()> {
+        // Graphic control extension, for the delay
+        self.w.write_all(&[0x21, 0xf9, 4, 0x04])?;
+        self.w.write_all(&delay.to_le_bytes())?;
+        self.w.write_all(&[0, 0])?;
+
+        // Image descriptor, covering the whole screen
+        self.w.write_all(&[0x2c, 0, 0, 0, 0])?;
+        self.w.write_all(&self.width.to_le_bytes())?;
+        self.w.write_all(&self.height.to_le_bytes())?;
+        self.w.write_all(&[0])?;
+
+        // The minimum code size can't be less than 2, even for 2 colours
+        let min_code_size = self.bits_per_pixel.max(2);
+        let data = compress(min_code_size, indices);
+
+        self.w.write_all(&[min_code_size])?;
+        for block in data.chunks(255) {
+            self.w.write_all(&[block.len() as u8])?;
+            self.w.write_all(block)?;
+        }
+        self.w.write_all(&[0])?;
+
+        Ok(())
+    }
+
+    // Writes the trailer, after which no more frames can be written
+    pub fn finish(mut self) -> io::Result<()> {
+        self.w.write_all(&[0x3b])?;
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A straightforward LZW decoder, as a GIF viewer would have, which also
+    // returns how many clear codes it saw and the biggest code size used
+    fn decompress(min_code_size: u8, data: &[u8]) -> (Vec<u8>, usize, u8) {
+        let clear_code = 1usize << min_code_size;
+        let end_code = clear_code + 1;
+
+        let initial: Vec<Vec<u8>> = (0 .. clear_code).map(|i| vec![i as u8])
+            .chain(vec![vec![], vec![]])
+            .collect();
+
+        let mut table = initial.clone();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+
+        let mut output = Vec::new();
+        let mut clears = 0;
+        let mut max_code_size = code_size;
+
+        let mut bits = 0u32;
+        let mut bit_count = 0;
+        let mut bytes = data.iter();
+
+        loop {
+            while bit_count < code_size {
+                bits |= (*bytes.next().expect("ran out of data") as u32) << bit_count;
+                bit_count += 8;
+            }
+
+            let code = (bits & ((1 << code_size) - 1)) as usize;
+            bits >>= code_size;
+            bit_count -= code_size;
+
+            if code == clear_code {
+                table = initial.clone();
+                code_size = min_code_size + 1;
+                previous = None;
+                clears += 1;
+                continue;
+            }
+
+            if code == end_code {
+                break;
+            }
+
+            let entry = match (table.get(code), previous.as_ref()) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) if code == table.len() => {
+                    let mut entry = previous.clone();
+                    entry.push(previous[0]);
+                    entry
+                },
+                _ => panic!("bad code {}", code),
+            };
+
+            output.extend_from_slice(&entry);
+
+            if let Some(mut previous) = previous.take() {
+                if table.len() < 1 << MAX_CODE_SIZE {
+                    previous.push(entry[0]);
+                    table.push(previous);
+                }
+            }
+
+            previous = Some(entry);
+
+            if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+                max_code_size = max_code_size.max(code_size);
+            }
+        }
+
+        (output, clears, max_code_size)
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let indices: Vec<u8> = (0 .. 256 * 240).map(|i| ((i / 7) % 13 + (i / 256) % 5) as u8).collect();
+        let (output, clears, _) = decompress(6, &compress(6, &indices));
+
+        assert_eq!(output, indices);
+        assert_eq!(clears, 1);
+    }
+
+    // Noisy pixels fill the table quickly, so the code size goes all the way
+    // up to 12 bits and the table is cleared and started again several times
+    #[test]
+    fn test_compress_table_full() {
+        let mut seed = 12345u32;
+        let indices: Vec<u8> = (0 .. 256 * 240)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((seed >> 16) % 64) as u8
+            })
+            .collect();
+
+        let (output, clears, max_code_size) = decompress(6, &compress(6, &indices));
+
+        assert_eq!(output, indices);
+        assert!(clears > 2);
+        assert_eq!(max_code_size, MAX_CODE_SIZE);
+    }
+
+    #[test]
+    fn test_compress_small() {
+        for indices in [vec![], vec![1], vec![0, 0, 0, 0], vec![3, 2, 1, 0, 3, 2, 1, 0]].iter() {
+            let (output, _, _) = decompress(2, &compress(2, indices));
+            assert_eq!(&output, indices);
+        }
+    }
+}
@@ -7,6 +7,7 @@ use crate::mapper::Mapper4;
 use crate::mapper::Mapper7;
 use crate::mapper::Mapper66;
 use crate::mapper::Mapper69;
+use crate::region::Region;
 
 use std::cell::RefCell;
 use std::fs::File;
@@ -21,13 +22,16 @@ pub enum CartridgeError {
     IO(io::Error),
     InvalidMagic,
     // InvalidZeroes,
-    UnsupportedCartridge,
     UnsupportedMapper(u8),
+
+    // An NSF or NSFe file that's truncated, or needs something we can't do
+    InvalidNSF(String),
 }
 
-pub fn load_file_into_memory(fh: &mut File)
-    -> Result<Rc<RefCell<Box<dyn Mapper>>>, CartridgeError>
-{
+// A loaded cartridge, along with the region it was made for
+type Cartridge = (Rc<RefCell<Box<dyn Mapper>>>, Region);
+
+pub fn load_file_into_memory(fh: &mut File) -> Result<Cartridge, CartridgeError> {
     let mut header = [0; 16];
     let _ = fh.read(&mut header).map_err(CartridgeError::IO)?;
 
@@ -45,8 +49,16 @@ pub fn load_file_into_memory(fh: &mut File)
     let n_vrom_banks = header[5] as usize;
     debug!("8KB VROM banks: {}", n_vrom_banks);
 
-    let mirror_mode = header[6] & 0x01;
-    debug!("mirroring: {}", if mirror_mode == 0 { "horizontal" } else { "vertical" });
+    // Bit 3 of flags 6 means the cartridge provides its own 2KB of VRAM, on
+    // top of the 2KB inside the console, giving four independent nametables.
+    // When it's set, the mirroring bit is ignored.
+    let four_screen = (header[6] & 0x08) != 0;
+    let mirror_mode = if four_screen { 4 } else { header[6] & 0x01 };
+    debug!("mirroring: {}", match mirror_mode {
+        0 => "horizontal",
+        1 => "vertical",
+        _ => "four-screen",
+    });
 
     let battery_backed = (header[7] & 0x02) != 0;
     debug!("battery backed RAM: {}", if battery_backed { "yes" } else { "no" });
@@ -61,16 +73,27 @@ pub fn load_file_into_memory(fh: &mut File)
     let n_ram_banks = header[8];
     debug!("8KB RAM banks: {}", n_ram_banks);
 
-    // Get the cartridge type, 1 for PAL, anything else means NTSC
-    let cartridge_type = header[9] >> 7;
-    debug!("cartridge type: {}", cartridge_type);
-    if cartridge_type == 1 {
-        return Err(CartridgeError::UnsupportedCartridge);
-    }
-
-    // Reserved bytes, must all be zeroes
+    // Get the region the cartridge was made for. NES 2.0 headers (identified
+    // by bits 2 and 3 of flags 7 being 10) have a proper field for this in
+    // byte 12, where 0 is NTSC, 1 is PAL, 2 means it runs on either and 3 is
+    // Dendy. Plain iNES headers only have a PAL bit, in bit 0 of flags 9.
+    let nes2 = (header[7] & 0x0c) == 0x08;
+    let region = if nes2 {
+        match header[12] & 0x03 {
+            1 => Region::PAL,
+            3 => Region::Dendy,
+            _ => Region::NTSC,
+        }
+    } else if (header[9] & 0x01) != 0 {
+        Region::PAL
+    } else {
+        Region::NTSC
+    };
+    debug!("region: {}", region);
+
+    // Reserved bytes, must all be zeroes in iNES headers
     let zeroes = &header[10 .. 16];
-    if zeroes != [0, 0, 0, 0, 0, 0] {
+    if !nes2 && zeroes != [0, 0, 0, 0, 0, 0] {
         warn!("Header section should be full of zeroes, but contains {:?}",
               zeroes);
 
@@ -102,15 +125,17 @@ pub fn load_file_into_memory(fh: &mut File)
         debug!("making 8KB of CHR-RAM");
     }
 
-    match mapper {
-        0 => Ok(Rc::new(RefCell::new(Box::new(Mapper0::new_mapper(rom, vrom, mirror_mode))))),
-        1 => Ok(Rc::new(RefCell::new(Box::new(Mapper1::new_mapper(rom, vrom, mirror_mode))))),
-        2 => Ok(Rc::new(RefCell::new(Box::new(Mapper2::new_mapper(rom, vrom, mirror_mode))))),
-        3 => Ok(Rc::new(RefCell::new(Box::new(Mapper3::new_mapper(rom, vrom, mirror_mode))))),
-        4 => Ok(Rc::new(RefCell::new(Box::new(Mapper4::new_mapper(rom, vrom, mirror_mode))))),
-        7 => Ok(Rc::new(RefCell::new(Box::new(Mapper7::new_mapper(rom, vrom, mirror_mode))))),
-        66 => Ok(Rc::new(RefCell::new(Box::new(Mapper66::new_mapper(rom, vrom, mirror_mode))))),
-        69 => Ok(Rc::new(RefCell::new(Box::new(Mapper69::new_mapper(rom, vrom, mirror_mode))))),
-        _ => Err(CartridgeError::UnsupportedMapper(mapper)),
-    }
+    let mapper: Rc<RefCell<Box<dyn Mapper>>> = match mapper {
+        0 => Rc::new(RefCell::new(Box::new(Mapper0::new_mapper(rom, vrom, mirror_mode)))),
+        1 => Rc::new(RefCell::new(Box::new(Mapper1::new_mapper(rom, vrom, mirror_mode)))),
+        2 => Rc::new(RefCell::new(Box::new(Mapper2::new_mapper(rom, vrom, mirror_mode)))),
+        3 => Rc::new(RefCell::new(Box::new(Mapper3::new_mapper(rom, vrom, mirror_mode)))),
+        4 => Rc::new(RefCell::new(Box::new(Mapper4::new_mapper(rom, vrom, mirror_mode)))),
+        7 => Rc::new(RefCell::new(Box::new(Mapper7::new_mapper(rom, vrom, mirror_mode)))),
+        66 => Rc::new(RefCell::new(Box::new(Mapper66::new_mapper(rom, vrom, mirror_mode)))),
+        69 => Rc::new(RefCell::new(Box::new(Mapper69::new_mapper(rom, vrom, mirror_mode)))),
+        _ => return Err(CartridgeError::UnsupportedMapper(mapper)),
+    };
+
+    Ok((mapper, region))
 }
@@ -2,20 +2,29 @@
 #[macro_use] extern crate lazy_static;
 
 mod apu;
+mod audio;
+mod chr;
 mod console;
 mod controller;
+mod gif;
 mod cpu;
 mod mapper;
 mod mem;
 mod ines;
+mod nsf;
+mod pacer;
 mod ppu;
 mod palette;
+mod png;
+mod recorder;
+mod region;
 mod serde;
+mod viewer;
 
 use std::env;
 use std::process;
 
-use crate::console::Console;
+use crate::console::{Console, NES_NSF_RENDER};
 use crate::ines::CartridgeError;
 
 fn main() {
@@ -24,24 +33,27 @@ fn main() {
     if let Some(rom) = env::args().skip(1).next() {
         match Console::new_nes_console(&rom) {
             Ok(mut console) => {
-                console.power_up();
+                match *NES_NSF_RENDER {
+                    Some(seconds) => console.render_nsf(seconds),
+                    None          => console.power_up(),
+                }
             },
             Err(CartridgeError::IO(io_e)) => {
                 println!("There was an error reading ROM data from {}: {}", rom, io_e);
                 process::exit(1);
             },
             Err(CartridgeError::InvalidMagic) => {
-                println!("File {} is invalid. Expected iNES formatted ROM.", rom);
-                process::exit(1);
-            },
-            Err(CartridgeError::UnsupportedCartridge) => {
-                println!("Unsupported cartridge type. Only supports NTSC for now.");
+                println!("File {} is invalid. Expected an iNES formatted ROM, or an NSF or NSFe file.", rom);
                 process::exit(1);
             },
             Err(CartridgeError::UnsupportedMapper(m)) => {
                 println!("Unsupported mapper type: {}", m);
                 process::exit(1);
             },
+            Err(CartridgeError::InvalidNSF(reason)) => {
+                println!("File {} is an invalid NSF file: {}", rom, reason);
+                process::exit(1);
+            },
         }
     } else {
         println!("Missing required parameter: a path to a ROM file.");
@@ -6,6 +6,8 @@ mod mapper4;
 mod mapper7;
 mod mapper66;
 mod mapper69;
+mod nsf;
+mod sunsoft5b;
 
 use std::io;
 use std::fs::File;
@@ -18,6 +20,7 @@ pub use mapper4::Mapper4;
 pub use mapper7::Mapper7;
 pub use mapper66::Mapper66;
 pub use mapper69::Mapper69;
+pub use nsf::NSFMapper;
 
 #[derive(Clone, Copy)]
 pub enum MirrorMode {
@@ -64,8 +67,11 @@ impl MirrorMode {
 
 pub enum MapperEvent {
     CPUTick(u64),
-    HBlank,
-    VRAMAddressChange(u16),
+
+    // An address the PPU has put on its address bus, along with the PPU cycle
+    // it happened on. This covers every background and sprite fetch (including
+    // the dummy ones), as well as $2006/$2007 accesses from the CPU.
+    PPUBusAddress(u16, u64),
 }
 
 pub trait Mapper {
@@ -76,6 +82,21 @@ pub trait Mapper {
     fn read(&mut self, address: u16) -> u8;
     fn write(&mut self, address: u16, val: u8);
 
+    // All of the cartridge's CHR-ROM (or CHR-RAM), regardless of what's
+    // currently banked in. Used to export and import graphics, which isn't
+    // possible for cartridges that don't provide it.
+    fn chr(&mut self) -> &mut [u8] { &mut [] }
+
+    // Nametable read/write, for cartridges that supply their own nametable
+    // memory (or otherwise want to intercept it) for a given 1KB page. `page'
+    // is the logical nametable being accessed (0 => $2000, 1 => $2400, 2 =>
+    // $2800, 3 => $2C00) and `offset' is the offset within that page.
+    //
+    // Returning None (or false when writing) lets the PPU fall back to the
+    // console's internal VRAM, using the mirroring mode above.
+    fn read_nametable(&mut self, _page: usize, _offset: u16) -> Option<u8> { None }
+    fn write_nametable(&mut self, _page: usize, _offset: u16, _val: u8) -> bool { false }
+
     // Called after every PPU execution, to determine whether or not an
     // interrupt should be raised.
     fn irq_flag(&self) -> bool { false }
@@ -83,6 +104,10 @@ pub trait Mapper {
     // Called on particular events, resulting in an observer-like pattern.
     fn notify(&mut self, _event: MapperEvent) { }
 
+    // The output of any sound chip on the cartridge, in the same units as
+    // the APU's mix, which it's added to. Called after every CPUTick.
+    fn audio(&self) -> f32 { 0.0 }
+
     // Serialisation and deserialisation to save states
     fn save(&self, output: &mut File) -> io::Result<()>;
     fn load(&mut self, input: &mut File) -> io::Result<()>;
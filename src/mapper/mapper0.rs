@@ -22,6 +22,10 @@ impl Mapper for Mapper0 {
         &self.mirror_mode
     }
 
+    fn chr(&mut self) -> &mut [u8] {
+        &mut self.chr_rom
+    }
+
     fn read(&mut self, address: u16) -> u8 {
         match address {
             0x0000 ..= 0x1fff => {
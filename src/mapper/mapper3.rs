@@ -40,6 +40,10 @@ impl Mapper for Mapper3 {
         &self.mirror_mode
     }
 
+    fn chr(&mut self) -> &mut [u8] {
+        &mut self.chr_rom
+    }
+
     fn read(&mut self, address: u16) -> u8 {
         match address {
             // CHR-ROM
@@ -9,6 +9,11 @@ use crate::serde;
 const PRG_BANK_SIZE: usize = 8192;
 const CHR_BANK_SIZE: usize = 1024;
 
+// The MMC3 filters the PPU's A12 line, so that a rise is only counted if A12
+// has been low for a while beforehand. This stops the back-to-back sprite
+// pattern fetches during a scanline from clocking the counter more than once.
+const A12_LOW_PPU_CYCLES: u64 = 10;
+
 //
 // MMC3/TxROM (mapper 4)
 //
@@ -31,7 +36,9 @@ pub struct Mapper4 {
     irq_period: u8,
     irq_enabled: bool,
     irq_flag: bool,
-    last_vram_addr: u16,
+
+    // The PPU cycle at which A12 was first seen low, or None if it's high
+    a12_low_since: Option<u64>,
 }
 
 impl Mapper4 {
@@ -57,8 +64,35 @@ impl Mapper4 {
             irq_period: 0,
             irq_enabled: false,
             irq_flag: false,
-            last_vram_addr: 0,
+
+            a12_low_since: Some(0),
+        }
+    }
+
+    // https://wiki.nesdev.com/w/index.php/MMC3#IRQ_Specifics
+    //
+    // The counter is clocked on each filtered rising edge of A12 on the PPU
+    // address bus. With the usual setup of background tiles at $0000 and
+    // sprites at $1000, this happens once per scanline, around dot 260, when
+    // the first sprite pattern is fetched.
+    fn watch_a12(&mut self, address: u16, ppu_cycle: u64) {
+        let a12 = address & 0x1000 != 0;
+
+        if !a12 {
+            if self.a12_low_since.is_none() {
+                self.a12_low_since = Some(ppu_cycle);
+            }
+
+            return;
+        }
+
+        if let Some(since) = self.a12_low_since {
+            if ppu_cycle.saturating_sub(since) >= A12_LOW_PPU_CYCLES {
+                self.step_irq_counter();
+            }
         }
+
+        self.a12_low_since = None;
     }
 
     fn step_irq_counter(&mut self) {
@@ -83,6 +117,10 @@ impl Mapper for Mapper4 {
         &self.mirror_mode
     }
 
+    fn chr(&mut self) -> &mut [u8] {
+        &mut self.chr_rom
+    }
+
     fn read(&mut self, address: u16) -> u8 {
         match address {
             // CHR-ROM
@@ -179,6 +217,11 @@ impl Mapper for Mapper4 {
             },
             0xa000 ..= 0xbfff => {
                 if even {
+                    // Boards wired for four-screen VRAM ignore this register
+                    if let MirrorMode::Four = self.mirror_mode {
+                        return;
+                    }
+
                     self.mirror_mode = if val & 1 == 0 {
                         MirrorMode::Vertical
                     } else {
@@ -229,19 +272,8 @@ impl Mapper for Mapper4 {
     }
 
     fn notify(&mut self, event: MapperEvent) {
-        match event {
-            MapperEvent::HBlank => { self.step_irq_counter() },
-            MapperEvent::VRAMAddressChange(addr) => {
-                let prev_a12 = (self.last_vram_addr >> 12) & 1;
-                let cur_a12  = (addr >> 12) & 1;
-
-                if prev_a12 == 0 && cur_a12 == 1 {
-                    self.step_irq_counter();
-                }
-
-                self.last_vram_addr = addr;
-            },
-            _ => { },
+        if let MapperEvent::PPUBusAddress(addr, ppu_cycle) = event {
+            self.watch_a12(addr, ppu_cycle);
         }
     }
 
@@ -260,10 +292,19 @@ impl Mapper for Mapper4 {
         serde::encode_u8(output, self.prg_mode as u8)?;
 
         serde::encode_u8(output, self.irq_counter)?;
+        serde::encode_u8(output, self.irq_reload as u8)?;
         serde::encode_u8(output, self.irq_period)?;
         serde::encode_u8(output, self.irq_enabled as u8)?;
         serde::encode_u8(output, self.irq_flag as u8)?;
 
+        match self.a12_low_since {
+            Some(since) => {
+                serde::encode_u8(output, 1)?;
+                serde::encode_u64(output, since)?;
+            },
+            None => { serde::encode_u8(output, 0)? },
+        }
+
         Ok(())
     }
 
@@ -282,10 +323,145 @@ impl Mapper for Mapper4 {
         self.prg_mode = serde::decode_u8(input)? != 0;
 
         self.irq_counter = serde::decode_u8(input)?;
+        self.irq_reload = serde::decode_u8(input)? != 0;
         self.irq_period = serde::decode_u8(input)?;
         self.irq_enabled = serde::decode_u8(input)? != 0;
         self.irq_flag = serde::decode_u8(input)? != 0;
 
+        self.a12_low_since = match serde::decode_u8(input)? {
+            0 => None,
+            _ => Some(serde::decode_u64(input)?),
+        };
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_mapper() -> Mapper4 {
+        Mapper4::new_mapper(vec![0; 0x8000], vec![0; 0x2000], 0)
+    }
+
+    fn a12(mapper: &mut Mapper4, high: bool, ppu_cycle: u64) {
+        let address = if high { 0x1000 } else { 0x0000 };
+        mapper.notify(MapperEvent::PPUBusAddress(address, ppu_cycle));
+    }
+
+    // A12 goes low for a background fetch and then high for a sprite fetch,
+    // once per scanline, like it does with sprites at $1000
+    fn scanline(mapper: &mut Mapper4, n: u64) {
+        a12(mapper, false, n * 341);
+        a12(mapper, true, n * 341 + 260);
+    }
+
+    #[test]
+    fn test_a12_filter() {
+        let mut mapper = new_mapper();
+        mapper.write(0xc000, 5);
+        mapper.write(0xc001, 0);
+
+        a12(&mut mapper, false, 100);
+        a12(&mut mapper, true, 110);
+        assert_eq!(mapper.irq_counter, 5);
+
+        // Too short a gap, like between two sprite fetches, isn't a rise
+        a12(&mut mapper, false, 112);
+        a12(&mut mapper, true, 121);
+        assert_eq!(mapper.irq_counter, 5);
+
+        // Staying high isn't a rise either
+        a12(&mut mapper, true, 200);
+        assert_eq!(mapper.irq_counter, 5);
+
+        // Only the first cycle A12 was seen low counts
+        a12(&mut mapper, false, 300);
+        a12(&mut mapper, false, 305);
+        a12(&mut mapper, true, 310);
+        assert_eq!(mapper.irq_counter, 4);
+    }
+
+    #[test]
+    fn test_reload_on_zero() {
+        let mut mapper = new_mapper();
+        mapper.write(0xc000, 2);
+
+        // The counter starts at zero, so the first clock reloads it
+        let counters: Vec<u8> = (0 .. 7).map(|n| {
+            scanline(&mut mapper, n);
+            mapper.irq_counter
+        }).collect();
+
+        assert_eq!(counters, [2, 1, 0, 2, 1, 0, 2]);
+        assert!(!mapper.irq_flag());
+
+        // A new latch value isn't used until the next reload
+        mapper.write(0xc000, 4);
+        scanline(&mut mapper, 7);
+        assert_eq!(mapper.irq_counter, 1);
+        scanline(&mut mapper, 8);
+        scanline(&mut mapper, 9);
+        assert_eq!(mapper.irq_counter, 4);
+    }
+
+    #[test]
+    fn test_reload_register() {
+        let mut mapper = new_mapper();
+        mapper.write(0xc000, 3);
+        scanline(&mut mapper, 0);
+        scanline(&mut mapper, 1);
+        assert_eq!(mapper.irq_counter, 2);
+
+        // $C001 clears the counter, so it's reloaded on the next clock, rather
+        // than carrying on down to zero
+        mapper.write(0xc001, 0);
+        assert!(mapper.irq_reload);
+        scanline(&mut mapper, 2);
+        assert_eq!(mapper.irq_counter, 3);
+        assert!(!mapper.irq_reload);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = new_mapper();
+        mapper.write(0xc000, 1);
+        mapper.write(0xe001, 0);
+
+        scanline(&mut mapper, 0);
+        assert!(!mapper.irq_flag());
+        scanline(&mut mapper, 1);
+        assert!(mapper.irq_flag());
+
+        // Stays asserted until acknowledged by $E000
+        scanline(&mut mapper, 2);
+        assert!(mapper.irq_flag());
+        mapper.write(0xe000, 0);
+        assert!(!mapper.irq_flag());
+
+        // ...which also disables it until $E001
+        scanline(&mut mapper, 3);
+        assert_eq!(mapper.irq_counter, 0);
+        assert!(!mapper.irq_flag());
+
+        mapper.write(0xe001, 0);
+        scanline(&mut mapper, 4);
+        scanline(&mut mapper, 5);
+        assert!(mapper.irq_flag());
+    }
+
+    #[test]
+    fn test_irq_latch_zero() {
+        // With a latch of zero, every clock asserts the IRQ
+        let mut mapper = new_mapper();
+        mapper.write(0xe001, 0);
+
+        for n in 0 .. 3 {
+            scanline(&mut mapper, n);
+            assert!(mapper.irq_flag());
+            mapper.write(0xe000, 0);
+            mapper.write(0xe001, 0);
+        }
+    }
+}
@@ -5,6 +5,7 @@ use std::fs::File;
 
 use crate::mapper::{Mapper, MapperEvent};
 use crate::mapper::MirrorMode;
+use crate::mapper::sunsoft5b::Sunsoft5B;
 use crate::serde;
 
 const PRG_BANK_SIZE: usize = 8192;
@@ -35,7 +36,11 @@ impl From<u8> for Command {
 }
 
 //
-// Sunsoft FME-7/5A (mapper 69)
+// Sunsoft FME-7/5A/5B (mapper 69)
+//
+// The 5B is an FME-7 with a sound chip, which is written to through $C000 and
+// $E000. The FME-7 and 5A don't have anything there, so the sound chip stays
+// silent for them.
 //
 pub struct Mapper69 {
     chr_rom: Vec<u8>,
@@ -57,6 +62,8 @@ pub struct Mapper69 {
     irq_counter_enabled: bool,
     irq_counter_value: u16,
     irq_flag: bool,
+
+    audio: Sunsoft5B,
 }
 
 
@@ -86,6 +93,8 @@ impl Mapper69 {
             irq_counter_enabled: false,
             irq_counter_value: 0,
             irq_flag: false,
+
+            audio: Sunsoft5B::new_sunsoft5b(),
         }
     }
 
@@ -212,10 +221,14 @@ impl Mapper for Mapper69 {
         &self.mirror_mode
     }
 
+    fn chr(&mut self) -> &mut [u8] {
+        &mut self.chr_rom
+    }
+
     fn notify(&mut self, event: MapperEvent) {
-        match event {
-            MapperEvent::CPUTick(cycles) => { self.step_irq_counter(cycles) },
-            _ => { },
+        if let MapperEvent::CPUTick(cycles) = event {
+            self.step_irq_counter(cycles);
+            self.audio.step(cycles);
         }
     }
 
@@ -223,6 +236,10 @@ impl Mapper for Mapper69 {
         self.irq_flag
     }
 
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+
     fn read(&mut self, address: u16) -> u8 {
         match address {
             // CHR-ROM
@@ -293,6 +310,10 @@ impl Mapper for Mapper69 {
                 self.run_cmd(val);
             },
 
+            // Sunsoft 5B audio
+            0xc000 ..= 0xdfff => self.audio.write_address(val),
+            0xe000 ..= 0xffff => self.audio.write_data(val),
+
             _ => { },
         }
     }
@@ -337,6 +358,8 @@ impl Mapper for Mapper69 {
         serde::encode_u8(output, self.irq_counter_enabled as u8)?;
         serde::encode_u16(output, self.irq_counter_value)?;
 
+        self.audio.save(output)?;
+
         Ok(())
     }
 
@@ -381,6 +404,8 @@ impl Mapper for Mapper69 {
         self.irq_counter_enabled = serde::decode_u8(input)? != 0;
         self.irq_counter_value = serde::decode_u16(input)?;
 
+        self.audio.load(input)?;
+
         Ok(())
     }
 }
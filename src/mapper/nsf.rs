@@ -0,0 +1,142 @@
+use std::io::{Read, Write};
+use std::io;
+use std::fs::File;
+
+use crate::mapper::{Mapper, MapperEvent};
+use crate::mapper::sunsoft5b::Sunsoft5B;
+use crate::nsf::{IDLE_ADDRESS, NSF, SUNSOFT_5B};
+use crate::serde;
+
+const PRG_BANK_SIZE: usize = 4096;
+
+// JMP IDLE_ADDRESS, which the CPU loops in between calls to INIT and PLAY
+const IDLE_LOOP: [u8; 3] = [0x4c, IDLE_ADDRESS as u8, (IDLE_ADDRESS >> 8) as u8];
+
+//
+// A synthetic cartridge for NSF music, with 8KB of RAM at $6000-$7FFF and the
+// music's data mapped into $8000-$FFFF in 4KB banks. Bankswitched music
+// picks the bank for each 4KB slot by writing to $5FF8-$5FFF, and music that
+// isn't bankswitched is loaded at its load address.
+//
+// Music that uses the Sunsoft 5B's sound chip writes to it through $C000 and
+// $E000, as it would on the real cartridge.
+//
+pub struct NSFMapper {
+    chr_ram: Vec<u8>,
+    prg_rom: Vec<u8>,
+    sram: [u8; 0x2000],
+
+    // The bank in each 4KB slot, from $8000 to $F000
+    banks: [u8; 8],
+
+    audio: Option<Sunsoft5B>,
+}
+
+impl Mapper for NSFMapper {
+    fn notify(&mut self, event: MapperEvent) {
+        if let (MapperEvent::CPUTick(cycles), Some(audio)) = (event, self.audio.as_mut()) {
+            audio.step(cycles);
+        }
+    }
+
+    fn audio(&self) -> f32 {
+        self.audio.as_ref().map_or(0.0, |audio| audio.output())
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000 ..= 0x1fff => self.chr_ram[address as usize],
+            IDLE_ADDRESS ..= 0x4102 => IDLE_LOOP[(address - IDLE_ADDRESS) as usize],
+            0x6000 ..= 0x7fff => self.sram[address as usize - 0x6000],
+            0x8000 ..= 0xffff => {
+                let slot = (address as usize - 0x8000) / PRG_BANK_SIZE;
+                let index = self.banks[slot] as usize * PRG_BANK_SIZE
+                          + (address as usize % PRG_BANK_SIZE);
+
+                self.prg_rom[index % self.prg_rom.len()]
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        match address {
+            0x0000 ..= 0x1fff => { self.chr_ram[address as usize] = val },
+            0x5ff8 ..= 0x5fff => { self.banks[address as usize - 0x5ff8] = val },
+            0x6000 ..= 0x7fff => { self.sram[address as usize - 0x6000] = val },
+            0xc000 ..= 0xdfff => {
+                if let Some(audio) = self.audio.as_mut() {
+                    audio.write_address(val);
+                }
+            },
+            0xe000 ..= 0xffff => {
+                if let Some(audio) = self.audio.as_mut() {
+                    audio.write_data(val);
+                }
+            },
+            _ => { },
+        }
+    }
+
+    fn save(&self, output: &mut File) -> io::Result<()> {
+        serde::encode_vec(output, &self.chr_ram)?;
+        output.write_all(&self.sram)?;
+        output.write_all(&self.banks)?;
+
+        if let Some(audio) = self.audio.as_ref() {
+            audio.save(output)?;
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut File) -> io::Result<()> {
+        self.chr_ram = serde::decode_vec(input)?;
+        input.read_exact(&mut self.sram)?;
+        input.read_exact(&mut self.banks)?;
+
+        if let Some(audio) = self.audio.as_mut() {
+            audio.load(input)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl NSFMapper {
+    pub fn new_mapper(nsf: &NSF) -> Self {
+        // Bankswitched music is split into banks starting at the 4KB
+        // boundary below its load address. Otherwise the whole of $8000-$FFFF
+        // is one 32KB image, with the data at its load address.
+        let (prg_rom, banks) = if nsf.bankswitched() {
+            let padding = nsf.load_address as usize % PRG_BANK_SIZE;
+            let mut prg_rom = vec![0; padding];
+            prg_rom.extend_from_slice(&nsf.data);
+
+            let len = prg_rom.len().div_ceil(PRG_BANK_SIZE).max(1) * PRG_BANK_SIZE;
+            prg_rom.resize(len, 0);
+
+            (prg_rom, nsf.banks)
+        } else {
+            let mut prg_rom = vec![0; 0x8000];
+            let start = (nsf.load_address as usize).saturating_sub(0x8000);
+            let len = nsf.data.len().min(prg_rom.len() - start);
+            prg_rom[start .. start + len].copy_from_slice(&nsf.data[.. len]);
+
+            (prg_rom, [0, 1, 2, 3, 4, 5, 6, 7])
+        };
+
+        Self {
+            chr_ram: vec![0; 0x2000],
+            prg_rom: prg_rom,
+            sram: [0; 0x2000],
+            banks: banks,
+
+            audio: if nsf.expansion & SUNSOFT_5B != 0 {
+                Some(Sunsoft5B::new_sunsoft5b())
+            } else {
+                None
+            },
+        }
+    }
+}
@@ -0,0 +1,467 @@
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+//
+// The Sunsoft 5B is an FME-7 with a YM2149F sound chip (a close relative of
+// the AY-3-8910) built in, used only by Gimmick!. It adds three square wave
+// channels, a noise generator that can be mixed into any of them, and an
+// envelope generator that can control the volume of any of them.
+//
+// The chip runs at half the CPU's clock, and everything in it is driven by a
+// further divider of 8, so it's easiest to think of it as ticking once every
+// 16 CPU cycles.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::io;
+
+use crate::serde;
+
+const CPU_CYCLES_PER_TICK: u64 = 16;
+
+// The output of one channel at full volume, in the same units as the APU's
+// mix. That's about one and a half times a square wave at full volume in the
+// linear mix (0.1128), or about 1.14 times one in the non-linear mix (0.149).
+const OUTPUT_LEVEL: f32 = 0.17;
+
+// Volumes are logarithmic, 1.5dB apart, with 32 steps from the envelope and
+// every other step from the 4-bit fixed volumes. Step 0 is silent.
+fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10.0f32.powf((level as f32 - 31.0) * 1.5 / 20.0)
+    }
+}
+
+pub struct Sunsoft5B {
+    // The register selected by the last write to $C000
+    address: u8,
+    registers: [u8; 16],
+
+    // CPU cycles left over since the last tick
+    cycles: u64,
+
+    // Each tone channel's counter, and whether its square wave is high
+    tone_counters: [u16; 3],
+    tone_high: [bool; 3],
+
+    // The noise comes from a 17-bit linear feedback shift register, which
+    // is shifted at half the rate of a tone with the same period
+    noise_counter: u8,
+    noise_half: bool,
+    noise_lfsr: u32,
+
+    // The envelope ramps through 32 steps, up if attacking and down if not,
+    // and then either holds or starts again depending on its shape
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5B {
+    pub fn new_sunsoft5b() -> Self {
+        Self {
+            address: 0,
+            registers: [0; 16],
+
+            cycles: 0,
+
+            tone_counters: [0; 3],
+            tone_high: [false; 3],
+
+            noise_counter: 0,
+            noise_half: false,
+            noise_lfsr: 1,
+
+            // Silent until a shape is written
+            envelope_counter: 0,
+            envelope_step: 31,
+            envelope_attack: false,
+            envelope_holding: true,
+        }
+    }
+
+    // $C000-$DFFF
+    //
+    // 7  bit  0
+    // ---- ----
+    // .... RRRR
+    //      ||||
+    //      ++++- The register to write to through $E000
+    //
+    // Writes with any of the upper bits set select nothing, so that writes
+    // through $E000 are ignored.
+    pub fn write_address(&mut self, val: u8) {
+        self.address = val;
+    }
+
+    // $E000-$FFFF
+    pub fn write_data(&mut self, val: u8) {
+        if self.address > 0x0f {
+            return;
+        }
+
+        self.registers[self.address as usize] = val;
+
+        // Writing the shape starts the envelope again
+        if self.address == 0x0d {
+            self.restart_envelope();
+        }
+    }
+
+    fn restart_envelope(&mut self) {
+        self.envelope_counter = 0;
+        self.envelope_step = 0;
+        self.envelope_attack = self.registers[0x0d] & 0b0100 != 0;
+        self.envelope_holding = false;
+    }
+
+    // Registers 0-5, two for each channel, hold a 12-bit period. A period of
+    // zero acts like a period of one.
+    fn tone_period(&self, channel: usize) -> u16 {
+        let low = self.registers[channel * 2] as u16;
+        let high = (self.registers[channel * 2 + 1] & 0x0f) as u16;
+
+        ((high << 8) | low).max(1)
+    }
+
+    // Register 6 holds a 5-bit period
+    fn noise_period(&self) -> u8 {
+        (self.registers[0x06] & 0x1f).max(1)
+    }
+
+    // Registers $B and $C hold a 16-bit period
+    fn envelope_period(&self) -> u16 {
+        u16::from_le_bytes([self.registers[0x0b], self.registers[0x0c]]).max(1)
+    }
+
+    // Register 7 turns the tone and noise off for each channel, with bits
+    // 0-2 for the tones and bits 3-5 for the noise. A channel with both
+    // turned off outputs its volume constantly.
+    fn tone_enabled(&self, channel: usize) -> bool {
+        self.registers[0x07] & (1 << channel) == 0
+    }
+
+    fn noise_enabled(&self, channel: usize) -> bool {
+        self.registers[0x07] & (8 << channel) == 0
+    }
+
+    // Registers 8-A hold each channel's volume, which is either fixed in
+    // bits 0-3, or the envelope's if bit 4 is set
+    fn volume(&self, channel: usize) -> u8 {
+        let val = self.registers[0x08 + channel];
+
+        if val & 0b1_0000 != 0 {
+            self.envelope_level()
+        } else if val & 0x0f == 0 {
+            0
+        } else {
+            (val & 0x0f) * 2 + 1
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    // Register D holds the envelope's shape
+    //
+    // 7  bit  0
+    // ---- ----
+    // .... CAaH
+    //      ||||
+    //      |||+- Hold: stop at the end of the first ramp
+    //      ||+-- Alternate: change direction at the end of each ramp
+    //      |+--- Attack: ramp up, rather than down
+    //      +---- Continue: if clear, drop to silence at the end of the
+    //            first ramp, whatever the other bits say
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let shape = self.registers[0x0d];
+        let cont = shape & 0b1000 != 0;
+        let alternate = shape & 0b0010 != 0;
+        let hold = shape & 0b0001 != 0;
+
+        if !cont {
+            // Holding at the end of a downward ramp is silent
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+            return;
+        }
+
+        if alternate {
+            self.envelope_attack = !self.envelope_attack;
+        }
+
+        if hold {
+            // Stays at the end of the ramp, or the start of it if
+            // alternating, which is the end of a ramp the other way
+            self.envelope_holding = true;
+        } else {
+            self.envelope_step = 0;
+        }
+    }
+
+    fn tick(&mut self) {
+        for channel in 0 .. 3 {
+            self.tone_counters[channel] += 1;
+
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_high[channel] = !self.tone_high[channel];
+            }
+        }
+
+        self.noise_counter += 1;
+
+        if self.noise_counter >= self.noise_period() {
+            self.noise_counter = 0;
+            self.noise_half = !self.noise_half;
+
+            if self.noise_half {
+                let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+                self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+            }
+        }
+
+        self.envelope_counter += 1;
+
+        if self.envelope_counter >= self.envelope_period() {
+            self.envelope_counter = 0;
+            self.step_envelope();
+        }
+    }
+
+    pub fn step(&mut self, cpu_cycles: u64) {
+        self.cycles += cpu_cycles;
+
+        while self.cycles >= CPU_CYCLES_PER_TICK {
+            self.cycles -= CPU_CYCLES_PER_TICK;
+            self.tick();
+        }
+    }
+
+    // The sum of the three channels, in the same units as the APU's mix
+    pub fn output(&self) -> f32 {
+        let noise = self.noise_lfsr & 1 != 0;
+
+        (0 .. 3)
+            .filter(|&channel| !self.tone_enabled(channel) || self.tone_high[channel])
+            .filter(|&channel| !self.noise_enabled(channel) || noise)
+            .map(|channel| amplitude(self.volume(channel)))
+            .sum::<f32>() * OUTPUT_LEVEL
+    }
+
+    // The tone and noise counters aren't saved, so their waves start again
+    // when loaded, which is too short to hear
+    pub fn save(&self, output: &mut File) -> io::Result<()> {
+        serde::encode_u8(output, self.address)?;
+        output.write_all(&self.registers)?;
+
+        serde::encode_u16(output, self.envelope_counter)?;
+        serde::encode_u8(output, self.envelope_step)?;
+        serde::encode_u8(output, self.envelope_attack as u8)?;
+        serde::encode_u8(output, self.envelope_holding as u8)?;
+
+        Ok(())
+    }
+
+    pub fn load(&mut self, input: &mut File) -> io::Result<()> {
+        self.address = serde::decode_u8(input)?;
+        input.read_exact(&mut self.registers)?;
+
+        self.envelope_counter = serde::decode_u16(input)?;
+        self.envelope_step = serde::decode_u8(input)?;
+        self.envelope_attack = serde::decode_u8(input)? != 0;
+        self.envelope_holding = serde::decode_u8(input)? != 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(chip: &mut Sunsoft5B, register: u8, val: u8) {
+        chip.write_address(register);
+        chip.write_data(val);
+    }
+
+    // Channel A on its own, with its tone and noise off so that it outputs
+    // its volume constantly
+    fn new_chip(volume: u8) -> Sunsoft5B {
+        let mut chip = Sunsoft5B::new_sunsoft5b();
+        write(&mut chip, 0x07, 0b11_1111);
+        write(&mut chip, 0x08, volume);
+        chip
+    }
+
+    fn level(chip: &Sunsoft5B) -> f32 {
+        chip.output() / OUTPUT_LEVEL
+    }
+
+    // The envelope's level after each of its steps, with the shortest period
+    // so that it steps on every tick
+    fn envelope(shape: u8, steps: usize) -> Vec<u8> {
+        let mut chip = new_chip(0b1_0000);
+        write(&mut chip, 0x0b, 1);
+        write(&mut chip, 0x0d, shape);
+
+        let mut levels = vec![];
+        for _ in 0 .. steps {
+            levels.push(chip.envelope_level());
+            assert_eq!(chip.output(), amplitude(chip.envelope_level()) * OUTPUT_LEVEL);
+
+            chip.step(CPU_CYCLES_PER_TICK);
+        }
+
+        levels
+    }
+
+    fn ramp(up: bool) -> Vec<u8> {
+        if up { (0 ..= 31).collect() } else { (0 ..= 31).rev().collect() }
+    }
+
+    #[test]
+    fn test_volume() {
+        assert_eq!(new_chip(0).output(), 0.0);
+        assert_eq!(new_chip(15).output(), OUTPUT_LEVEL);
+
+        // Each fixed volume is two envelope steps, or 3dB, apart
+        for volume in 1 .. 15 {
+            let ratio = level(&new_chip(volume + 1)) / level(&new_chip(volume));
+            assert!((ratio - 10.0f32.powf(3.0 / 20.0)).abs() < 1e-4);
+            assert_eq!(new_chip(volume).volume(0), volume * 2 + 1);
+        }
+
+        // Silent until a shape is written
+        assert_eq!(new_chip(0b1_0000).output(), 0.0);
+    }
+
+    #[test]
+    fn test_envelope_without_continue() {
+        // Either way, it drops to silence at the end of the first ramp
+        for attack in [false, true] {
+            let shape = if attack { 0b0100 } else { 0b0000 };
+            let levels = envelope(shape, 64);
+
+            assert_eq!(levels[.. 32], ramp(attack)[..]);
+            assert!(levels[32 ..].iter().all(|level| *level == 0));
+        }
+    }
+
+    #[test]
+    fn test_envelope_repeat() {
+        // Continue without hold starts the ramp again
+        for attack in [false, true] {
+            let shape = if attack { 0b1100 } else { 0b1000 };
+            let levels = envelope(shape, 96);
+
+            assert_eq!(levels, [ramp(attack), ramp(attack), ramp(attack)].concat());
+        }
+    }
+
+    #[test]
+    fn test_envelope_alternate() {
+        // A triangle wave
+        let levels = envelope(0b1110, 128);
+        assert_eq!(levels, [ramp(true), ramp(false), ramp(true), ramp(false)].concat());
+    }
+
+    #[test]
+    fn test_envelope_hold() {
+        // Down and holding at silence, or up and holding at full volume
+        let levels = envelope(0b1001, 64);
+        assert_eq!(levels[.. 32], ramp(false)[..]);
+        assert!(levels[32 ..].iter().all(|level| *level == 0));
+
+        let levels = envelope(0b1101, 64);
+        assert_eq!(levels[.. 32], ramp(true)[..]);
+        assert!(levels[32 ..].iter().all(|level| *level == 31));
+
+        // Alternating as well holds at the start of the ramp instead
+        let levels = envelope(0b1011, 64);
+        assert_eq!(levels[.. 32], ramp(false)[..]);
+        assert!(levels[32 ..].iter().all(|level| *level == 31));
+
+        let levels = envelope(0b1111, 64);
+        assert_eq!(levels[.. 32], ramp(true)[..]);
+        assert!(levels[32 ..].iter().all(|level| *level == 0));
+    }
+
+    #[test]
+    fn test_envelope_restart() {
+        let mut chip = new_chip(0b1_0000);
+        write(&mut chip, 0x0b, 1);
+        write(&mut chip, 0x0d, 0b1101);
+        chip.step(CPU_CYCLES_PER_TICK * 10);
+        assert_eq!(chip.envelope_level(), 10);
+
+        // Writing the shape again, even the same one, starts from the
+        // beginning
+        write(&mut chip, 0x0d, 0b1101);
+        assert_eq!(chip.envelope_level(), 0);
+    }
+
+    #[test]
+    fn test_noise() {
+        // Only the noise, with the shortest period, so the LFSR shifts on
+        // every other tick
+        let mut chip = new_chip(15);
+        write(&mut chip, 0x07, 0b11_0111);
+        write(&mut chip, 0x06, 1);
+
+        let mut lfsr: u32 = 1;
+        let mut ones = 0;
+
+        for _ in 0 .. 1000 {
+            let expected = if lfsr & 1 != 0 { OUTPUT_LEVEL } else { 0.0 };
+            assert_eq!(chip.output(), expected);
+            ones += lfsr & 1;
+
+            chip.step(CPU_CYCLES_PER_TICK * 2);
+
+            let feedback = (lfsr ^ (lfsr >> 3)) & 1;
+            lfsr = (lfsr >> 1) | (feedback << 16);
+        }
+
+        // It's noise rather than a pattern, so it's on about half the time
+        assert!(ones > 400 && ones < 600);
+    }
+
+    #[test]
+    fn test_noise_sequence() {
+        // The 17-bit LFSR repeats after 2^17 - 1 shifts
+        let mut chip = Sunsoft5B::new_sunsoft5b();
+        write(&mut chip, 0x06, 1);
+
+        let start = chip.noise_lfsr;
+        let mut shifts = 0;
+
+        loop {
+            chip.step(CPU_CYCLES_PER_TICK * 2);
+            shifts += 1;
+
+            if chip.noise_lfsr == start {
+                break;
+            }
+
+            assert_ne!(chip.noise_lfsr, 0);
+        }
+
+        assert_eq!(shifts, (1 << 17) - 1);
+    }
+}
@@ -11,6 +11,20 @@ use crate::ppu::PPU;
 pub trait Memory {
     fn read(&mut self, _address: u16) -> u8 { 0 }
     fn write(&mut self, _address: u16, _val: u8) { }
+
+    // Called by the CPU before each memory access, with the number of CPU
+    // cycles that have passed since the start of the current instruction.
+    // Components that are stepped after the CPU can use this to catch up to
+    // the exact cycle of the access.
+    fn sync(&mut self, _cpu_cycles: u64) { }
+
+    // Copies a page of memory to OAM, after a write of `page' to $4014
+    fn oam_dma(&mut self, _page: u8, data: &[u8; 256]) {
+        for val in data.iter() {
+            self.write(0x2004, *val);
+        }
+    }
+
     fn save(&self, _output: &mut File) -> io::Result<()> { Ok(()) }
     fn load(&mut self, _input: &mut File) -> io::Result<()> { Ok(()) }
 }
@@ -20,6 +34,9 @@ pub struct NESMemory {
     apu:        Rc<RefCell<APU>>,
     controller: Rc<RefCell<Controller>>,
     ram:        [u8; 0x800],
+
+    // CPU cycles into the current instruction, as of the current access
+    cpu_cycles: u64,
 }
 
 impl Memory for NESMemory {
@@ -31,25 +48,30 @@ impl Memory for NESMemory {
 
             // The PPU registers exist from 0x2000 to 0x2007, the rest of the
             // address space is just a mirror of these first eight bytes.
-            0x2000 ..= 0x3fff => self.ppu.borrow_mut().read(address),
+            0x2000 ..= 0x3fff => {
+                let mut ppu = self.ppu.borrow_mut();
+                ppu.catch_up(self.cpu_cycles);
+                ppu.read(address)
+            },
 
             // APU registers
-            0x4000 ..= 0x4013 => self.apu.borrow_mut().read(address),
+            0x4000 ..= 0x4013 | 0x4015 => {
+                let mut apu = self.apu.borrow_mut();
+                apu.catch_up(self.cpu_cycles);
+                apu.read(address)
+            },
 
             // OAM DMA
             0x4014            => 0,
 
-            // APU registers
-            0x4015            => self.apu.borrow_mut().read(address),
-
             // Controller 1
             0x4016            => self.controller.borrow_mut().read(address),
 
             // Controller 2
             0x4017            => 0,
 
-            // Expansion ROM
-            0x4020 ..= 0x5fff => 0,
+            // Expansion ROM, which is only used by some cartridges
+            0x4020 ..= 0x5fff => self.ppu.borrow_mut().data.mapper.borrow_mut().read(address),
 
             // SRAM
             0x6000 ..= 0x7fff => self.ppu.borrow_mut().data.mapper.borrow_mut().read(address),
@@ -67,25 +89,28 @@ impl Memory for NESMemory {
             0x0000 ..= 0x1fff => { self.ram[(address as usize) % 0x800] = val; },
 
             // PPU registers
-            0x2000 ..= 0x3fff => self.ppu.borrow_mut().write(address, val),
-
-            // APU registers
-            0x4000 ..= 0x4013 => self.apu.borrow_mut().write(address, val),
+            0x2000 ..= 0x3fff => {
+                let mut ppu = self.ppu.borrow_mut();
+                ppu.catch_up(self.cpu_cycles);
+                ppu.write(address, val)
+            },
+
+            // APU registers, including the frame counter at $4017, which
+            // shares its address with controller 2
+            0x4000 ..= 0x4013 | 0x4015 | 0x4017 => {
+                let mut apu = self.apu.borrow_mut();
+                apu.catch_up(self.cpu_cycles);
+                apu.write(address, val)
+            },
 
             // OAM DMA
             0x4014            => unreachable!("this should've been intercepted by the CPU"),
 
-            // APU registers
-            0x4015            => self.apu.borrow_mut().write(address, val),
-
             // Controller 1
             0x4016            => self.controller.borrow_mut().write(address, val),
 
-            // Controller 2
-            0x4017            => { },
-
-            // Expansion ROM
-            0x4020 ..= 0x5fff => { },
+            // Expansion ROM, which is only used by some cartridges
+            0x4020 ..= 0x5fff => self.ppu.borrow_mut().data.mapper.borrow_mut().write(address, val),
 
             // SRAM
             0x6000 ..= 0x7fff => self.ppu.borrow_mut().data.mapper.borrow_mut().write(address, val),
@@ -97,6 +122,16 @@ impl Memory for NESMemory {
         }
     }
 
+    fn sync(&mut self, cpu_cycles: u64) {
+        self.cpu_cycles = cpu_cycles;
+    }
+
+    fn oam_dma(&mut self, page: u8, data: &[u8; 256]) {
+        let mut ppu = self.ppu.borrow_mut();
+        ppu.catch_up(self.cpu_cycles);
+        ppu.oam_dma(page, data);
+    }
+
     fn save(&self, output: &mut File) -> io::Result<()> {
         output.write(&self.ram)?;
         Ok(())
@@ -119,6 +154,8 @@ impl NESMemory {
             apu: apu,
             controller: controller,
             ram: [0; 0x800],
+
+            cpu_cycles: 0,
         }
     }
 }
@@ -127,34 +164,71 @@ impl NESMemory {
 mod tests {
     use super::*;
 
+    use crate::mapper::{Mapper, Mapper0};
+    use crate::region::Region;
+
+    fn new_nes_mem(prg: Vec<u8>) -> (NESMemory, Rc<RefCell<PPU>>, Rc<RefCell<APU>>) {
+        let mapper: Box<dyn Mapper> = Box::new(Mapper0::new_mapper(prg, vec![0; 0x2000], 0));
+        let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(Rc::new(RefCell::new(mapper)), Region::NTSC)));
+        let apu = Rc::new(RefCell::new(APU::new_nes_apu(Region::NTSC)));
+        let ctrl = Rc::new(RefCell::new(Controller::new_controller()));
+
+        (NESMemory::new_nes_mem(ppu.clone(), apu.clone(), ctrl), ppu, apu)
+    }
+
     #[test]
     fn test_read_write() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mut mem = NESMemory::new_nes_mem(ppu, ctrl);
+        let (mut mem, _, _) = new_nes_mem(vec![0; 0x8000]);
 
         // RAM
-        assert_eq!(mem.read(0x1000), Ok(0));
-        assert_eq!(mem.write(0x1000, 5), Ok(5));
-        assert_eq!(mem.read(0x1000), Ok(5));
+        assert_eq!(mem.read(0x1000), 0);
+        mem.write(0x1000, 5);
+        assert_eq!(mem.read(0x1000), 5);
 
         // ROM
-        mem.load_rom(&vec![0; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(0));
-        assert_eq!(mem.read(0x8001), Ok(0));
-        assert_eq!(mem.read(0xffff), Ok(0));
+        assert_eq!(mem.read(0x8000), 0);
+        assert_eq!(mem.read(0x8001), 0);
+        assert_eq!(mem.read(0xffff), 0);
     }
 
     #[test]
     fn test_load_rom() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mut mem = NESMemory::new_nes_mem(ppu, ctrl);
-        mem.load_rom(&vec![0; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(0));
-        assert_eq!(mem.read(0xffff), Ok(0));
-        mem.load_rom(&vec![1; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(1));
-        assert_eq!(mem.read(0xffff), Ok(1));
+        let (mut mem, _, _) = new_nes_mem(vec![0; 0x8000]);
+        assert_eq!(mem.read(0x8000), 0);
+        assert_eq!(mem.read(0xffff), 0);
+
+        let (mut mem, _, _) = new_nes_mem(vec![1; 0x8000]);
+        assert_eq!(mem.read(0x8000), 1);
+        assert_eq!(mem.read(0xffff), 1);
+    }
+
+    #[test]
+    fn test_catch_up() {
+        let (mut mem, ppu, apu) = new_nes_mem(vec![0; 0x8000]);
+
+        // Only accesses to the PPU's registers catch the PPU up...
+        mem.sync(2);
+        mem.write(0x0000, 1);
+        mem.write(0x4000, 0);
+        assert_eq!(ppu.borrow_mut().take_caught_up().0, 0);
+
+        mem.write(0x2003, 0);
+        assert_eq!(ppu.borrow_mut().take_caught_up().0, 6);
+
+        // ...and the same goes for the APU, which the write to $4000 caught
+        // up, but not the read from $2002
+        mem.sync(3);
+        mem.read(0x2002);
+        assert_eq!(apu.borrow_mut().take_caught_up().0, 2);
+
+        mem.read(0x4015);
+        assert_eq!(apu.borrow_mut().take_caught_up().0, 3);
+
+        // It never goes backwards within an instruction
+        mem.sync(3);
+        mem.write(0x2003, 0);
+        mem.sync(1);
+        mem.write(0x2003, 0);
+        assert_eq!(ppu.borrow_mut().take_caught_up().0, 9);
     }
 }
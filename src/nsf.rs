@@ -0,0 +1,947 @@
+// https://www.nesdev.org/wiki/NSF
+//
+// NSF files are music ripped from NES games: the game's sound driver and
+// music data, along with the addresses of two routines. INIT is called once
+// to start a track, and PLAY is called at a fixed rate (usually 60 times a
+// second) to keep it going.
+//
+// There's no program of its own to call these routines from, so between
+// calls the CPU sits in a tiny idle loop supplied by the NSF cartridge, and
+// the player sets up the registers and stack to call each routine as if from
+// a JSR in that loop. When the routine returns, the CPU lands back in the
+// loop.
+//
+// https://www.nesdev.org/wiki/NSFe
+// https://www.nesdev.org/wiki/NSF2
+//
+// NSFe files hold the same things as NSF files, but in a series of chunks,
+// with extra chunks for each track's title, length and fade-out, and the
+// order to play them in. NSF2 files are NSF files that can have the same
+// chunks after the music data.
+
+use std::fs::File;
+use std::io::Read;
+
+use crate::cpu::CPU;
+use crate::ines::CartridgeError;
+use crate::mem::Memory;
+use crate::region::Region;
+
+pub const NSF_MAGIC: [u8; 5] = [0x4e, 0x45, 0x53, 0x4d, 0x1a];
+pub const NSFE_MAGIC: [u8; 4] = [0x4e, 0x53, 0x46, 0x45];
+
+// Where the CPU waits between calls to INIT and PLAY. This is in the
+// expansion area, which NSF cartridges don't otherwise use below $5FF8.
+pub const IDLE_ADDRESS: u16 = 0x4100;
+
+const HEADER_SIZE: usize = 0x80;
+
+// How long a track fades out for, in milliseconds, if it has a length but
+// no fade of its own
+const DEFAULT_FADE: u32 = 5000;
+
+// The expansion audio chips that bits 0-5 of byte $7B say the music uses
+const EXPANSION_CHIPS: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "Namco 163", "Sunsoft 5B"];
+
+pub const SUNSOFT_5B: u8 = 1 << 5;
+
+// The expansion audio chips that are emulated
+const EMULATED_CHIPS: u8 = SUNSOFT_5B;
+
+// What the metadata says about each track, if anything
+#[derive(Clone, Default)]
+pub struct Track {
+    pub title: Option<String>,
+
+    // How long the track plays for before it starts fading out, and how
+    // long the fade lasts, in milliseconds
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+pub struct NSF {
+    pub songs: u8,
+
+    // The first track to play, counting from 1
+    pub starting_song: u8,
+
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+
+    // How often PLAY is called, in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+
+    // The initial values of the bank registers at $5FF8-$5FFF. If these are
+    // all zero, the music isn't bankswitched.
+    pub banks: [u8; 8],
+
+    pub region: Region,
+
+    // Bit flags for each of EXPANSION_CHIPS
+    pub expansion: u8,
+
+    pub data: Vec<u8>,
+
+    // One for each song
+    pub tracks: Vec<Track>,
+
+    // The tracks to play, in order, counting from 0. Without a playlist in
+    // the metadata, this is every track.
+    pub playlist: Vec<u8>,
+}
+
+pub fn is_nsf(magic: &[u8]) -> bool {
+    magic.starts_with(&NSF_MAGIC) || magic.starts_with(&NSFE_MAGIC)
+}
+
+// Strings are null-terminated, and in the NSF header, in a 32 byte field
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[.. end]).trim().to_string()
+}
+
+// A chunk full of null-terminated strings, one after another
+fn chunk_strings(bytes: &[u8]) -> Vec<String> {
+    bytes.split(|b| *b == 0)
+        .map(|s| String::from_utf8_lossy(s).trim().to_string())
+        .collect()
+}
+
+// A chunk of signed 32-bit times in milliseconds, where negative times mean
+// the time isn't known
+fn chunk_times(bytes: &[u8]) -> Vec<Option<u32>> {
+    bytes.chunks_exact(4)
+        .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .map(|ms| if ms < 0 { None } else { Some(ms as u32) })
+        .collect()
+}
+
+fn header_u16(header: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([header[offset], header[offset + 1]])
+}
+
+fn truncated() -> CartridgeError {
+    CartridgeError::InvalidNSF("the file is truncated".to_string())
+}
+
+// Where metadata chunks come from, which changes which chunks are allowed
+#[derive(Clone, Copy, PartialEq)]
+enum Chunks {
+    // An NSFe file, which is nothing but chunks
+    NSFe,
+
+    // The metadata after the music data in an NSF2 file. The header already
+    // has everything needed to play the music, so chunks that would replace
+    // it are ignored, and unknown chunks are only a problem if bit 7 of byte
+    // $7C says the metadata is mandatory.
+    NSF2 { mandatory: bool },
+}
+
+pub fn load_file(fh: &mut File) -> Result<NSF, CartridgeError> {
+    let mut bytes = Vec::new();
+    fh.read_to_end(&mut bytes).map_err(CartridgeError::IO)?;
+
+    parse(&bytes)
+}
+
+fn parse(bytes: &[u8]) -> Result<NSF, CartridgeError> {
+    let mut nsf = if bytes.starts_with(&NSF_MAGIC) {
+        parse_nsf(bytes)?
+    } else if bytes.starts_with(&NSFE_MAGIC) {
+        parse_nsfe(&bytes[NSFE_MAGIC.len() ..])?
+    } else {
+        return Err(CartridgeError::InvalidMagic);
+    };
+
+    // Music that isn't bankswitched is loaded straight into $8000-$FFFF
+    if !nsf.bankswitched() && nsf.load_address < 0x8000 {
+        return Err(CartridgeError::InvalidNSF(format!("the load address ${:04X} is below $8000", nsf.load_address)));
+    }
+
+    nsf.songs = nsf.songs.max(1);
+    nsf.starting_song = nsf.starting_song.clamp(1, nsf.songs);
+    nsf.tracks.resize(nsf.songs as usize, Track::default());
+
+    let songs = nsf.songs;
+    nsf.playlist.retain(|track| *track < songs);
+    if nsf.playlist.is_empty() {
+        nsf.playlist = (0 .. songs).collect();
+    }
+
+    debug!("NSF: {} songs, load ${:04X}, init ${:04X}, play ${:04X}",
+           nsf.songs, nsf.load_address, nsf.init_address, nsf.play_address);
+
+    Ok(nsf)
+}
+
+fn parse_nsf(bytes: &[u8]) -> Result<NSF, CartridgeError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(truncated());
+    }
+
+    let (header, rest) = bytes.split_at(HEADER_SIZE);
+
+    // Bit 1 of byte $7A means the music works on either, in which case it's
+    // played as NTSC
+    let region = if header[0x7a] & 0x03 == 0x01 {
+        Region::PAL
+    } else {
+        Region::NTSC
+    };
+
+    let mut banks = [0; 8];
+    banks.copy_from_slice(&header[0x70 .. 0x78]);
+
+    // NSF2 files give the length of the music data in bytes $7D-$7F, and if
+    // it's there, metadata chunks follow the data
+    let version = header[0x05];
+    let data_length = header[0x7d] as usize
+                    | (header[0x7e] as usize) << 8
+                    | (header[0x7f] as usize) << 16;
+
+    let (data, metadata) = if version >= 2 && data_length > 0 {
+        if data_length > rest.len() {
+            return Err(truncated());
+        }
+
+        rest.split_at(data_length)
+    } else {
+        (rest, &[][..])
+    };
+
+    let mut nsf = NSF {
+        songs: header[0x06],
+        starting_song: header[0x07],
+
+        load_address: header_u16(header, 0x08),
+        init_address: header_u16(header, 0x0a),
+        play_address: header_u16(header, 0x0c),
+
+        title: header_string(&header[0x0e .. 0x2e]),
+        artist: header_string(&header[0x2e .. 0x4e]),
+        copyright: header_string(&header[0x4e .. 0x6e]),
+
+        ntsc_speed: header_u16(header, 0x6e),
+        pal_speed: header_u16(header, 0x78),
+
+        banks: banks,
+        region: region,
+        expansion: header[0x7b],
+        data: data.to_vec(),
+
+        tracks: Vec::new(),
+        playlist: Vec::new(),
+    };
+
+    let mandatory = header[0x7c] & 0x80 != 0;
+    read_chunks(&mut nsf, metadata, Chunks::NSF2 { mandatory: mandatory })?;
+
+    Ok(nsf)
+}
+
+fn parse_nsfe(bytes: &[u8]) -> Result<NSF, CartridgeError> {
+    let mut nsf = NSF {
+        songs: 1,
+        starting_song: 1,
+        load_address: 0,
+        init_address: 0,
+        play_address: 0,
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ntsc_speed: 0,
+        pal_speed: 0,
+        banks: [0; 8],
+        region: Region::NTSC,
+        expansion: 0,
+        data: Vec::new(),
+        tracks: Vec::new(),
+        playlist: Vec::new(),
+    };
+
+    let chunks = read_chunks(&mut nsf, bytes, Chunks::NSFe)?;
+
+    for required in [b"INFO", b"DATA"].iter() {
+        if !chunks.contains(*required) {
+            let name = String::from_utf8_lossy(*required);
+            return Err(CartridgeError::InvalidNSF(format!("there's no {} chunk", name)));
+        }
+    }
+
+    Ok(nsf)
+}
+
+// Reads metadata chunks into `nsf', until the end chunk or the end of the
+// data, returning the IDs of the chunks that were read.
+//
+// Each chunk is its length, its 4 character ID, and then its data. Chunks
+// with an upper case first letter are required to play the music properly,
+// so we can only skip the ones we don't know about that start in lower case,
+// unless they're in optional NSF2 metadata.
+fn read_chunks(nsf: &mut NSF, bytes: &[u8], source: Chunks) -> Result<Vec<[u8; 4]>, CartridgeError> {
+    let mut chunks = Vec::new();
+    let mut rest = bytes;
+
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            return Err(truncated());
+        }
+
+        let length = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let id = [rest[4], rest[5], rest[6], rest[7]];
+        rest = &rest[8 ..];
+
+        if length > rest.len() {
+            return Err(truncated());
+        }
+
+        let (data, next) = rest.split_at(length);
+        rest = next;
+
+        if let Chunks::NSF2 { .. } = source {
+            if [b"INFO", b"DATA", b"BANK"].contains(&&id) {
+                debug!("ignoring NSF2 chunk: {}", String::from_utf8_lossy(&id));
+                continue;
+            }
+        }
+
+        match &id {
+            b"INFO" => {
+                if data.len() < 8 {
+                    return Err(truncated());
+                }
+
+                nsf.load_address = header_u16(data, 0);
+                nsf.init_address = header_u16(data, 2);
+                nsf.play_address = header_u16(data, 4);
+                nsf.region = if data[6] & 0x03 == 0x01 { Region::PAL } else { Region::NTSC };
+                nsf.expansion = data[7];
+                nsf.songs = data.get(8).cloned().unwrap_or(1);
+
+                // The starting track counts from 0 here, unlike in NSF files
+                if let Some(start) = data.get(9) {
+                    nsf.starting_song = start + 1;
+                }
+            },
+            b"DATA" => nsf.data = data.to_vec(),
+            b"BANK" => {
+                for (bank, val) in nsf.banks.iter_mut().zip(data.iter()) {
+                    *bank = *val;
+                }
+            },
+            b"RATE" => {
+                if data.len() >= 2 {
+                    nsf.ntsc_speed = header_u16(data, 0);
+                }
+                if data.len() >= 4 {
+                    nsf.pal_speed = header_u16(data, 2);
+                }
+            },
+            b"auth" => {
+                let mut strings = chunk_strings(data).into_iter();
+                nsf.title = strings.next().unwrap_or_default();
+                nsf.artist = strings.next().unwrap_or_default();
+                nsf.copyright = strings.next().unwrap_or_default();
+            },
+            b"tlbl" => {
+                let titles = chunk_strings(data);
+                nsf.tracks.resize(titles.len().max(nsf.tracks.len()), Track::default());
+
+                for (track, title) in nsf.tracks.iter_mut().zip(titles) {
+                    track.title = if title.is_empty() { None } else { Some(title) };
+                }
+            },
+            b"time" => {
+                let times = chunk_times(data);
+                nsf.tracks.resize(times.len().max(nsf.tracks.len()), Track::default());
+
+                for (track, time) in nsf.tracks.iter_mut().zip(times) {
+                    track.length = time;
+                }
+            },
+            b"fade" => {
+                let times = chunk_times(data);
+                nsf.tracks.resize(times.len().max(nsf.tracks.len()), Track::default());
+
+                for (track, time) in nsf.tracks.iter_mut().zip(times) {
+                    track.fade = time;
+                }
+            },
+            b"plst" => nsf.playlist = data.to_vec(),
+
+            // The NSF2 flags from byte $7C, which are only about features we
+            // don't need to know about
+            b"NSF2" => { },
+
+            b"NEND" => break,
+            _ => {
+                let required = match source {
+                    Chunks::NSFe               => true,
+                    Chunks::NSF2 { mandatory } => mandatory,
+                };
+
+                if required && id[0].is_ascii_uppercase() {
+                    let name = String::from_utf8_lossy(&id);
+                    return Err(CartridgeError::InvalidNSF(format!("unsupported {} chunk", name)));
+                }
+
+                debug!("skipping NSFe chunk: {}", String::from_utf8_lossy(&id));
+            },
+        }
+
+        chunks.push(id);
+    }
+
+    Ok(chunks)
+}
+
+impl NSF {
+    pub fn bankswitched(&self) -> bool {
+        self.banks.iter().any(|b| *b != 0)
+    }
+
+    // The names of the expansion audio chips the music uses that aren't
+    // emulated
+    pub fn missing_chips(&self) -> Vec<&'static str> {
+        let missing = self.expansion & !EMULATED_CHIPS;
+
+        EXPANSION_CHIPS.iter()
+            .enumerate()
+            .filter(|(i, _)| missing & (1 << i) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    // How many CPU cycles there are between calls to PLAY. A speed of zero
+    // isn't valid, so the region's frame rate is used instead.
+    fn play_period(&self, region: Region) -> f64 {
+        let speed = match region {
+            Region::PAL | Region::Dendy => self.pal_speed,
+            Region::NTSC                => self.ntsc_speed,
+        };
+
+        if speed == 0 {
+            region.cpu_clock_rate() / region.frame_rate()
+        } else {
+            region.cpu_clock_rate() * speed as f64 / 1_000_000.0
+        }
+    }
+}
+
+// Minutes and seconds, like 2:05
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+pub struct NSFPlayer {
+    nsf: NSF,
+    region: Region,
+
+    // The track that's playing, counting from 0, and where it is in the
+    // playlist
+    track: u8,
+    position: usize,
+
+    // CPU cycles since the track started
+    elapsed: u64,
+
+    // CPU cycles between calls to PLAY, and until the next one is due
+    play_period: f64,
+    until_play: f64,
+
+    // Whether INIT or PLAY is still running
+    busy: bool,
+}
+
+impl NSFPlayer {
+    pub fn new_nsf_player(nsf: NSF, region: Region) -> Self {
+        let play_period = nsf.play_period(region);
+        let track = nsf.starting_song - 1;
+        let position = nsf.playlist.iter().position(|t| *t == track).unwrap_or(0);
+
+        Self {
+            nsf: nsf,
+            region: region,
+            track: track,
+            position: position,
+            elapsed: 0,
+            play_period: play_period,
+            until_play: play_period,
+            busy: false,
+        }
+    }
+
+    pub fn nsf(&self) -> &NSF {
+        &self.nsf
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // Where the current track is in the playlist, counting from 0
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn track_title(&self) -> Option<&str> {
+        self.nsf.tracks[self.track as usize].title.as_deref()
+    }
+
+    // Starts a track from the beginning, counting from 0. The RAM and sound
+    // registers are cleared first, the same way a hardware player would.
+    pub fn start_track(&mut self, cpu: &mut CPU, track: u8) {
+        self.track = track % self.nsf.songs;
+
+        if let Some(position) = self.nsf.playlist.iter().position(|t| *t == self.track) {
+            self.position = position;
+        }
+
+        for addr in 0x0000 .. 0x0800 {
+            cpu.write(addr, 0);
+        }
+
+        for addr in 0x6000 .. 0x8000 {
+            cpu.write(addr, 0);
+        }
+
+        for addr in 0x4000 .. 0x4014 {
+            cpu.write(addr, 0);
+        }
+
+        cpu.write(0x4015, 0x00);
+        cpu.write(0x4015, 0x0f);
+        cpu.write(0x4017, 0x40);
+
+        if self.nsf.bankswitched() {
+            for (i, bank) in self.nsf.banks.iter().enumerate() {
+                cpu.write(0x5ff8 + i as u16, *bank);
+            }
+        }
+
+        let x = match self.region {
+            Region::PAL | Region::Dendy => 1,
+            Region::NTSC                => 0,
+        };
+
+        cpu.call(self.nsf.init_address, IDLE_ADDRESS);
+        cpu.a = self.track;
+        cpu.x = x;
+
+        self.busy = true;
+        self.elapsed = 0;
+        self.until_play = self.play_period;
+    }
+
+    // Moves along the playlist, wrapping around at either end
+    pub fn next_track(&mut self, cpu: &mut CPU) {
+        let position = (self.position + 1) % self.nsf.playlist.len();
+        self.start_position(cpu, position);
+    }
+
+    pub fn previous_track(&mut self, cpu: &mut CPU) {
+        let len = self.nsf.playlist.len();
+        let position = (self.position + len - 1) % len;
+        self.start_position(cpu, position);
+    }
+
+    // Starts the track at a position in the playlist. The same track can be
+    // in the playlist more than once, so the position is set after starting.
+    pub fn start_position(&mut self, cpu: &mut CPU, position: usize) {
+        let track = self.nsf.playlist[position];
+        self.start_track(cpu, track);
+        self.position = position;
+    }
+
+    // Called after every CPU instruction, with how many cycles it took, to
+    // call PLAY when it's due. If INIT or the last PLAY is still running,
+    // the call waits until it returns.
+    pub fn tick(&mut self, cpu: &mut CPU, cycles: u64) {
+        self.elapsed += cycles;
+
+        if cpu.pc == IDLE_ADDRESS {
+            self.busy = false;
+        }
+
+        self.until_play -= cycles as f64;
+
+        if self.until_play <= 0.0 && !self.busy {
+            cpu.call(self.nsf.play_address, IDLE_ADDRESS);
+            self.busy = true;
+
+            // Calls that were held up aren't made up for later
+            self.until_play = (self.until_play + self.play_period).max(0.0);
+        }
+    }
+
+    // How long the current track has been playing, in seconds
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed as f64 / self.region.cpu_clock_rate()
+    }
+
+    // How long the current track plays for and then fades out for, in
+    // seconds, if the metadata says
+    pub fn track_length(&self) -> Option<(f64, f64)> {
+        let track = &self.nsf.tracks[self.track as usize];
+        let length = track.length? as f64 / 1000.0;
+        let fade = track.fade.unwrap_or(DEFAULT_FADE) as f64 / 1000.0;
+
+        Some((length, fade))
+    }
+
+    // How loud the music should be, which goes down to nothing as the track
+    // fades out
+    pub fn gain(&self) -> f32 {
+        match self.track_length() {
+            Some((length, fade)) if self.elapsed() > length => {
+                if fade > 0.0 {
+                    (1.0 - (self.elapsed() - length) / fade).max(0.0) as f32
+                } else {
+                    0.0
+                }
+            },
+            _ => 1.0,
+        }
+    }
+
+    // Whether the current track has played for as long as it should and
+    // finished fading out. Tracks without a length never finish.
+    pub fn finished(&self) -> bool {
+        match self.track_length() {
+            Some((length, fade)) => self.elapsed() >= length + fade,
+            None                 => false,
+        }
+    }
+
+    // A line of text for each thing to show about what's playing
+    pub fn describe(&self) -> Vec<String> {
+        let time = match self.track_length() {
+            Some((length, fade)) => format!("{} / {}", format_time(self.elapsed()), format_time(length + fade)),
+            None                 => format_time(self.elapsed()),
+        };
+
+        let mut lines = vec![
+            self.nsf.title.clone(),
+            self.nsf.artist.clone(),
+            self.nsf.copyright.clone(),
+            String::new(),
+            format!("track {} of {}", self.track + 1, self.nsf.songs),
+            self.track_title().unwrap_or_default().to_string(),
+            time,
+        ];
+
+        let chips = self.nsf.missing_chips();
+        if !chips.is_empty() {
+            lines.push(String::new());
+            lines.push(format!("{} audio not emulated", chips.join(", ")));
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(id);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn times(ms: &[i32]) -> Vec<u8> {
+        ms.iter().flat_map(|ms| ms.to_le_bytes().to_vec()).collect()
+    }
+
+    // An NSF file with 3 songs, starting on the second, followed by
+    // `metadata' if it's an NSF2 file
+    fn nsf_file(version: u8, flags: u8, metadata: &[u8]) -> Vec<u8> {
+        nsf_image(0x8000, &[0xea; 16], version, flags, metadata)
+    }
+
+    // The same, with INIT at $8010 and PLAY at $8020, and `data' loaded at
+    // `load_address'
+    fn nsf_image(load_address: u16, data: &[u8], version: u8, flags: u8, metadata: &[u8]) -> Vec<u8> {
+        let mut header = [0; HEADER_SIZE];
+
+        header[.. 5].copy_from_slice(&NSF_MAGIC);
+        header[0x05] = version;
+        header[0x06] = 3;
+        header[0x07] = 2;
+        header[0x08 .. 0x0a].copy_from_slice(&load_address.to_le_bytes());
+        header[0x0a .. 0x0e].copy_from_slice(&[0x10, 0x80, 0x20, 0x80]);
+        header[0x0e .. 0x13].copy_from_slice(b"Title");
+        header[0x2e .. 0x34].copy_from_slice(b"Artist");
+        header[0x4e .. 0x52].copy_from_slice(b"1990");
+        header[0x6e .. 0x70].copy_from_slice(&16639u16.to_le_bytes());
+        header[0x7c] = flags;
+
+        if version >= 2 {
+            header[0x7d] = data.len() as u8;
+        }
+
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(metadata);
+        bytes
+    }
+
+    fn nsfe_file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = NSFE_MAGIC.to_vec();
+        bytes.extend(chunks.iter().flatten());
+        bytes
+    }
+
+    fn info() -> Vec<u8> {
+        chunk(b"INFO", &[0x00, 0x80, 0x10, 0x80, 0x20, 0x80, 0x00, 0x00, 4, 1])
+    }
+
+    fn data() -> Vec<u8> {
+        chunk(b"DATA", &[0xea; 16])
+    }
+
+    fn error(result: Result<NSF, CartridgeError>) -> String {
+        match result {
+            Err(CartridgeError::InvalidNSF(message)) => message,
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_)  => panic!("parsed successfully"),
+        }
+    }
+
+    #[test]
+    fn test_nsf() {
+        let nsf = parse(&nsf_file(1, 0, &[])).unwrap();
+
+        assert_eq!((nsf.songs, nsf.starting_song), (3, 2));
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8010, 0x8020));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Title", "Artist", "1990"));
+        assert_eq!(nsf.ntsc_speed, 16639);
+        assert_eq!(nsf.data, [0xea; 16]);
+        assert_eq!(nsf.playlist, [0, 1, 2]);
+        assert_eq!(nsf.tracks.len(), 3);
+        assert!(nsf.tracks.iter().all(|t| t.title.is_none() && t.length.is_none()));
+    }
+
+    #[test]
+    fn test_nsfe() {
+        let nsf = parse(&nsfe_file(&[
+            info(),
+            data(),
+            chunk(b"BANK", &[0, 1, 2]),
+            chunk(b"RATE", &[0x1a, 0x41, 0x20, 0x4e]),
+            chunk(b"auth", b"Title\0Artist\0 1990 \0Ripper\0"),
+            chunk(b"tlbl", b"Intro\0\0Boss"),
+            chunk(b"time", &times(&[90_000, -1, 120_500])),
+            chunk(b"fade", &times(&[1000])),
+            chunk(b"plst", &[2, 0, 9, 3]),
+            chunk(b"NEND", &[]),
+            chunk(b"ZZZZ", &[]),
+        ])).unwrap();
+
+        assert_eq!((nsf.songs, nsf.starting_song), (4, 2));
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8010, 0x8020));
+        assert_eq!(nsf.data, [0xea; 16]);
+        assert_eq!(nsf.banks, [0, 1, 2, 0, 0, 0, 0, 0]);
+        assert_eq!((nsf.ntsc_speed, nsf.pal_speed), (0x411a, 0x4e20));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Title", "Artist", "1990"));
+
+        let titles: Vec<Option<&str>> = nsf.tracks.iter().map(|t| t.title.as_deref()).collect();
+        assert_eq!(titles, [Some("Intro"), None, Some("Boss"), None]);
+
+        let lengths: Vec<Option<u32>> = nsf.tracks.iter().map(|t| t.length).collect();
+        assert_eq!(lengths, [Some(90_000), None, Some(120_500), None]);
+        assert_eq!(nsf.tracks[0].fade, Some(1000));
+        assert_eq!(nsf.tracks[2].fade, None);
+
+        // Tracks that don't exist are left out of the playlist
+        assert_eq!(nsf.playlist, [2, 0, 3]);
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        // The NSF2 chunk can be in NSFe files too
+        assert!(parse(&nsfe_file(&[info(), chunk(b"NSF2", &[0]), data()])).is_ok());
+
+        // Unknown chunks in lower case can be skipped, but not in upper case
+        assert!(parse(&nsfe_file(&[info(), chunk(b"vrc7", &[1, 2]), data()])).is_ok());
+        assert_eq!(error(parse(&nsfe_file(&[info(), chunk(b"VRC7", &[1, 2]), data()]))),
+                   "unsupported VRC7 chunk");
+
+        assert_eq!(error(parse(&nsfe_file(&[info()]))), "there's no DATA chunk");
+        assert_eq!(error(parse(&nsfe_file(&[data()]))), "there's no INFO chunk");
+
+        let mut truncated = nsfe_file(&[info(), data()]);
+        truncated.pop();
+        assert_eq!(error(parse(&truncated)), "the file is truncated");
+    }
+
+    #[test]
+    fn test_nsf2_metadata() {
+        let metadata: Vec<u8> = [
+            chunk(b"tlbl", b"One\0Two\0Three"),
+            chunk(b"time", &times(&[1000, 2000, 3000])),
+            chunk(b"plst", &[2, 1]),
+
+            // These would replace what's in the header, so they're ignored
+            chunk(b"INFO", &[0x00, 0x90, 0x00, 0x90, 0x00, 0x90, 0x00, 0x00, 9, 0]),
+            chunk(b"DATA", &[0; 4]),
+            chunk(b"BANK", &[1, 2, 3]),
+        ].concat();
+
+        let nsf = parse(&nsf_file(2, 0, &metadata)).unwrap();
+
+        assert_eq!(nsf.songs, 3);
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8010, 0x8020));
+        assert_eq!(nsf.data, [0xea; 16]);
+        assert_eq!(nsf.banks, [0; 8]);
+
+        assert_eq!(nsf.tracks[2].title.as_deref(), Some("Three"));
+        assert_eq!(nsf.tracks[1].length, Some(2000));
+        assert_eq!(nsf.playlist, [2, 1]);
+    }
+
+    #[test]
+    fn test_nsf2_unknown_chunks() {
+        let metadata = [chunk(b"tlbl", b"One"), chunk(b"VRC7", &[1])].concat();
+
+        // Optional metadata can be skipped...
+        let nsf = parse(&nsf_file(2, 0, &metadata)).unwrap();
+        assert_eq!(nsf.tracks[0].title.as_deref(), Some("One"));
+
+        // ...but not mandatory metadata
+        assert_eq!(error(parse(&nsf_file(2, 0x80, &metadata))), "unsupported VRC7 chunk");
+
+        // Without a data length, anything after the header is music data,
+        // and not metadata
+        let nsf = parse(&nsf_file(1, 0x80, &metadata)).unwrap();
+        assert_eq!(nsf.data.len(), 16 + metadata.len());
+        assert!(nsf.tracks[0].title.is_none());
+    }
+
+    #[test]
+    fn test_load_address() {
+        // Below $8000 is only allowed for bankswitched music, which is
+        // placed by its banks instead
+        assert_eq!(error(parse(&nsf_image(0x6000, &[0; 16], 1, 0, &[]))),
+                   "the load address $6000 is below $8000");
+
+        let mut bytes = nsf_image(0x7000, &[0; 16], 1, 0, &[]);
+        bytes[0x71] = 1;
+        assert!(parse(&bytes).is_ok());
+
+        assert_eq!(error(parse(&nsfe_file(&[chunk(b"INFO", &[0x00, 0x60, 0x10, 0x80, 0x20, 0x80, 0x00, 0x00, 4, 1]), data()]))),
+                   "the load address $6000 is below $8000");
+    }
+
+    // A console playing `nsf', with the CPU stepped and the player ticked
+    // the same way the console does it
+    struct TestPlayer {
+        cpu: CPU,
+        player: NSFPlayer,
+    }
+
+    impl TestPlayer {
+        fn new_test_player(nsf: NSF) -> Self {
+            use std::cell::RefCell;
+            use std::rc::Rc;
+
+            use crate::apu::APU;
+            use crate::controller::Controller;
+            use crate::mapper::{Mapper, NSFMapper};
+            use crate::mem::NESMemory;
+            use crate::ppu::PPU;
+
+            let mapper: Box<dyn Mapper> = Box::new(NSFMapper::new_mapper(&nsf));
+            let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(Rc::new(RefCell::new(mapper)), Region::NTSC)));
+            let apu = Rc::new(RefCell::new(APU::new_nes_apu(Region::NTSC)));
+            let controller = Rc::new(RefCell::new(Controller::new_controller()));
+
+            Self {
+                cpu: CPU::new_cpu(Box::new(NESMemory::new_nes_mem(ppu, apu, controller))),
+                player: NSFPlayer::new_nsf_player(nsf, Region::NTSC),
+            }
+        }
+
+        fn run(&mut self, periods: f64) {
+            let cycles = (self.player.play_period * periods) as u64;
+            let mut elapsed = 0;
+
+            while elapsed < cycles {
+                let step = self.cpu.step();
+                self.player.tick(&mut self.cpu, step);
+                elapsed += step;
+            }
+        }
+    }
+
+    // INIT stores A and X at $00 and $01 and counts its calls at $02, and
+    // PLAY, which is moved to $8040, counts its calls at $03. INIT runs
+    // `init_delay' before it returns.
+    fn player_image(init_delay: &[u8]) -> NSF {
+        let mut init = init_delay.to_vec();
+        init.extend_from_slice(&[0x85, 0x00, 0x86, 0x01, 0xe6, 0x02, 0x60]);
+
+        let mut data = vec![0xea; 0x43];
+        data[0x10 .. 0x10 + init.len()].copy_from_slice(&init);
+        data[0x40 .. 0x43].copy_from_slice(&[0xe6, 0x03, 0x60]);
+
+        let mut nsf = parse(&nsf_image(0x8000, &data, 1, 0, &[])).unwrap();
+        nsf.play_address = 0x8040;
+        nsf
+    }
+
+    #[test]
+    fn test_player() {
+        let mut test = TestPlayer::new_test_player(player_image(&[]));
+        test.player.start_track(&mut test.cpu, 1);
+
+        // INIT runs straight away, with the track in A and NTSC in X, and
+        // then the CPU sits in the idle loop
+        test.run(0.5);
+        assert_eq!([test.cpu.read(0x00), test.cpu.read(0x01), test.cpu.read(0x02)], [1, 0, 1]);
+        assert_eq!(test.cpu.read(0x03), 0);
+        assert!((IDLE_ADDRESS ..= IDLE_ADDRESS + 2).contains(&test.cpu.pc));
+
+        // PLAY is called once per period
+        test.run(5.0);
+        assert_eq!(test.cpu.read(0x03), 5);
+        assert_eq!(test.cpu.read(0x02), 1);
+
+        // Starting a track clears RAM and calls INIT again
+        test.player.start_track(&mut test.cpu, 2);
+        test.run(0.5);
+        assert_eq!([test.cpu.read(0x00), test.cpu.read(0x02), test.cpu.read(0x03)], [2, 1, 0]);
+    }
+
+    #[test]
+    fn test_player_waits_for_init() {
+        // LDX #0; LDY #0; loop: DEY; BNE loop; DEX; BNE loop, which takes
+        // about 11 PLAY periods
+        let delay = [0xa2, 0x00, 0xa0, 0x00, 0x88, 0xd0, 0xfd, 0xca, 0xd0, 0xfa];
+        let mut test = TestPlayer::new_test_player(player_image(&delay));
+        test.player.start_track(&mut test.cpu, 0);
+
+        test.run(10.0);
+        assert_eq!(test.cpu.read(0x02), 0);
+        assert_eq!(test.cpu.read(0x03), 0);
+
+        // PLAY is called as soon as INIT returns, and then once per period,
+        // without making up for the calls that were missed
+        test.run(2.0);
+        assert_eq!(test.cpu.read(0x02), 1);
+        assert!(test.cpu.read(0x03) >= 1);
+
+        test.run(5.0);
+        let plays = test.cpu.read(0x03);
+        assert!((6 ..= 8).contains(&plays), "{} calls to PLAY", plays);
+    }
+}
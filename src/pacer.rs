@@ -0,0 +1,278 @@
+// Keeps the emulator running at the console's real frame rate.
+//
+// Rather than sleeping for a fixed amount of time after each frame, which
+// drifts by however long the frame took to emulate and draw, and by however
+// much the OS oversleeps, each frame has a deadline that's exactly one frame
+// after the previous one. Being late for one frame means sleeping a bit less
+// for the next one, so the average rate comes out right.
+//
+// The OS is only trusted to sleep until shortly before the deadline, and the
+// rest of the time is spent spinning, since thread::sleep is often a
+// millisecond or more out.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How close to the deadline to stop sleeping and start spinning
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500);
+
+// If we fall further behind than this many frames (e.g. the window was being
+// dragged around, or the emulator was paused), give up on catching up and
+// start again from now, rather than running flat out until we're back on
+// schedule.
+const MAX_FRAMES_BEHIND: f64 = 4.0;
+
+// The speeds that can be cycled through
+const SPEEDS: [f64; 7] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 4.0];
+const NORMAL_SPEED: usize = 3;
+
+pub struct FramePacer {
+    // The console's frame rate, at normal speed
+    frame_rate: f64,
+
+    // When presenting frames is synced to the display's vertical blank, at
+    // normal speed the display does the pacing for us
+    vsync: bool,
+
+    // Index into SPEEDS
+    speed: usize,
+
+    // When the next frame is due
+    deadline: Instant,
+
+    // Used to drop frames when fast-forwarding with vsync on, because every
+    // frame that's presented takes a whole display refresh
+    present_credit: f64,
+}
+
+impl FramePacer {
+    pub fn new_frame_pacer(frame_rate: f64, vsync: bool) -> Self {
+        Self {
+            frame_rate: frame_rate,
+            vsync: vsync,
+            speed: NORMAL_SPEED,
+            deadline: Instant::now(),
+            present_credit: 0.0,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        SPEEDS[self.speed]
+    }
+
+    pub fn faster(&mut self) {
+        if self.speed < SPEEDS.len() - 1 {
+            self.speed += 1;
+        }
+
+        self.reset();
+    }
+
+    pub fn slower(&mut self) {
+        if self.speed > 0 {
+            self.speed -= 1;
+        }
+
+        self.reset();
+    }
+
+    pub fn normal_speed(&mut self) {
+        self.speed = NORMAL_SPEED;
+        self.reset();
+    }
+
+    // Starts pacing again from now, after a pause or a change of speed
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now();
+        self.present_credit = 0.0;
+    }
+
+    fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / (self.frame_rate * self.speed()))
+    }
+
+    // Whether the frame that just finished should be drawn. This is always
+    // true unless fast-forwarding with vsync on, where only as many frames as
+    // the display can show are drawn.
+    pub fn should_present(&mut self) -> bool {
+        if !self.vsync || self.speed() <= 1.0 {
+            return true;
+        }
+
+        self.present_credit += 1.0 / self.speed();
+
+        if self.present_credit >= 1.0 {
+            self.present_credit -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Moves the deadline on to the next frame, and returns how long there is
+    // to wait for it as of `now', or None if there's no need to wait
+    fn next_deadline(&mut self, now: Instant) -> Option<Duration> {
+        let duration = self.frame_duration();
+        self.deadline += duration;
+
+        if now > self.deadline {
+            let behind = (now - self.deadline).as_secs_f64() / duration.as_secs_f64();
+
+            if behind > MAX_FRAMES_BEHIND {
+                debug!("{:.1} frames behind, resetting the frame pacer", behind);
+                self.deadline = now;
+            }
+
+            return None;
+        }
+
+        // Presenting the frame has already waited for the display
+        if self.vsync && self.speed() == 1.0 {
+            self.deadline = now;
+            return None;
+        }
+
+        Some(self.deadline - now)
+    }
+
+    // Waits until the next frame is due
+    pub fn wait(&mut self) {
+        let remaining = match self.next_deadline(Instant::now()) {
+            Some(remaining) => remaining,
+            None            => return,
+        };
+
+        if remaining > SPIN_THRESHOLD {
+            thread::sleep(remaining - SPIN_THRESHOLD);
+        }
+
+        while Instant::now() < self.deadline {
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(pacer: &FramePacer, n: f64) -> Duration {
+        pacer.frame_duration().mul_f64(n)
+    }
+
+    #[test]
+    fn test_on_time() {
+        let mut pacer = FramePacer::new_frame_pacer(50.0, false);
+        let start = pacer.deadline;
+
+        // Taking no time at all leaves a whole frame to wait
+        assert_eq!(pacer.next_deadline(start), Some(frames(&pacer, 1.0)));
+
+        // Taking part of a frame leaves the rest, and the deadlines stay a
+        // frame apart however long each frame took
+        let now = start + frames(&pacer, 1.25);
+        assert_eq!(pacer.next_deadline(now), Some(frames(&pacer, 0.75)));
+        assert_eq!(pacer.deadline, start + frames(&pacer, 2.0));
+    }
+
+    #[test]
+    fn test_catching_up() {
+        let mut pacer = FramePacer::new_frame_pacer(50.0, false);
+        let start = pacer.deadline;
+
+        // Two and a half frames behind, so the next two frames run straight
+        // away, and the one after waits for the rest
+        let now = start + frames(&pacer, 2.5);
+        assert_eq!(pacer.next_deadline(now), None);
+        assert_eq!(pacer.next_deadline(now), None);
+        assert_eq!(pacer.next_deadline(now), Some(frames(&pacer, 0.5)));
+        assert_eq!(pacer.deadline, start + frames(&pacer, 3.0));
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut pacer = FramePacer::new_frame_pacer(50.0, false);
+        let start = pacer.deadline;
+
+        // Too far behind to catch up, so it starts again from now
+        let now = start + frames(&pacer, MAX_FRAMES_BEHIND + 2.0);
+        assert_eq!(pacer.next_deadline(now), None);
+        assert_eq!(pacer.deadline, now);
+        assert_eq!(pacer.next_deadline(now), Some(frames(&pacer, 1.0)));
+    }
+
+    #[test]
+    fn test_vsync() {
+        let mut pacer = FramePacer::new_frame_pacer(60.0, true);
+        let start = pacer.deadline;
+
+        // The display does the waiting at normal speed...
+        let now = start + frames(&pacer, 0.5);
+        assert_eq!(pacer.next_deadline(now), None);
+        assert_eq!(pacer.deadline, now);
+
+        // ...but not at any other
+        pacer.slower();
+        let start = pacer.deadline;
+        assert_eq!(pacer.next_deadline(start), Some(frames(&pacer, 1.0)));
+    }
+
+    #[test]
+    fn test_speeds() {
+        let mut pacer = FramePacer::new_frame_pacer(50.0, false);
+        let normal = pacer.frame_duration();
+
+        pacer.faster();
+        assert_eq!(pacer.speed(), 1.5);
+
+        for _ in 0 .. 10 {
+            pacer.faster();
+        }
+        assert_eq!(pacer.speed(), 4.0);
+        assert_eq!(pacer.frame_duration(), normal / 4);
+
+        for _ in 0 .. 10 {
+            pacer.slower();
+        }
+        assert_eq!(pacer.speed(), 0.25);
+        assert_eq!(pacer.frame_duration(), normal * 4);
+
+        pacer.normal_speed();
+        assert_eq!(pacer.speed(), 1.0);
+        assert_eq!(pacer.frame_duration(), normal);
+    }
+
+    // Which of the next few frames are presented
+    fn presented(pacer: &mut FramePacer) -> Vec<bool> {
+        (0 .. 8).map(|_| pacer.should_present()).collect()
+    }
+
+    #[test]
+    fn test_frame_skipping() {
+        let mut pacer = FramePacer::new_frame_pacer(60.0, true);
+        assert!(presented(&mut pacer).iter().all(|p| *p));
+
+        // Two frames in every three at 1.5x
+        pacer.faster();
+        let count = (0 .. 30).filter(|_| pacer.should_present()).count();
+        assert!((19 ..= 21).contains(&count));
+
+        pacer.faster();
+        assert_eq!(presented(&mut pacer), [false, true, false, true, false, true, false, true]);
+
+        pacer.faster();
+        assert_eq!(presented(&mut pacer), [false, false, false, true, false, false, false, true]);
+
+        // Slowing down never skips
+        pacer.normal_speed();
+        pacer.slower();
+        assert!(presented(&mut pacer).iter().all(|p| *p));
+
+        // And without vsync, every frame is presented however fast it goes
+        let mut pacer = FramePacer::new_frame_pacer(60.0, false);
+        pacer.faster();
+        pacer.faster();
+        pacer.faster();
+        assert!(presented(&mut pacer).iter().all(|p| *p));
+    }
+}
@@ -0,0 +1,892 @@
+// A small, self-contained PNG encoder and decoder, so that screenshots and
+// CHR exports work without any image libraries.
+//
+// https://www.w3.org/TR/PNG/
+//
+// The encoder writes 8-bit RGB or 8-bit indexed images, compressed with a
+// simple LZ77 matcher and the fixed Huffman codes from DEFLATE. It won't win
+// any prizes, but NES graphics are mostly flat colours, which compress well
+// even like this.
+//
+// The decoder handles what image editors are likely to save an edited CHR
+// export as: non-interlaced greyscale, RGB, indexed and with alpha, at any of
+// the standard bit depths up to 8.
+
+use std::fmt;
+
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+const COLOR_GREY: u8       = 0;
+const COLOR_RGB: u8        = 2;
+const COLOR_INDEXED: u8    = 3;
+const COLOR_GREY_ALPHA: u8 = 4;
+const COLOR_RGBA: u8       = 6;
+
+#[derive(Debug)]
+pub enum PNGError {
+    InvalidSignature,
+    Invalid(&'static str),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for PNGError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PNGError::InvalidSignature => write!(f, "not a PNG file"),
+            PNGError::Invalid(why)     => write!(f, "invalid PNG: {}", why),
+            PNGError::Unsupported(why) => write!(f, "unsupported PNG: {}", why),
+        }
+    }
+}
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+
+    // 3 bytes per pixel
+    pub rgb: Vec<u8>,
+
+    // For indexed images, the palette index of each pixel
+    pub indices: Option<Vec<u8>>,
+}
+
+//
+// Checksums
+//
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+
+    for b in data.iter() {
+        crc ^= *b as u32;
+
+        for _ in 0 .. 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1_u32;
+    let mut b = 0_u32;
+
+    for chunk in data.chunks(5552) {
+        for v in chunk.iter() {
+            a += *v as u32;
+            b += a;
+        }
+
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+//
+// DEFLATE tables, shared by the compressor and decompressor
+//
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// The order that code length code lengths are stored in, in dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+//
+// Compression
+//
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_SIZE: usize = 1 << 15;
+
+struct BitWriter {
+    output: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, val: u32, count: u32) {
+        self.bits |= val << self.count;
+        self.count += count;
+
+        while self.count >= 8 {
+            self.output.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn write_code(&mut self, code: u32, len: u32) {
+        let mut reversed = 0;
+
+        for i in 0 .. len {
+            reversed |= ((code >> i) & 1) << (len - 1 - i);
+        }
+
+        self.write_bits(reversed, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.output.push(self.bits as u8);
+        }
+
+        self.output
+    }
+}
+
+fn write_literal(w: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+
+    match symbol {
+        0   ..= 143 => w.write_code(0x30 + symbol, 8),
+        144 ..= 255 => w.write_code(0x190 + symbol - 144, 9),
+        256 ..= 279 => w.write_code(symbol - 256, 7),
+        _           => w.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let i = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+    write_literal(w, 257 + i as u16);
+    w.write_bits((length - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i] as u32);
+
+    let i = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+    w.write_code(i as u32, 5);
+    w.write_bits((distance - DISTANCE_BASE[i] as usize) as u32, DISTANCE_EXTRA[i] as u32);
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let v = ((data[pos] as usize) << 10)
+          ^ ((data[pos + 1] as usize) << 5)
+          ^ (data[pos + 2] as usize);
+
+    v % HASH_SIZE
+}
+
+// Compresses `data' into a single fixed Huffman DEFLATE block, wrapped in a
+// zlib stream
+fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        output: vec![0x78, 0x01],
+        bits: 0,
+        count: 0,
+    };
+
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman codes)
+    w.write_bits(1, 1);
+    w.write_bits(1, 2);
+
+    // The most recent position that each hash was seen at, and a chain of the
+    // positions before that with the same hash
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(data, pos);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+
+        if pos + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(data, pos)];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = (0 .. max_length)
+                    .take_while(|i| data[candidate + i] == data[pos + i])
+                    .count();
+
+                if length > best_length {
+                    best_length = length;
+                    best_distance = pos - candidate;
+
+                    if length == max_length {
+                        break;
+                    }
+                }
+
+                let next = prev[candidate % WINDOW_SIZE];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut w, best_length, best_distance);
+
+            for p in pos .. pos + best_length {
+                insert(p, &mut head, &mut prev);
+            }
+
+            pos += best_length;
+        } else {
+            write_literal(&mut w, data[pos] as u16);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+
+    // End of block
+    write_literal(&mut w, 256);
+
+    let mut output = w.finish();
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+//
+// Decompression
+//
+
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32, PNGError> {
+        while self.count < count {
+            if self.pos >= self.input.len() {
+                return Err(PNGError::Invalid("compressed data ends early"));
+            }
+
+            self.bits |= (self.input[self.pos] as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+
+        let val = self.bits & ((1 << count) - 1);
+        self.bits >>= count;
+        self.count -= count;
+
+        Ok(val)
+    }
+
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+// A canonical Huffman code, stored as the number of codes of each length, and
+// the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new_huffman(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for len in lengths.iter() {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; 16];
+        for len in 1 .. 16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+
+        Self {
+            counts: counts,
+            symbols: symbols,
+        }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, PNGError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for len in 1 .. 16 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(PNGError::Invalid("bad Huffman code"))
+    }
+}
+
+fn inflate_block(r: &mut BitReader,
+                 output: &mut Vec<u8>,
+                 literals: &Huffman,
+                 distances: &Huffman)
+    -> Result<(), PNGError>
+{
+    loop {
+        let symbol = literals.decode(r)? as usize;
+
+        match symbol {
+            0 ..= 255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            257 ..= 285 => {
+                let i = symbol - 257;
+                let length = LENGTH_BASE[i] as usize + r.bits(LENGTH_EXTRA[i] as u32)? as usize;
+
+                let i = distances.decode(r)? as usize;
+                if i >= 30 {
+                    return Err(PNGError::Invalid("bad distance code"));
+                }
+                let distance = DISTANCE_BASE[i] as usize + r.bits(DISTANCE_EXTRA[i] as u32)? as usize;
+
+                if distance > output.len() {
+                    return Err(PNGError::Invalid("distance too far back"));
+                }
+
+                let start = output.len() - distance;
+                for j in 0 .. length {
+                    let b = output[start + j];
+                    output.push(b);
+                }
+            },
+            _ => return Err(PNGError::Invalid("bad length code")),
+        }
+    }
+}
+
+fn read_dynamic_tables(r: &mut BitReader) -> Result<(Huffman, Huffman), PNGError> {
+    let n_literals = r.bits(5)? as usize + 257;
+    let n_distances = r.bits(5)? as usize + 1;
+    let n_code_lengths = r.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(n_code_lengths) {
+        code_lengths[*i] = r.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new_huffman(&code_lengths);
+
+    let mut lengths = vec![];
+    while lengths.len() < n_literals + n_distances {
+        let symbol = code_lengths.decode(r)?;
+
+        let (len, repeat) = match symbol {
+            0 ..= 15 => (symbol as u8, 1),
+            16 => {
+                let prev = *lengths.last().ok_or(PNGError::Invalid("repeat with no length"))?;
+                (prev, 3 + r.bits(2)?)
+            },
+            17 => (0, 3 + r.bits(3)?),
+            _  => (0, 11 + r.bits(7)?),
+        };
+
+        for _ in 0 .. repeat {
+            lengths.push(len);
+        }
+    }
+
+    if lengths.len() > n_literals + n_distances {
+        return Err(PNGError::Invalid("too many code lengths"));
+    }
+
+    Ok((Huffman::new_huffman(&lengths[.. n_literals]),
+        Huffman::new_huffman(&lengths[n_literals ..])))
+}
+
+fn zlib_decompress(input: &[u8]) -> Result<Vec<u8>, PNGError> {
+    if input.len() < 2 || input[0] & 0x0f != 8 {
+        return Err(PNGError::Invalid("not a zlib stream"));
+    }
+
+    let mut r = BitReader {
+        input: &input[2 ..],
+        pos: 0,
+        bits: 0,
+        count: 0,
+    };
+
+    let mut output = vec![];
+
+    loop {
+        let last = r.bits(1)? == 1;
+
+        match r.bits(2)? {
+            // Stored
+            0 => {
+                r.align();
+
+                let start = r.pos;
+                if start + 4 > r.input.len() {
+                    return Err(PNGError::Invalid("compressed data ends early"));
+                }
+
+                let len = u16::from_le_bytes([r.input[start], r.input[start + 1]]) as usize;
+                let start = start + 4;
+
+                if start + len > r.input.len() {
+                    return Err(PNGError::Invalid("compressed data ends early"));
+                }
+
+                output.extend_from_slice(&r.input[start .. start + len]);
+                r.pos = start + len;
+            },
+
+            // Fixed Huffman codes
+            1 => {
+                let mut lengths = [0; 288];
+                for (i, len) in lengths.iter_mut().enumerate() {
+                    *len = match i {
+                        0   ..= 143 => 8,
+                        144 ..= 255 => 9,
+                        256 ..= 279 => 7,
+                        _           => 8,
+                    };
+                }
+
+                let literals = Huffman::new_huffman(&lengths);
+                let distances = Huffman::new_huffman(&[5; 30]);
+                inflate_block(&mut r, &mut output, &literals, &distances)?;
+            },
+
+            // Dynamic Huffman codes
+            2 => {
+                let (literals, distances) = read_dynamic_tables(&mut r)?;
+                inflate_block(&mut r, &mut output, &literals, &distances)?;
+            },
+
+            _ => return Err(PNGError::Invalid("bad block type")),
+        }
+
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+//
+// Filtering
+//
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Filters each row with whichever of None, Sub or Up looks like it will
+// compress best, going by the usual heuristic of the smallest sum of
+// absolute differences.
+fn filter(data: &[u8], stride: usize, bpp: usize) -> Vec<u8> {
+    let mut output = vec![];
+    let empty = vec![0; stride];
+
+    for (y, row) in data.chunks(stride).enumerate() {
+        let above = if y == 0 { &empty[..] } else { &data[(y - 1) * stride .. y * stride] };
+
+        let none: Vec<u8> = row.to_vec();
+        let sub: Vec<u8> = (0 .. stride)
+            .map(|i| row[i].wrapping_sub(if i >= bpp { row[i - bpp] } else { 0 }))
+            .collect();
+        let up: Vec<u8> = (0 .. stride)
+            .map(|i| row[i].wrapping_sub(above[i]))
+            .collect();
+
+        let cost = |v: &Vec<u8>| -> u32 {
+            v.iter().map(|b| (*b as i8).unsigned_abs() as u32).sum()
+        };
+
+        let (kind, filtered) = [(0, none), (1, sub), (2, up)].iter()
+            .min_by_key(|(_, v)| cost(v))
+            .cloned()
+            .unwrap();
+
+        output.push(kind);
+        output.extend_from_slice(&filtered);
+    }
+
+    output
+}
+
+fn unfilter(data: &[u8], stride: usize, bpp: usize, height: usize) -> Result<Vec<u8>, PNGError> {
+    if data.len() < (stride + 1) * height {
+        return Err(PNGError::Invalid("image data ends early"));
+    }
+
+    let mut output = vec![0; stride * height];
+
+    for y in 0 .. height {
+        let kind = data[y * (stride + 1)];
+        let row = &data[y * (stride + 1) + 1 .. (y + 1) * (stride + 1)];
+
+        for i in 0 .. stride {
+            let a = if i >= bpp { output[y * stride + i - bpp] } else { 0 };
+            let b = if y > 0 { output[(y - 1) * stride + i] } else { 0 };
+            let c = if y > 0 && i >= bpp { output[(y - 1) * stride + i - bpp] } else { 0 };
+
+            let prediction = match kind {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(PNGError::Invalid("bad filter type")),
+            };
+
+            output[y * stride + i] = row[i].wrapping_add(prediction);
+        }
+    }
+
+    Ok(output)
+}
+
+//
+// Encoding
+//
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+
+    let crc = crc32(&output[start ..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn encode(width: usize, height: usize, color_type: u8, bpp: usize, palette: Option<&[[u8; 3]]>, data: &[u8])
+    -> Vec<u8>
+{
+    let mut output = SIGNATURE.to_vec();
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.push(8);          // bit depth
+    header.push(color_type);
+    header.push(0);          // compression method
+    header.push(0);          // filter method
+    header.push(0);          // interlace method
+    write_chunk(&mut output, b"IHDR", &header);
+
+    if let Some(palette) = palette {
+        let entries: Vec<u8> = palette.iter().flat_map(|c| c.iter().cloned()).collect();
+        write_chunk(&mut output, b"PLTE", &entries);
+    }
+
+    let filtered = filter(data, width * bpp, bpp);
+    write_chunk(&mut output, b"IDAT", &zlib_compress(&filtered));
+    write_chunk(&mut output, b"IEND", &[]);
+
+    output
+}
+
+// Encodes an image with 3 bytes per pixel
+pub fn encode_rgb(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    encode(width, height, COLOR_RGB, 3, None, rgb)
+}
+
+// Encodes an image with one palette index per pixel
+pub fn encode_indexed(width: usize, height: usize, palette: &[[u8; 3]], indices: &[u8]) -> Vec<u8> {
+    encode(width, height, COLOR_INDEXED, 1, Some(palette), indices)
+}
+
+//
+// Decoding
+//
+
+pub fn decode(input: &[u8]) -> Result<Image, PNGError> {
+    if input.len() < 8 || input[0 .. 8] != SIGNATURE {
+        return Err(PNGError::InvalidSignature);
+    }
+
+    let mut width = 0;
+    let mut height = 0;
+    let mut bit_depth = 0;
+    let mut color_type = 0;
+    let mut palette = vec![];
+    let mut compressed = vec![];
+
+    let mut pos = 8;
+    loop {
+        if pos + 8 > input.len() {
+            return Err(PNGError::Invalid("missing IEND chunk"));
+        }
+
+        let len = u32::from_be_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]]) as usize;
+        let kind = &input[pos + 4 .. pos + 8];
+
+        if pos + 12 + len > input.len() {
+            return Err(PNGError::Invalid("chunk ends early"));
+        }
+
+        let data = &input[pos + 8 .. pos + 8 + len];
+        pos += 12 + len;
+
+        match kind {
+            b"IHDR" => {
+                if len != 13 {
+                    return Err(PNGError::Invalid("bad IHDR chunk"));
+                }
+
+                width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+                bit_depth = data[8];
+                color_type = data[9];
+
+                if data[12] != 0 {
+                    return Err(PNGError::Unsupported("interlaced images"));
+                }
+            },
+            b"PLTE" => {
+                palette = data.chunks(3)
+                    .filter(|c| c.len() == 3)
+                    .map(|c| [c[0], c[1], c[2]])
+                    .collect();
+            },
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => { },
+        }
+    }
+
+    let channels = match (color_type, bit_depth) {
+        (COLOR_GREY, 1) | (COLOR_GREY, 2) | (COLOR_GREY, 4) | (COLOR_GREY, 8) => 1,
+        (COLOR_INDEXED, 1) | (COLOR_INDEXED, 2) | (COLOR_INDEXED, 4) | (COLOR_INDEXED, 8) => 1,
+        (COLOR_RGB, 8)        => 3,
+        (COLOR_GREY_ALPHA, 8) => 2,
+        (COLOR_RGBA, 8)       => 4,
+        _ => return Err(PNGError::Unsupported("colour type or bit depth")),
+    };
+
+    if color_type == COLOR_INDEXED && palette.is_empty() {
+        return Err(PNGError::Invalid("indexed image with no palette"));
+    }
+
+    let bits_per_pixel = channels * bit_depth as usize;
+    let stride = (width * bits_per_pixel).div_ceil(8);
+    let bpp = bits_per_pixel.div_ceil(8);
+
+    let data = zlib_decompress(&compressed)?;
+    let data = unfilter(&data, stride, bpp, height)?;
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    let mut indices = vec![];
+
+    for y in 0 .. height {
+        let row = &data[y * stride .. (y + 1) * stride];
+
+        for x in 0 .. width {
+            let sample = |channel: usize| -> u8 {
+                if bit_depth == 8 {
+                    row[x * channels + channel]
+                } else {
+                    let bit = x * bit_depth as usize;
+                    let shift = 8 - bit_depth as usize - (bit % 8);
+                    (row[bit / 8] >> shift) & ((1 << bit_depth) - 1)
+                }
+            };
+
+            match color_type {
+                COLOR_INDEXED => {
+                    let i = sample(0);
+                    let color = palette.get(i as usize).cloned().unwrap_or([0, 0, 0]);
+                    rgb.extend_from_slice(&color);
+                    indices.push(i);
+                },
+                COLOR_GREY | COLOR_GREY_ALPHA => {
+                    // Scale lower bit depths up to the full 0-255 range
+                    let max = (1_u16 << bit_depth) - 1;
+                    let v = (sample(0) as u16 * 255 / max) as u8;
+                    rgb.extend_from_slice(&[v, v, v]);
+                },
+                _ => {
+                    rgb.extend_from_slice(&[sample(0), sample(1), sample(2)]);
+                },
+            }
+        }
+    }
+
+    Ok(Image {
+        width: width,
+        height: height,
+        rgb: rgb,
+        indices: if color_type == COLOR_INDEXED { Some(indices) } else { None },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 12x8 RGB images made with zlib rather than our own encoder, one
+    // compressed with dynamic Huffman codes, and one in stored blocks split
+    // across two IDAT chunks. Each row uses a different filter, cycling
+    // through all five.
+    const DYNAMIC_PNG: [u8; 296] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x08, 0x08, 0x02, 0x00, 0x00, 0x00, 0x42, 0x86, 0x89,
+        0xa6, 0x00, 0x00, 0x00, 0xef, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x4d, 0x8e, 0xa1, 0x4b, 0x43,
+        0x61, 0x14, 0x47, 0xcf, 0xdc, 0x0b, 0x17, 0xbe, 0xf2, 0x95, 0x2b, 0x08, 0x8f, 0x31, 0x8b, 0xc2,
+        0x40, 0x18, 0xec, 0x1f, 0x18, 0xfe, 0x05, 0xcb, 0x17, 0xc3, 0x8c, 0xb7, 0xad, 0xae, 0x8c, 0x45,
+        0x93, 0xcc, 0x62, 0x11, 0xc6, 0x60, 0x18, 0xd4, 0x89, 0xc3, 0x22, 0xac, 0x18, 0x2c, 0x16, 0x8d,
+        0xaf, 0x08, 0x46, 0x93, 0xc5, 0xc1, 0x98, 0xb0, 0xe2, 0xe7, 0xb3, 0x58, 0x0e, 0x3f, 0x0e, 0x97,
+        0xcb, 0x01, 0x2c, 0x62, 0x75, 0xac, 0x89, 0xb5, 0xb1, 0x0e, 0xd6, 0xc5, 0x7a, 0xd8, 0x10, 0x1b,
+        0x61, 0x13, 0x6c, 0x8e, 0x55, 0xd0, 0x74, 0x54, 0x8d, 0xac, 0x23, 0x9b, 0x92, 0xad, 0x7f, 0x3b,
+        0xf9, 0x87, 0xb4, 0xb7, 0x50, 0xd0, 0x35, 0xba, 0x44, 0x77, 0xd1, 0x1c, 0x9d, 0xa2, 0x63, 0xf4,
+        0x05, 0x7d, 0x46, 0x1f, 0xd1, 0x05, 0x7a, 0x55, 0xa5, 0x71, 0x20, 0xe1, 0x5b, 0xc2, 0x9e, 0x84,
+        0x95, 0x84, 0x1b, 0x09, 0x35, 0x09, 0x99, 0x84, 0x0b, 0x09, 0x7d, 0x09, 0x47, 0x12, 0xae, 0x25,
+        0xec, 0x64, 0xbf, 0x9f, 0x58, 0xc3, 0x12, 0x66, 0x90, 0xc3, 0xb4, 0x64, 0xda, 0x5f, 0xf0, 0x09,
+        0x0b, 0xf8, 0x00, 0xb7, 0xe8, 0xfd, 0xba, 0xe7, 0x4d, 0x6f, 0xb5, 0xbd, 0xe8, 0xf8, 0xaa, 0xeb,
+        0x27, 0x3d, 0xbf, 0x1b, 0xfa, 0xe1, 0xc8, 0x8f, 0x27, 0x9e, 0xcd, 0xfd, 0xad, 0xc2, 0x20, 0x85,
+        0x6f, 0x47, 0xc6, 0x25, 0x5f, 0x23, 0xe7, 0x91, 0x5a, 0xa4, 0x88, 0x9c, 0x96, 0xe6, 0x3d, 0x99,
+        0xbf, 0xf0, 0x0d, 0xda, 0x40, 0x2f, 0xd1, 0x19, 0xba, 0x8f, 0x16, 0xe8, 0x59, 0x19, 0xfe, 0x84,
+        0xde, 0xa3, 0xb7, 0x3f, 0x8f, 0x58, 0x44, 0xa3, 0x54, 0x1b, 0xca, 0xc9, 0x00, 0x00, 0x00, 0x00,
+        0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    const STORED_PNG: [u8; 376] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x08, 0x08, 0x02, 0x00, 0x00, 0x00, 0x42, 0x86, 0x89,
+        0xa6, 0x00, 0x00, 0x00, 0x64, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x01, 0x28, 0x01, 0xd7, 0xfe,
+        0x00, 0x00, 0x00, 0x5a, 0x10, 0x00, 0x5a, 0x20, 0x00, 0x5a, 0x30, 0x00, 0x5a, 0x40, 0x00, 0x5a,
+        0x50, 0x00, 0x5a, 0x60, 0x00, 0x5a, 0x70, 0x00, 0x5a, 0x80, 0x00, 0x5a, 0x90, 0x00, 0x5a, 0xa0,
+        0x00, 0x5a, 0xb0, 0x00, 0x5a, 0x01, 0x00, 0x15, 0x5a, 0x10, 0x00, 0x03, 0x10, 0x00, 0xf7, 0x10,
+        0x00, 0xfb, 0x10, 0x00, 0xf7, 0x10, 0x00, 0x33, 0x10, 0x00, 0xf7, 0x10, 0x00, 0xfb, 0x10, 0x00,
+        0xf7, 0x10, 0x00, 0x03, 0x10, 0x00, 0xb7, 0x10, 0x00, 0xfb, 0x02, 0x00, 0x15, 0x00, 0x00, 0x15,
+        0xf7, 0x00, 0x15, 0xf2, 0x00, 0x15, 0x21, 0x00, 0x15, 0x1c, 0x00, 0x15, 0xa3, 0xbd, 0x11, 0xfd,
+        0x3c, 0x00, 0x00, 0x00, 0xcf, 0x49, 0x44, 0x41, 0x54, 0x00, 0x15, 0x9e, 0x00, 0x15, 0xcd, 0x00,
+        0x15, 0xc8, 0x00, 0x15, 0xbf, 0x00, 0x15, 0xba, 0x00, 0x15, 0xa9, 0x03, 0x00, 0x2a, 0x2d, 0x08,
+        0x0b, 0xf8, 0x08, 0x0b, 0x26, 0x08, 0x0b, 0xf5, 0x08, 0x0b, 0xab, 0x08, 0x0b, 0x1e, 0x08, 0x0b,
+        0x04, 0x08, 0x0b, 0x9b, 0x08, 0x0b, 0x79, 0x08, 0x0b, 0x5c, 0x08, 0x0b, 0xaa, 0x08, 0x0b, 0x19,
+        0x04, 0x00, 0x15, 0x00, 0x00, 0x00, 0xf7, 0x00, 0x00, 0xf2, 0x00, 0x00, 0xac, 0x00, 0x00, 0x1c,
+        0x00, 0x00, 0xa3, 0x00, 0x00, 0x1c, 0x00, 0x00, 0xac, 0x00, 0x00, 0xf1, 0x00, 0x00, 0xec, 0x00,
+        0x00, 0xba, 0x00, 0x00, 0xe6, 0x00, 0x00, 0x69, 0x5a, 0x10, 0x69, 0x79, 0x20, 0x69, 0x1c, 0x30,
+        0x69, 0x33, 0x40, 0x69, 0xd6, 0x50, 0x69, 0xf5, 0x60, 0x69, 0x88, 0x70, 0x69, 0xaf, 0x80, 0x69,
+        0x42, 0x90, 0x69, 0x61, 0xa0, 0x69, 0x04, 0xb0, 0x69, 0xdb, 0x01, 0x00, 0x7e, 0x5a, 0x10, 0x00,
+        0x16, 0x10, 0x00, 0x9e, 0x10, 0x00, 0x16, 0x10, 0x00, 0xce, 0x10, 0x00, 0x96, 0x10, 0x00, 0x1e,
+        0x10, 0x00, 0xd6, 0x10, 0x00, 0x8e, 0x10, 0x00, 0x16, 0x10, 0x00, 0xde, 0x10, 0x00, 0x96, 0x02,
+        0x00, 0x15, 0x00, 0x00, 0x15, 0xfb, 0x00, 0x15, 0x2a, 0x00, 0x15, 0xa5, 0x00, 0x15, 0xac, 0x00,
+        0x15, 0x27, 0x00, 0x15, 0xd6, 0x00, 0x15, 0x91, 0x00, 0x15, 0xc8, 0x00, 0x15, 0xc3, 0x00, 0x15,
+        0xb2, 0x00, 0x15, 0xad, 0x8f, 0x58, 0x44, 0xa3, 0x05, 0x01, 0x0a, 0x87, 0x00, 0x00, 0x00, 0x00,
+        0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    // The pixels in both of the images above
+    fn fixture_pixels() -> Vec<u8> {
+        let mut rgb = Vec::new();
+
+        for y in 0 .. 8 {
+            for x in 0 .. 12 {
+                rgb.extend_from_slice(&[(x * 16) as u8, (y * 21) as u8, ((x * y * 7) ^ 0x5a) as u8]);
+            }
+        }
+
+        rgb
+    }
+
+    #[test]
+    fn test_decode_dynamic_huffman() {
+        let image = decode(&DYNAMIC_PNG).unwrap();
+
+        assert_eq!((image.width, image.height), (12, 8));
+        assert_eq!(image.rgb, fixture_pixels());
+    }
+
+    #[test]
+    fn test_decode_stored() {
+        let image = decode(&STORED_PNG).unwrap();
+
+        assert_eq!((image.width, image.height), (12, 8));
+        assert_eq!(image.rgb, fixture_pixels());
+    }
+
+    #[test]
+    fn test_rgb_round_trip() {
+        let (width, height) = (37, 11);
+        let rgb: Vec<u8> = (0 .. width * height * 3)
+            .map(|i| ((i * 7) % 13 * 19) as u8)
+            .collect();
+
+        let image = decode(&encode_rgb(width, height, &rgb)).unwrap();
+
+        assert_eq!(image.width, width);
+        assert_eq!(image.height, height);
+        assert_eq!(image.rgb, rgb);
+        assert!(image.indices.is_none());
+    }
+
+    #[test]
+    fn test_indexed_round_trip() {
+        let palette = [[0, 0, 0], [85, 85, 85], [170, 170, 170], [255, 255, 255]];
+        let indices: Vec<u8> = (0 .. 128 * 64).map(|i| (i / 3 % 4) as u8).collect();
+
+        let image = decode(&encode_indexed(128, 64, &palette, &indices)).unwrap();
+
+        assert_eq!(image.indices, Some(indices));
+        assert_eq!(image.rgb[0 .. 3], [0, 0, 0]);
+        assert_eq!(image.rgb[9 .. 12], [85, 85, 85]);
+    }
+}
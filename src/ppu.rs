@@ -1,12 +1,16 @@
 mod debug;
+mod events;
 mod regs;
+mod sprites;
 
 use std::cell::RefCell;
 use std::fs::File;
+use std::io::{Read, Write};
 use std::io;
 use std::rc::Rc;
 
 use crate::palette::PALETTE;
+use crate::png;
 use crate::mapper::{Mapper, MapperEvent};
 use crate::mem::Memory;
 use crate::ppu::regs::PPUCtrl;
@@ -14,10 +18,18 @@ use crate::ppu::regs::PPUMask;
 use crate::ppu::regs::PPUStatus;
 use crate::ppu::regs::OAM;
 use crate::ppu::regs::PPUData;
+use crate::ppu::sprites::SpriteEvaluation;
+use crate::region::Region;
 use crate::serde;
 
+pub use crate::ppu::events::{Event, EventKind};
+
 use sdl2::pixels::Color;
 
+// Each bit of the I/O latch decays to 0 roughly 600ms after it was last
+// refreshed, which is this many PPU cycles.
+const IO_LATCH_DECAY_CYCLES: u64 = 3_221_590;
+
 pub struct PPU {
     // PPU registers
     ctrl: PPUCtrl,
@@ -32,6 +44,19 @@ pub struct PPU {
     dot: u16,
     scanline: u16,
 
+    // Total number of PPU cycles executed, used to timestamp the addresses
+    // that mappers see on the PPU bus
+    cycles: u64,
+
+    // The region decides how many scanlines there are, and how many dots the
+    // PPU runs per CPU cycle
+    region: Region,
+
+    // Master clock cycles left over from the last CPU instruction that weren't
+    // enough to make up a whole dot. Only ever non-zero on PAL, where there
+    // are 3.2 dots per CPU cycle.
+    clock_phase: u64,
+
     // Rendering data
     nametable_byte: u8,
     attrtable_byte: u8,
@@ -40,11 +65,51 @@ pub struct PPU {
     tile_data: u64,
 
     // Sprite data
+    //
+    // There's room for all 64 sprites, but only the first 8 (sprite_count) are
+    // what the hardware would draw. The rest (extra_sprite_count) are only
+    // filled in when the sprite limit has been removed.
     sprite_count: usize,
-    sprite_patterns: [u32; 8],
-    sprite_positions: [u8; 8],
-    sprite_priorities: [u8; 8],
-    sprite_indexes: [usize; 8],
+    extra_sprite_count: usize,
+    sprite_patterns: [u32; 64],
+    sprite_positions: [u8; 64],
+    sprite_priorities: [u8; 64],
+    sprite_indexes: [usize; 64],
+
+    // Whether to only draw 8 sprites per scanline, like the hardware does.
+    // Turning this off gets rid of flickering, but has no effect on anything
+    // that the game itself can see, such as the sprite overflow flag.
+    sprite_limit: bool,
+
+    // A bit for each sprite that was in range of a scanline during this
+    // frame, but was dropped because there were already 8 sprites on it. This
+    // is only used by the sprite viewer.
+    dropped_sprites: u64,
+
+    // Register accesses and mapper IRQs, for the event viewer. `events' is
+    // filled in during the frame, and `frame_events' holds the last complete
+    // frame. Only recorded when record_events is set.
+    record_events: bool,
+    events: Vec<events::Event>,
+    frame_events: Vec<events::Event>,
+
+    // Whether the mapper's IRQ line was raised as of the last dot
+    mapper_irq: bool,
+
+    // The pattern table addresses fetched for each sprite slot during dots
+    // 257-320
+    sprite_fetch_addresses: [u16; 8],
+
+    // Sprite evaluation state. Sprites in range of the next scanline are
+    // copied into secondary OAM, along with their index in primary OAM.
+    secondary_oam: [u8; 32],
+    secondary_indexes: [usize; 8],
+    secondary_addr: usize,
+    sprites_found: usize,
+    eval_state: SpriteEvaluation,
+    eval_n: usize,
+    eval_m: usize,
+    eval_data: u8,
 
     // Odd/even frame state
     odd_frame: bool,
@@ -55,6 +120,15 @@ pub struct PPU {
     nmi_previous: bool,
     nmi_delay: usize,
 
+    // Set when PPUSTATUS is read on the dot just before the vblank flag would
+    // be set, which stops it being set (and the NMI happening) for the frame
+    vbl_suppressed: bool,
+
+    // The number of dots that have been run ahead of the console, during the
+    // current CPU instruction, and what happened during them
+    dots_ahead: u64,
+    caught_up: StepResult,
+
     // PPUSCROLL registers
     t: u16,
     x: u8,
@@ -63,35 +137,210 @@ pub struct PPU {
     // PPUDATA read buffer
     buffered_data: u8,
 
-    // The last written value to any PPU register
-    // For use when reading the PPUSTATUS
-    last_value: u8,
+    // The PPU's I/O data bus acts as a latch, holding the last value written
+    // to (or read from) any PPU register. Reading a write-only register, or
+    // the unused bits of a register, returns whatever is in the latch. Each
+    // bit decays to 0 if it isn't refreshed for a while, so we keep track of
+    // when each bit was last refreshed.
+    io_latch: u8,
+    io_latch_refreshed: [u64; 8],
 
     pixels: Vec<Vec<Color>>,
 }
 
 impl Memory for PPU {
     fn read(&mut self, address: u16) -> u8 {
+        let val = self.read_register(address);
+        self.record_event(EventKind::Read(address % 8 + 0x2000, val));
+        val
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        self.record_event(EventKind::Write(address % 8 + 0x2000, val));
+        self.write_register(address, val);
+    }
+
+    fn save(&self, output: &mut File) -> io::Result<()> {
+        let PPUCtrl(v) = self.ctrl;
+        serde::encode_u8(output, v)?;
+
+        let PPUMask(v) = self.mask;
+        serde::encode_u8(output, v)?;
+
+        let PPUStatus(v) = self.status;
+        serde::encode_u8(output, v)?;
+
+        serde::encode_u8(output, self.oam_addr)?;
+        self.oam.save(output)?;
+        serde::encode_u16(output, self.ppu_addr)?;
+
+        self.data.save(output)?;
+
+        serde::encode_u16(output, self.dot)?;
+        serde::encode_u16(output, self.scanline)?;
+        serde::encode_u64(output, self.cycles)?;
+        serde::encode_u64(output, self.clock_phase)?;
+
+        serde::encode_u8(output, self.nametable_byte)?;
+        serde::encode_u8(output, self.attrtable_byte)?;
+        serde::encode_u8(output, self.low_tile_byte)?;
+        serde::encode_u8(output, self.high_tile_byte)?;
+        serde::encode_u64(output, self.tile_data)?;
+
+        // Including any extra sprites drawn with the sprite limit removed
+        serde::encode_usize(output, self.sprite_count)?;
+        serde::encode_usize(output, self.extra_sprite_count)?;
+        for i in 0 .. self.sprite_count + self.extra_sprite_count {
+            serde::encode_u32(output, self.sprite_patterns[i])?;
+            serde::encode_u8(output, self.sprite_positions[i])?;
+            serde::encode_u8(output, self.sprite_priorities[i])?;
+            serde::encode_usize(output, self.sprite_indexes[i])?;
+        }
+
+        for i in 0 .. 8 {
+            serde::encode_u16(output, self.sprite_fetch_addresses[i])?;
+        }
+
+        output.write_all(&self.secondary_oam)?;
+        for i in 0 .. 8 {
+            serde::encode_usize(output, self.secondary_indexes[i])?;
+        }
+        serde::encode_usize(output, self.secondary_addr)?;
+        serde::encode_usize(output, self.sprites_found)?;
+        serde::encode_u8(output, self.eval_state.to_u8())?;
+        serde::encode_usize(output, self.eval_n)?;
+        serde::encode_usize(output, self.eval_m)?;
+        serde::encode_u8(output, self.eval_data)?;
+
+        serde::encode_u8(output, self.odd_frame as u8)?;
+        serde::encode_u8(output, self.nmi_occurred as u8)?;
+        serde::encode_u8(output, self.nmi_output as u8)?;
+        serde::encode_u8(output, self.nmi_previous as u8)?;
+        serde::encode_usize(output, self.nmi_delay)?;
+        serde::encode_u8(output, self.vbl_suppressed as u8)?;
+
+        serde::encode_u16(output, self.t)?;
+        serde::encode_u8(output, self.x)?;
+        serde::encode_u8(output, self.w as u8)?;
+
+        serde::encode_u8(output, self.buffered_data)?;
+        serde::encode_u8(output, self.io_latch)?;
+        for i in 0 .. 8 {
+            serde::encode_u64(output, self.io_latch_refreshed[i])?;
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut File) -> io::Result<()> {
+        self.ctrl = PPUCtrl(serde::decode_u8(input)?);
+        self.mask = PPUMask(serde::decode_u8(input)?);
+        self.status = PPUStatus(serde::decode_u8(input)?);
+        self.oam_addr = serde::decode_u8(input)?;
+        self.oam.load(input)?;
+        self.ppu_addr = serde::decode_u16(input)?;
+
+        self.data.load(input)?;
+
+        self.dot = serde::decode_u16(input)?;
+        self.scanline = serde::decode_u16(input)?;
+        self.cycles = serde::decode_u64(input)?;
+        self.clock_phase = serde::decode_u64(input)?;
+
+        self.nametable_byte = serde::decode_u8(input)?;
+        self.attrtable_byte = serde::decode_u8(input)?;
+        self.low_tile_byte = serde::decode_u8(input)?;
+        self.high_tile_byte = serde::decode_u8(input)?;
+        self.tile_data = serde::decode_u64(input)?;
+
+        self.sprite_count = serde::decode_usize(input)?;
+        self.extra_sprite_count = serde::decode_usize(input)?;
+        if self.sprite_count > 8 || self.extra_sprite_count > 64 - self.sprite_count {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sprite count"));
+        }
+
+        for i in 0 .. self.sprite_count + self.extra_sprite_count {
+            self.sprite_patterns[i] = serde::decode_u32(input)?;
+            self.sprite_positions[i] = serde::decode_u8(input)?;
+            self.sprite_priorities[i] = serde::decode_u8(input)?;
+            self.sprite_indexes[i] = serde::decode_usize(input)?;
+        }
+
+        for i in 0 .. 8 {
+            self.sprite_fetch_addresses[i] = serde::decode_u16(input)?;
+        }
+
+        input.read_exact(&mut self.secondary_oam)?;
+        for i in 0 .. 8 {
+            self.secondary_indexes[i] = serde::decode_usize(input)?;
+        }
+        self.secondary_addr = serde::decode_usize(input)?;
+        self.sprites_found = serde::decode_usize(input)?;
+        self.eval_state = SpriteEvaluation::from_u8(serde::decode_u8(input)?);
+        self.eval_n = serde::decode_usize(input)?;
+        self.eval_m = serde::decode_usize(input)?;
+        self.eval_data = serde::decode_u8(input)?;
+
+        self.odd_frame = serde::decode_u8(input)? != 0;
+        self.nmi_occurred = serde::decode_u8(input)? != 0;
+        self.nmi_output = serde::decode_u8(input)? != 0;
+        self.nmi_previous = serde::decode_u8(input)? != 0;
+        self.nmi_delay = serde::decode_usize(input)?;
+        self.vbl_suppressed = serde::decode_u8(input)? != 0;
+
+        self.t = serde::decode_u16(input)?;
+        self.x = serde::decode_u8(input)?;
+        self.w = serde::decode_u8(input)? != 0;
+
+        self.buffered_data = serde::decode_u8(input)?;
+        self.io_latch = serde::decode_u8(input)?;
+        for i in 0 .. 8 {
+            self.io_latch_refreshed[i] = serde::decode_u64(input)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PPU {
+    // OAM DMA copies a page of CPU memory into OAM by writing it to OAMDATA a
+    // byte at a time, but it's recorded as a single event.
+    pub fn oam_dma(&mut self, page: u8, data: &[u8; 256]) {
+        self.record_event(EventKind::OAMDMA(page));
+
+        for val in data.iter() {
+            self.write_register(0x2004, *val);
+        }
+    }
+
+    fn read_register(&mut self, address: u16) -> u8 {
         // The PPU registers exist from 0x2000 to 0x2007, the rest of the
         // address space is just a mirror of these first eight bytes.
         let address = address % 8 + 0x2000;
         match address {
-            0x2000 => {
-                let PPUCtrl(n) = self.ctrl;
-                n
-            },
-            0x2001 => {
-                let PPUMask(n) = self.mask;
-                n
-            },
+            0x2000 => self.read_io_latch(), // PPUCTRL is write-only
+            0x2001 => self.read_io_latch(), // PPUMASK is write-only
             0x2002 => {
                 let PPUStatus(mut n) = self.status;
 
-                // Whatever the last value was written to the PPU (to any
-                // register), set the first 5 bits of the PPUSTATUS value to
-                // the first 5 bits of _that_ last value.
+                // Only the top 3 bits of PPUSTATUS are driven by the PPU. The
+                // bottom 5 bits come from the I/O latch.
                 n &= ! 0x1f;
-                n |= self.last_value & 0x1f;
+                n |= self.read_io_latch() & 0x1f;
+
+                // https://wiki.nesdev.com/w/index.php/PPU_frame_timing#VBL_Flag_Timing
+                //
+                // Reading PPUSTATUS one dot before the vblank flag is set reads
+                // it as clear, and stops the flag from being set at all for
+                // this frame, so there's no NMI either.
+                //
+                // Reading it on the same dot that it's set, or on the one
+                // after, reads it as set and clears it, but the NMI has been
+                // suppressed all the same. This falls out of the NMI delay
+                // (see nmi_change) and clearing nmi_occurred here.
+                if self.scanline == self.region.vblank_scanline() && self.dot == 0 {
+                    self.vbl_suppressed = true;
+                }
 
                 if self.nmi_occurred {
                     n |= 1 << 7;
@@ -102,31 +351,49 @@ impl Memory for PPU {
                 // w:                  = 0
                 self.w = false;
 
+                self.refresh_io_latch(n, 0xe0);
+
                 n
             },
-            0x2003 => 0, // OAMADDR is write-only
-            0x2004 => self.oam.read(self.oam_addr as u16),
-            0x2005 => 0, // PPUSCROLL is write-only
-            0x2006 => 0, // PPUADDR is write-only
+            0x2003 => self.read_io_latch(), // OAMADDR is write-only
+            0x2004 => {
+                let n = self.read_oam_data();
+                self.refresh_io_latch(n, 0xff);
+                n
+            },
+            0x2005 => self.read_io_latch(), // PPUSCROLL is write-only
+            0x2006 => self.read_io_latch(), // PPUADDR is write-only
             0x2007 => {
                 let rv;
 
+                self.set_bus_address(self.ppu_addr);
+
                 // Emulate 1-byte delayed read
-                // Palette reads aren't buffered
                 if self.ppu_addr % 0x4000 <= 0x3eff {
                     rv = self.buffered_data;
                     self.buffered_data = self.data.read(self.ppu_addr);
+                    self.refresh_io_latch(rv, 0xff);
                 } else {
-                    // TODO why do we subtract 0x1000 ?
+                    // Palette reads aren't buffered, but the buffer is still
+                    // filled with the nametable byte that's "underneath" the
+                    // palette, since $3F00-$3FFF mirrors $2F00-$2FFF.
                     self.buffered_data = self.data.read(self.ppu_addr - 0x1000);
-                    rv = self.data.read(self.ppu_addr);
+
+                    // Palette entries are only 6 bits wide, so the top two
+                    // bits come from the I/O latch. Greyscale mode also
+                    // applies to palette reads.
+                    let mut palette = self.data.read(self.ppu_addr) & 0x3f;
+                    if self.mask.greyscale() {
+                        palette &= 0x30;
+                    }
+
+                    rv = palette | (self.read_io_latch() & 0xc0);
+                    self.refresh_io_latch(rv, 0x3f);
                 }
 
                 self.ppu_addr = self.ppu_addr.wrapping_add(
                     self.ctrl.vram_addr_increment());
-
-                self.data.mapper.borrow_mut()
-                    .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+                self.set_bus_address(self.ppu_addr);
 
                 rv
             },
@@ -135,8 +402,8 @@ impl Memory for PPU {
         }
     }
 
-    fn write(&mut self, address: u16, val: u8) {
-        self.last_value = val;
+    fn write_register(&mut self, address: u16, val: u8) {
+        self.refresh_io_latch(val, 0xff);
 
         let address = address % 8 + 0x2000;
         match address {
@@ -186,8 +453,7 @@ impl Memory for PPU {
                     self.ppu_addr = self.t;
                     self.w = false;
 
-                    self.data.mapper.borrow_mut()
-                        .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+                    self.set_bus_address(self.ppu_addr);
                 } else {
                     // t: .FEDCBA ........ = d: ..FEDCBA
                     // t: X...... ........ = 0
@@ -198,108 +464,16 @@ impl Memory for PPU {
                 }
             },
             0x2007 => {
+                self.set_bus_address(self.ppu_addr);
                 self.data.write(self.ppu_addr, val);
                 self.ppu_addr = self.ppu_addr.wrapping_add(
                     self.ctrl.vram_addr_increment());
-
-                self.data.mapper.borrow_mut()
-                    .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+                self.set_bus_address(self.ppu_addr);
             },
 
             _ => panic!("bad PPU address 0x{:04X}", address)
         }
     }
-
-    fn save(&self, output: &mut File) -> io::Result<()> {
-        let PPUCtrl(v) = self.ctrl;
-        serde::encode_u8(output, v)?;
-
-        let PPUMask(v) = self.mask;
-        serde::encode_u8(output, v)?;
-
-        let PPUStatus(v) = self.status;
-        serde::encode_u8(output, v)?;
-
-        serde::encode_u8(output, self.oam_addr)?;
-        self.oam.save(output)?;
-        serde::encode_u16(output, self.ppu_addr)?;
-
-        self.data.save(output)?;
-
-        serde::encode_u16(output, self.dot)?;
-        serde::encode_u16(output, self.scanline)?;
-
-        serde::encode_u8(output, self.nametable_byte)?;
-        serde::encode_u8(output, self.attrtable_byte)?;
-        serde::encode_u8(output, self.low_tile_byte)?;
-        serde::encode_u8(output, self.high_tile_byte)?;
-        serde::encode_u64(output, self.tile_data)?;
-
-        serde::encode_usize(output, self.sprite_count)?;
-        for i in 0 .. 7 {
-            serde::encode_u32(output, self.sprite_patterns[i])?;
-            serde::encode_u8(output, self.sprite_positions[i])?;
-            serde::encode_u8(output, self.sprite_priorities[i])?;
-            serde::encode_usize(output, self.sprite_indexes[i])?;
-        }
-
-        serde::encode_u8(output, self.odd_frame as u8)?;
-        serde::encode_u8(output, self.nmi_occurred as u8)?;
-        serde::encode_u8(output, self.nmi_output as u8)?;
-        serde::encode_usize(output, self.nmi_delay)?;
-
-        serde::encode_u16(output, self.t)?;
-        serde::encode_u8(output, self.x)?;
-        serde::encode_u8(output, self.w as u8)?;
-
-        serde::encode_u8(output, self.buffered_data)?;
-        serde::encode_u8(output, self.last_value)?;
-
-        Ok(())
-    }
-
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
-        self.ctrl = PPUCtrl(serde::decode_u8(input)?);
-        self.mask = PPUMask(serde::decode_u8(input)?);
-        self.status = PPUStatus(serde::decode_u8(input)?);
-        self.oam_addr = serde::decode_u8(input)?;
-        self.oam.load(input)?;
-        self.ppu_addr = serde::decode_u16(input)?;
-
-        self.data.load(input)?;
-
-        self.dot = serde::decode_u16(input)?;
-        self.scanline = serde::decode_u16(input)?;
-
-        self.nametable_byte = serde::decode_u8(input)?;
-        self.attrtable_byte = serde::decode_u8(input)?;
-        self.low_tile_byte = serde::decode_u8(input)?;
-        self.high_tile_byte = serde::decode_u8(input)?;
-        self.tile_data = serde::decode_u64(input)?;
-
-        self.sprite_count = serde::decode_usize(input)?;
-        for i in 0 .. 7 {
-            self.sprite_patterns[i] = serde::decode_u32(input)?;
-            self.sprite_positions[i] = serde::decode_u8(input)?;
-            self.sprite_priorities[i] = serde::decode_u8(input)?;
-            self.sprite_indexes[i] = serde::decode_usize(input)?;
-        }
-
-        self.odd_frame = serde::decode_u8(input)? != 0;
-        self.nmi_occurred = serde::decode_u8(input)? != 0;
-        self.nmi_output = serde::decode_u8(input)? != 0;
-        self.nmi_previous = serde::decode_u8(input)? != 0;
-        self.nmi_delay = serde::decode_usize(input)?;
-
-        self.t = serde::decode_u16(input)?;
-        self.x = serde::decode_u8(input)?;
-        self.w = serde::decode_u8(input)? != 0;
-
-        self.buffered_data = serde::decode_u8(input)?;
-        self.last_value = serde::decode_u8(input)?;
-
-        Ok(())
-    }
 }
 
 pub struct StepResult {
@@ -307,8 +481,15 @@ pub struct StepResult {
     pub frame_finished: bool,
 }
 
+impl StepResult {
+    fn merge(&mut self, other: StepResult) {
+        self.trigger_nmi |= other.trigger_nmi;
+        self.frame_finished |= other.frame_finished;
+    }
+}
+
 impl PPU {
-    pub fn new_nes_ppu(cartridge: Rc<RefCell<Box<dyn Mapper>>>) -> Self {
+    pub fn new_nes_ppu(cartridge: Rc<RefCell<Box<dyn Mapper>>>, region: Region) -> Self {
         Self {
             ctrl: PPUCtrl(0),
             mask: PPUMask(0),
@@ -320,6 +501,10 @@ impl PPU {
 
             dot: 0,
             scanline: 0,
+            cycles: 0,
+
+            region: region,
+            clock_phase: 0,
 
             nametable_byte: 0,
             attrtable_byte: 0,
@@ -328,10 +513,29 @@ impl PPU {
             tile_data: 0,
 
             sprite_count: 0,
-            sprite_patterns: [0; 8],
-            sprite_positions: [0; 8],
-            sprite_priorities: [0; 8],
-            sprite_indexes: [0; 8],
+            extra_sprite_count: 0,
+            sprite_patterns: [0; 64],
+            sprite_positions: [0; 64],
+            sprite_priorities: [0; 64],
+            sprite_indexes: [0; 64],
+
+            sprite_limit: true,
+            dropped_sprites: 0,
+
+            record_events: false,
+            events: Vec::new(),
+            frame_events: Vec::new(),
+            mapper_irq: false,
+            sprite_fetch_addresses: [0; 8],
+
+            secondary_oam: [0xff; 32],
+            secondary_indexes: [0; 8],
+            secondary_addr: 0,
+            sprites_found: 0,
+            eval_state: SpriteEvaluation::Done,
+            eval_n: 0,
+            eval_m: 0,
+            eval_data: 0,
 
             odd_frame: false,
 
@@ -340,13 +544,22 @@ impl PPU {
             nmi_previous: false,
             nmi_delay: 0,
 
+            vbl_suppressed: false,
+
+            dots_ahead: 0,
+            caught_up: StepResult{
+                trigger_nmi: false,
+                frame_finished: false,
+            },
+
             t: 0,
             x: 0,
             w: false,
 
             buffered_data: 0,
 
-            last_value: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
 
             pixels: vec![vec![Color::RGB(0, 0, 0); 256]; 240],
         }
@@ -356,14 +569,160 @@ impl PPU {
         &self.pixels
     }
 
+    // Encodes the last frame as a PNG, with every pixel blown up to a `scale'
+    // by `scale' square. This doesn't touch SDL, so works without a window.
+    pub fn screenshot(&self, scale: usize) -> Vec<u8> {
+        let width = 256 * scale;
+        let height = 240 * scale;
+        let mut rgb = Vec::with_capacity(width * height * 3);
+
+        for row in self.pixels.iter() {
+            for _ in 0 .. scale {
+                for color in row.iter() {
+                    for _ in 0 .. scale {
+                        rgb.extend_from_slice(&[color.r, color.g, color.b]);
+                    }
+                }
+            }
+        }
+
+        png::encode_rgb(width, height, &rgb)
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
     fn rendering_enabled(&self) -> bool {
         self.mask.show_background() || self.mask.show_sprites()
     }
 
+    // Sets the bits of the I/O latch that are selected by `mask' to the bits
+    // in `val', refreshing them so they don't decay.
+    fn refresh_io_latch(&mut self, val: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (val & mask);
+
+        for bit in 0 .. 8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refreshed[bit] = self.cycles;
+            }
+        }
+    }
+
+    // Reads the I/O latch, after letting any bits that haven't been refreshed
+    // in a while decay to 0.
+    fn read_io_latch(&mut self) -> u8 {
+        for bit in 0 .. 8 {
+            let age = self.cycles - self.io_latch_refreshed[bit];
+
+            if age >= IO_LATCH_DECAY_CYCLES {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+
+        self.io_latch
+    }
+
+    // Reading OAMDATA while the PPU is rendering exposes whatever is on the
+    // internal OAM bus during sprite evaluation and fetching, rather than the
+    // byte at OAMADDR.
+    fn read_oam_data(&mut self) -> u8 {
+        let visible_line = self.scanline <= 239;
+        let render_line  = visible_line || self.scanline == self.pre_render_scanline();
+
+        if !self.rendering_enabled() || !render_line {
+            return self.oam.read(self.oam_addr as u16);
+        }
+
+        match self.dot {
+            1 ..= 64 if visible_line => 0xff,
+            65 ..= 256 if visible_line => self.eval_data,
+            257 ..= 320 => {
+                let slot = (self.dot as usize - 257) / 8;
+                let byte = ((self.dot as usize - 257) % 8).min(3);
+                self.secondary_oam[slot * 4 + byte]
+            },
+            321 ..= 340 | 0 => self.secondary_oam[0],
+            _ => self.oam.read(self.oam_addr as u16),
+        }
+    }
+
+    // Lets the mapper see an address that the PPU has put on its address bus.
+    // Mappers such as the MMC3 watch A12 on this bus to count scanlines.
+    fn set_bus_address(&mut self, address: u16) {
+        self.data.mapper.borrow_mut()
+            .notify(MapperEvent::PPUBusAddress(address, self.cycles));
+    }
+
+    fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines() - 1
+    }
+
+    // The number of dots that happen in the first `cpu_cycles' cycles of the
+    // current CPU instruction
+    fn dots_for(&self, cpu_cycles: u64) -> u64 {
+        (self.clock_phase + cpu_cycles * self.region.cpu_divider())
+            / self.region.ppu_divider()
+    }
+
+    // Returns the number of dots to run for a CPU instruction that took
+    // `cpu_cycles' cycles, and moves the clock along to the next instruction.
+    pub fn clock(&mut self, cpu_cycles: u64) -> u64 {
+        let dots = self.dots_for(cpu_cycles);
+
+        self.clock_phase = (self.clock_phase + cpu_cycles * self.region.cpu_divider())
+            % self.region.ppu_divider();
+
+        dots
+    }
+
+    // The PPU is normally stepped after the CPU has finished an instruction,
+    // but when the CPU accesses a PPU register, the PPU needs to have caught
+    // up to the exact cycle of the access for things like the vblank flag
+    // timing to be correct. `cpu_cycles' is the number of cycles into the
+    // current CPU instruction that the access happens on.
+    pub fn catch_up(&mut self, cpu_cycles: u64) {
+        let dots = self.dots_for(cpu_cycles);
+
+        while self.dots_ahead < dots {
+            let res = self.step();
+            self.caught_up.merge(res);
+            self.dots_ahead += 1;
+        }
+    }
+
+    // Returns the number of dots already run for the current CPU instruction,
+    // and what happened during them, so the console can run the rest.
+    pub fn take_caught_up(&mut self) -> (u64, StepResult) {
+        let res = StepResult{
+            trigger_nmi: self.caught_up.trigger_nmi,
+            frame_finished: self.caught_up.frame_finished,
+        };
+
+        let dots = self.dots_ahead;
+
+        self.dots_ahead = 0;
+        self.caught_up.trigger_nmi = false;
+        self.caught_up.frame_finished = false;
+
+        (dots, res)
+    }
+
     fn nmi_change(&mut self) {
+        // The NMI fires a couple of dots after the vblank flag is set (or NMIs
+        // are enabled while it's set), so reading PPUSTATUS (or disabling NMIs)
+        // right as the flag is set cancels it.
         let nmi = self.nmi_output && self.nmi_occurred;
         if nmi && !self.nmi_previous {
-            self.nmi_delay = 15;
+            self.nmi_delay = 2;
         }
         self.nmi_previous = nmi;
     }
@@ -433,7 +792,7 @@ impl PPU {
             return None;
         }
 
-        for i in 0 .. self.sprite_count {
+        for i in 0 .. self.sprite_count + self.extra_sprite_count {
             let mut offset = (self.dot as i16 - 1) - self.sprite_positions[i] as i16;
 
             if offset < 0 || offset > 7 {
@@ -453,116 +812,6 @@ impl PPU {
         None
     }
 
-    // Fetches the sprite pattern for a single row of a tile. If you wanted the
-    // pattern tables for every row of a sprite, you would call this with the
-    // `row' parameter being the values from 0 to 7 (inclusive).
-    fn fetch_sprite_pattern(&mut self, i: u16, row: i16) -> u32 {
-        let mut tile = self.oam.read(i * 4 + 1) as u16;
-        let attributes = self.oam.read(i * 4 + 2);
-
-        let address: u16;
-        let mut row = row;
-
-        if self.ctrl.sprite_size() == 8 {
-            if attributes & 0x80 == 0x80 {
-                row = 7 - row;
-            }
-
-            address = self.ctrl.sprite_pattern_table_addr()
-                + (tile * 16)
-                + row as u16;
-        } else {
-            if attributes & 0x80 == 0x80 {
-                row = 15 - row;
-            }
-
-            let table = tile & 1;
-            tile &= 0xfe;
-
-            if row > 7 {
-                tile += 1;
-                row -= 8;
-            }
-
-            address = 0x1000 * table
-                + (tile * 16)
-                + row as u16;
-        }
-
-        let a = ((attributes & 3) << 2) as u32;
-        let mut low_tile_byte = self.data.read(address) as u32;
-        let mut high_tile_byte = self.data.read(address + 8) as u32;
-
-        // Now we need to return a 32-bit unsigned value, representing the 8
-        // pixels of this row of the sprite. This means we have 4 bits per
-        // pixel. The 4 bits are made up of 2 bits of attribute data, and 2 bits
-        // of palette data.
-        //
-        // The attribute data contains the palette offset that this pixel
-        // selects.
-        //
-        // The low tile byte contains one bit of the pattern table data. And the
-        // high tile byte contains the other bit of pattern table data. These
-        // two bits are OR'd together and values 0, 1, and 2 map to an index
-        // within the palette.
-
-        (0 .. 8).fold(0, |acc, _| {
-            let p1;
-            let p2;
-
-            // Flip the sprite vertically, so we read from the other end of the
-            // low and high tile bytes
-            if attributes & 0x40 == 0x40 {
-                p1 = (low_tile_byte & 1) << 0;
-                p2 = (high_tile_byte & 1) << 1;
-                low_tile_byte >>= 1;
-                high_tile_byte >>= 1;
-            } else {
-                p1 = (low_tile_byte & 0x80) >> 7;
-                p2 = (high_tile_byte & 0x80) >> 6;
-                low_tile_byte <<= 1;
-                high_tile_byte <<= 1;
-            }
-
-            (acc << 4) | (a | p1 | p2)
-        } )
-    }
-
-    fn evaluate_sprites(&mut self) {
-        let sz = self.ctrl.sprite_size() as i16;
-
-        let mut count = 0;
-
-        for i in 0 .. 64 {
-            let sprite = i as u16;
-            let y = self.oam.read(sprite * 4 + 0);
-            let a = self.oam.read(sprite * 4 + 2);
-            let x = self.oam.read(sprite * 4 + 3);
-
-            let row: i16 = (self.scanline as i16) - (y as i16);
-
-            if row < 0 || row >= sz {
-                continue
-            }
-
-            if count < 8 {
-                self.sprite_patterns[count] = self.fetch_sprite_pattern(sprite, row);
-                self.sprite_positions[count] = x;
-                self.sprite_priorities[count] = (a >> 5) & 1;
-                self.sprite_indexes[count] = i;
-            }
-
-            count += 1;
-        }
-
-        if count > 8 {
-            count = 8;
-            self.status.set_sprite_overflow();
-        }
-
-        self.sprite_count = count;
-    }
-
     fn render_pixel(&mut self) {
         let x = self.dot - 1;
         let y = self.scanline;
@@ -623,6 +872,7 @@ impl PPU {
         // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
         let addr = 0x2000 | (v & 0x0fff);
         debug!("fetching NT byte from 0x{:04X}", addr);
+        self.set_bus_address(addr);
         self.data.read(addr)
     }
 
@@ -636,6 +886,7 @@ impl PPU {
                  | ((v >> 2) & 0x07);
 
         debug!("fetching AT byte from 0x{:04X}", addr);
+        self.set_bus_address(addr);
         let attrbyte = self.data.read(addr);
 
         let shift = ((v >> 4) & 4) | (v & 2);
@@ -650,6 +901,7 @@ impl PPU {
             + (16 * tile);
 
         debug!("fetching low tile byte from 0x{:04X}", addr);
+        self.set_bus_address(addr);
         self.data.read(addr)
     }
 
@@ -661,6 +913,7 @@ impl PPU {
             + (16 * tile);
 
         debug!("fetching high tile byte from 0x{:04X}", addr + 8);
+        self.set_bus_address(addr + 8);
         self.data.read(addr + 8)
     }
 
@@ -686,6 +939,8 @@ impl PPU {
     }
 
     fn tick(&mut self, res: &mut StepResult) {
+        self.cycles += 1;
+
         if self.nmi_delay > 0 {
             self.nmi_delay -= 1;
 
@@ -695,7 +950,11 @@ impl PPU {
         }
 
         if self.rendering_enabled() {
-            if self.odd_frame && self.scanline == 261 && self.dot == 339 {
+            if self.region.skips_odd_frame_dot()
+                && self.odd_frame
+                && self.scanline == self.pre_render_scanline()
+                && self.dot == 339
+            {
                 self.dot = 0;
                 self.scanline = 0;
                 self.odd_frame = false;
@@ -708,7 +967,7 @@ impl PPU {
             self.dot = 0;
             self.scanline += 1;
 
-            if self.scanline > 261 {
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
                 self.odd_frame = ! self.odd_frame;
             }
@@ -718,11 +977,12 @@ impl PPU {
     pub fn step(&mut self) -> StepResult {
         // http://wiki.nesdev.com/w/index.php/PPU_rendering#Line-by-line_timing
         //
-        // There are a total of 262 scanlines per frame
+        // There are a total of 262 scanlines per frame (312 on PAL and Dendy)
         //   Scanlines 0 to 239 are for display (i.e. the NES is 256 x _240_)
         //   Scanline  240 is a post-render scanline (idle)
-        //   Scanlines 241 to 260 are the vblank interval
-        //   Scanline  261 is a pre-render scanline
+        //   Scanlines 241 to 260 are the vblank interval (241 to 310 on PAL,
+        //             and 291 to 310 on Dendy, after a longer post-render)
+        //   Scanline  261 is a pre-render scanline (311 on PAL and Dendy)
         //
         // There are a total of 341 dots per scanline
         //   The first 256 dots are displayable (i.e. the NES is _256_ x 240)
@@ -734,9 +994,15 @@ impl PPU {
 
         self.tick(&mut res);
 
+        if self.scanline == 0 && self.dot == 0 {
+            self.finish_event_frame();
+        }
+
+        self.watch_mapper_irq();
+
         // All of this logic has been borrowed from github.com/fogleman/nes
 
-        let pre_line          = self.scanline == 261;
+        let pre_line          = self.scanline == self.pre_render_scanline();
         let visible_line      = self.scanline <= 239;
         let render_line       = pre_line || visible_line;
         let _post_render_line = self.scanline == 240;
@@ -800,39 +1066,47 @@ impl PPU {
         }
 
         // sprite logic
-        if self.rendering_enabled() && self.dot == 257 {
+        if self.rendering_enabled() {
             if visible_line {
-                self.evaluate_sprites();
-            } else {
-                self.sprite_count = 0;
+                match self.dot {
+                    1 ..= 64   => self.clear_secondary_oam(),
+                    65 ..= 256 => self.evaluate_sprites(),
+                    _ => { },
+                }
             }
 
+            if render_line && self.dot >= 257 && self.dot <= 320 {
+                // OAMADDR is set to 0 during each of these dots
+                self.oam_addr = 0;
+                self.fetch_sprites(pre_line);
+            }
+
+            // The two unused nametable fetches at the end of the line
+            if render_line && (self.dot == 337 || self.dot == 339) {
+                self.set_bus_address(0x2000 | (self.ppu_addr & 0x0fff));
+            }
         }
 
         // vblank logic
-        if self.scanline == 241 && self.dot == 1 {
+        if self.scanline == self.region.vblank_scanline() && self.dot == 1 {
             debug!("vblank started");
 
-            self.nmi_occurred = true;
-            self.nmi_change();
+            if self.vbl_suppressed {
+                self.vbl_suppressed = false;
+            } else {
+                self.nmi_occurred = true;
+                self.nmi_change();
+            }
 
             res.frame_finished = true;
             return res;
         }
 
-        // TODO
-        // This isn't entirely correct. It should be at dot 260 as far as NesDev
-        // tells me, but changing this to 260 messes up the HUD in Kirby.
-        // I have a feeling that the IRQ counting for MMC3 games still has
-        // problems and needs to be looked at in its entirety again.
-        if (pre_line || visible_line) && self.rendering_enabled() && self.dot == 280 {
-            self.data.mapper.borrow_mut().notify(MapperEvent::HBlank);
-        }
-
         if pre_line && self.dot == 1 {
             debug!("vblank ended");
             self.status.clear_sprite_zero_hit();
             self.status.clear_sprite_overflow();
+            self.dropped_sprites = 0;
 
             self.nmi_occurred = false;
             self.nmi_change();
@@ -841,3 +1115,192 @@ impl PPU {
         return res;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mapper::Mapper0;
+
+    fn new_ppu(region: Region) -> PPU {
+        let mapper: Box<dyn Mapper> = Box::new(Mapper0::new_mapper(vec![0; 0x4000], vec![0; 0x2000], 0));
+        PPU::new_nes_ppu(Rc::new(RefCell::new(mapper)), region)
+    }
+
+    fn set_ppu_addr(ppu: &mut PPU, address: u16) {
+        ppu.write(0x2006, (address >> 8) as u8);
+        ppu.write(0x2006, address as u8);
+    }
+
+    #[test]
+    fn test_status_open_bus() {
+        let mut ppu = new_ppu(Region::NTSC);
+
+        // The bottom 5 bits are whatever was last on the bus...
+        ppu.write(0x2003, 0xff);
+        ppu.status.set_sprite_overflow();
+        assert_eq!(ppu.read(0x2002), 0x3f);
+
+        ppu.write(0x2003, 0xaa);
+        assert_eq!(ppu.read(0x2002), 0x2a);
+
+        // ...and reading it only puts the top 3 bits on the bus
+        assert_eq!(ppu.read(0x2000), 0x2a);
+    }
+
+    #[test]
+    fn test_io_latch_decay() {
+        let mut ppu = new_ppu(Region::NTSC);
+        ppu.write(0x2003, 0xff);
+
+        ppu.cycles += IO_LATCH_DECAY_CYCLES - 1;
+        assert_eq!(ppu.read(0x2005), 0xff);
+
+        // Reading PPUSTATUS refreshes the top 3 bits, but not the rest
+        assert_eq!(ppu.read(0x2002), 0x1f);
+
+        ppu.cycles += 1;
+        assert_eq!(ppu.read(0x2005), 0x00);
+
+        // Writing refreshes every bit
+        ppu.write(0x2001, 0xc3);
+        ppu.cycles += IO_LATCH_DECAY_CYCLES - 1;
+        assert_eq!(ppu.read(0x2000), 0xc3);
+        ppu.cycles += 1;
+        assert_eq!(ppu.read(0x2000), 0x00);
+    }
+
+    #[test]
+    fn test_palette_read_buffer() {
+        let mut ppu = new_ppu(Region::NTSC);
+
+        set_ppu_addr(&mut ppu, 0x2f05);
+        ppu.write(0x2007, 0x42);
+        set_ppu_addr(&mut ppu, 0x3f05);
+        ppu.write(0x2007, 0x3b);
+
+        // Palette reads come straight back, with the top 2 bits from the bus,
+        // and put the nametable byte underneath into the buffer
+        set_ppu_addr(&mut ppu, 0x3f05);
+        assert_eq!(ppu.read(0x2007), 0x3b);
+        assert_eq!(ppu.buffered_data, 0x42);
+
+        set_ppu_addr(&mut ppu, 0x3f05);
+        ppu.write(0x2003, 0xc0);
+        assert_eq!(ppu.read(0x2007), 0xfb);
+
+        // The next nametable read gets what was buffered
+        set_ppu_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.read(0x2007), 0x42);
+    }
+
+    #[test]
+    fn test_oam_data_while_rendering() {
+        let mut ppu = new_ppu(Region::NTSC);
+        ppu.write(0x2003, 0x10);
+        ppu.write(0x2004, 0x99);
+        ppu.write(0x2003, 0x10);
+
+        ppu.scanline = 100;
+        ppu.dot = 10;
+        assert_eq!(ppu.read(0x2004), 0x99);
+
+        // Secondary OAM is being cleared
+        ppu.write(0x2001, 0x18);
+        assert_eq!(ppu.read(0x2004), 0xff);
+
+        // But not during vblank
+        ppu.scanline = 250;
+        assert_eq!(ppu.read(0x2004), 0x99);
+    }
+
+    // Steps the PPU until it gets to `scanline' and `dot', returning whether
+    // an NMI was triggered on the way
+    fn run_to(ppu: &mut PPU, scanline: u16, dot: u16) -> bool {
+        let mut nmi = false;
+
+        while ppu.scanline != scanline || ppu.dot != dot {
+            nmi |= ppu.step().trigger_nmi;
+        }
+
+        nmi
+    }
+
+    // Reads PPUSTATUS at a dot around the start of vblank, returning the
+    // vblank flag and whether there was an NMI for the frame
+    fn vblank_race(region: Region, dot: u16) -> (bool, bool) {
+        let mut ppu = new_ppu(region);
+        ppu.write(0x2000, 0x80);
+
+        let vblank = region.vblank_scanline();
+        let mut nmi = run_to(&mut ppu, vblank, dot);
+
+        let flag = ppu.read(0x2002) & 0x80 != 0;
+        nmi |= run_to(&mut ppu, vblank + 1, 0);
+
+        (flag, nmi)
+    }
+
+    #[test]
+    fn test_vblank_suppression() {
+        for region in [Region::NTSC, Region::PAL, Region::Dendy] {
+            // Reading just before the flag is set stops it being set at all
+            let mut ppu = new_ppu(region);
+            ppu.write(0x2000, 0x80);
+            run_to(&mut ppu, region.vblank_scanline(), 0);
+            assert_eq!(ppu.read(0x2002) & 0x80, 0);
+            run_to(&mut ppu, region.vblank_scanline(), 10);
+            assert_eq!(ppu.read(0x2002) & 0x80, 0);
+
+            assert_eq!(vblank_race(region, 0), (false, false));
+
+            // Reading as it's set sees it, but still stops the NMI
+            assert_eq!(vblank_race(region, 1), (true, false));
+            assert_eq!(vblank_race(region, 2), (true, false));
+
+            // Reading any later is too late to stop the NMI
+            assert_eq!(vblank_race(region, 3), (true, true));
+        }
+    }
+
+    #[test]
+    fn test_vblank_suppression_only_lasts_a_frame() {
+        let mut ppu = new_ppu(Region::NTSC);
+        ppu.write(0x2000, 0x80);
+
+        run_to(&mut ppu, 241, 0);
+        ppu.read(0x2002);
+        assert!(!run_to(&mut ppu, 0, 0));
+        assert!(run_to(&mut ppu, 242, 0));
+    }
+
+    // The number of dots in each of the next few frames, from the start of
+    // one vblank to the next
+    fn frame_lengths(region: Region, mask: u8) -> Vec<u64> {
+        let mut ppu = new_ppu(region);
+        ppu.write(0x2001, mask);
+
+        let vblank = region.vblank_scanline();
+        run_to(&mut ppu, vblank, 1);
+
+        (0 .. 4).map(|_| {
+            let start = ppu.cycles;
+            ppu.step();
+            run_to(&mut ppu, vblank, 1);
+            ppu.cycles - start
+        }).collect()
+    }
+
+    #[test]
+    fn test_frame_lengths() {
+        // NTSC skips a dot on every other frame, but only while rendering
+        assert_eq!(frame_lengths(Region::NTSC, 0x18), [89342, 89341, 89342, 89341]);
+        assert_eq!(frame_lengths(Region::NTSC, 0x00), [89342; 4]);
+
+        // PAL and Dendy have 312 scanlines, and never skip a dot
+        assert_eq!(frame_lengths(Region::PAL, 0x18), [106392; 4]);
+        assert_eq!(frame_lengths(Region::PAL, 0x00), [106392; 4]);
+        assert_eq!(frame_lengths(Region::Dendy, 0x18), [106392; 4]);
+        assert_eq!(frame_lengths(Region::Dendy, 0x00), [106392; 4]);
+    }
+}
@@ -12,6 +12,52 @@ use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 
+// Everything there is to know about a single tile in the nametables, as shown
+// in the nametable viewer
+pub struct NametableTile {
+    // Which of the four logical nametables the tile is in
+    pub nametable: u16,
+
+    // The position of the tile within its nametable
+    pub x: u16,
+    pub y: u16,
+
+    // The address of the tile in the nametable, and the tile number there
+    pub address: u16,
+    pub tile: u8,
+
+    // The address of the attribute byte that covers the tile, the byte
+    // itself, and the palette it selects for this tile
+    pub attribute_address: u16,
+    pub attribute: u8,
+    pub palette: u8,
+
+    // The address of the tile's pattern, in the background pattern table
+    pub pattern_address: u16,
+}
+
+// One of the 64 sprites in OAM, as shown in the sprite viewer
+pub struct Sprite {
+    pub index: usize,
+
+    // The position of the top-left corner of the sprite on the screen. The Y
+    // coordinate in OAM is one less than this, since sprites are evaluated on
+    // the scanline before they're drawn.
+    pub x: u8,
+    pub y: u16,
+
+    pub tile: u8,
+    pub attributes: u8,
+    pub palette: u8,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub behind_background: bool,
+
+    // Whether the sprite was dropped from any scanline this frame, because of
+    // the sprite limit
+    pub dropped: bool,
+}
+
 impl PPU {
     // For debugging purposes. Renders a pattern table at `x' and `y'.
     fn render_pattern_table(&mut self,
@@ -124,4 +170,175 @@ impl PPU {
             }
         }
     }
+
+    // For debugging purposes. Looks up the tile at the pixel position `x' and
+    // `y' of the four logical nametables, laid out in a 512x480 grid.
+    pub fn nametable_tile(&mut self, x: usize, y: usize) -> NametableTile {
+        let nametable = ((y / 240) * 2 + (x / 256)) as u16;
+        let tile_x = ((x % 256) / 8) as u16;
+        let tile_y = ((y % 240) / 8) as u16;
+
+        let base = 0x2000 + nametable * 0x400;
+        let address = base + tile_y * 32 + tile_x;
+        let tile = self.data.read(address);
+
+        // Each attribute byte covers a 4x4 tile area, split into four 2x2 tile
+        // quadrants with two bits each
+        let attribute_address = base + 0x3c0 + (tile_y / 4) * 8 + (tile_x / 4);
+        let attribute = self.data.read(attribute_address);
+        let shift = ((tile_y & 2) << 1) | (tile_x & 2);
+        let palette = (attribute >> shift) & 3;
+
+        let pattern_address = self.ctrl.background_pattern_table_addr()
+            + (tile as u16) * 16;
+
+        NametableTile {
+            nametable: nametable,
+            x: tile_x,
+            y: tile_y,
+            address: address,
+            tile: tile,
+            attribute_address: attribute_address,
+            attribute: attribute,
+            palette: palette,
+            pattern_address: pattern_address,
+        }
+    }
+
+    // For debugging purposes. Draws all four logical nametables, as they're
+    // currently mirrored, into a 512x480 RGB24 buffer, using the current
+    // background pattern table and palettes.
+    pub fn render_nametables(&mut self, buffer: &mut [u8], pitch: usize) {
+        for y in (0 .. 480).step_by(8) {
+            for x in (0 .. 512).step_by(8) {
+                let tile = self.nametable_tile(x, y);
+
+                for row in 0 .. 8 {
+                    let addr = tile.pattern_address + row as u16;
+                    let mut low_byte = self.data.read(addr);
+                    let mut high_byte = self.data.read(addr + 8);
+
+                    for col in 0 .. 8 {
+                        let p1 = (low_byte & 0x80) >> 7;
+                        let p2 = (high_byte & 0x80) >> 6;
+                        low_byte <<= 1;
+                        high_byte <<= 1;
+
+                        // Colour 0 of every palette is the backdrop colour
+                        let palette_index = p1 | p2;
+                        let palette_addr = if palette_index == 0 {
+                            0x3f00
+                        } else {
+                            0x3f00 + (tile.palette as u16) * 4 + palette_index as u16
+                        };
+
+                        let i = self.data.read(palette_addr) as usize;
+                        let color = PALETTE[i % 64];
+
+                        let offset = (y + row) * pitch + (x + col) * 3;
+                        buffer[offset]     = color.r;
+                        buffer[offset + 1] = color.g;
+                        buffer[offset + 2] = color.b;
+                    }
+                }
+            }
+        }
+    }
+
+    // For debugging purposes. The position of the top-left corner of the
+    // screen within the four nametables, as last set by the game through
+    // PPUCTRL, PPUSCROLL and PPUADDR. Games that change the scroll partway
+    // through the frame will only show the last one.
+    pub fn scroll_position(&self) -> (usize, usize) {
+        let coarse_x = (self.t & 0x1f) as usize;
+        let coarse_y = ((self.t >> 5) & 0x1f) as usize;
+        let fine_y = ((self.t >> 12) & 0x07) as usize;
+        let nametable_x = ((self.t >> 10) & 1) as usize;
+        let nametable_y = ((self.t >> 11) & 1) as usize;
+
+        let x = nametable_x * 256 + coarse_x * 8 + self.x as usize;
+        let y = nametable_y * 240 + coarse_y * 8 + fine_y;
+
+        (x, y)
+    }
+
+    // The colours of one of the 8 palettes, 0-3 being the background palettes
+    // and 4-7 the sprite palettes. The first colour is always the backdrop.
+    pub fn palette_colors(&mut self, palette: u8) -> [[u8; 3]; 4] {
+        let mut colors = [[0; 3]; 4];
+
+        for (offset, rgb) in colors.iter_mut().enumerate() {
+            let addr = if offset == 0 {
+                0x3f00
+            } else {
+                0x3f00 + (palette as u16 % 8) * 4 + offset as u16
+            };
+
+            let color = PALETTE[self.data.read(addr) as usize % 64];
+            *rgb = [color.r, color.g, color.b];
+        }
+
+        colors
+    }
+
+    // For debugging purposes. Looks up one of the 64 sprites in OAM.
+    pub fn sprite(&mut self, index: usize) -> Sprite {
+        let base = index as u16 * 4;
+        let y = self.oam.read(base);
+        let tile = self.oam.read(base + 1);
+        let attributes = self.oam.read(base + 2);
+        let x = self.oam.read(base + 3);
+
+        Sprite {
+            index: index,
+            x: x,
+            y: y as u16 + 1,
+            tile: tile,
+            attributes: attributes,
+            palette: attributes & 3,
+            flip_horizontal: attributes & 0x40 == 0x40,
+            flip_vertical: attributes & 0x80 == 0x80,
+            behind_background: attributes & 0x20 == 0x20,
+            dropped: self.dropped_sprites & (1 << index) != 0,
+        }
+    }
+
+    // The height of every sprite, 8 or 16 pixels
+    pub fn sprite_height(&self) -> usize {
+        self.ctrl.sprite_size()
+    }
+
+    // For debugging purposes. Draws one of the 64 sprites into an RGB24
+    // buffer, with its top-left corner at `x' and `y'. Transparent pixels are
+    // left alone.
+    pub fn render_sprite(&mut self, index: usize, buffer: &mut [u8], pitch: usize, x: usize, y: usize) {
+        let sprite = self.sprite(index);
+
+        for row in 0 .. self.sprite_height() {
+            // This takes care of 8x16 sprites and vertical flipping
+            let addr = self.sprite_pattern_address(sprite.tile, sprite.attributes, row as i16);
+            let low_byte = self.data.read(addr);
+            let high_byte = self.data.read(addr + 8);
+
+            for col in 0 .. 8 {
+                let bit = if sprite.flip_horizontal { col } else { 7 - col };
+                let p1 = (low_byte >> bit) & 1;
+                let p2 = ((high_byte >> bit) & 1) << 1;
+
+                let palette_index = p1 | p2;
+                if palette_index == 0 {
+                    continue;
+                }
+
+                let palette_addr = 0x3f10 + (sprite.palette as u16) * 4 + palette_index as u16;
+                let i = self.data.read(palette_addr) as usize;
+                let color = PALETTE[i % 64];
+
+                let offset = (y + row) * pitch + (x + col) * 3;
+                buffer[offset]     = color.r;
+                buffer[offset + 1] = color.g;
+                buffer[offset + 2] = color.b;
+            }
+        }
+    }
 }
@@ -0,0 +1,133 @@
+// Records when things happen during a frame, by scanline and dot, for the
+// event viewer. This makes raster effects, like split scrolling and mid-frame
+// palette or bank changes, much easier to debug.
+//
+// Events are only recorded while the event viewer is open.
+
+use std::fmt;
+
+use crate::ppu::PPU;
+
+#[derive(Clone, Copy)]
+pub enum EventKind {
+    // A CPU read from one of the PPU registers, and the value read
+    Read(u16, u8),
+
+    // A CPU write to one of the PPU registers, and the value written
+    Write(u16, u8),
+
+    // A write to $4014, starting an OAM DMA from the given page
+    OAMDMA(u8),
+
+    // The mapper raising its IRQ line
+    MapperIRQ,
+}
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    pub scanline: u16,
+    pub dot: u16,
+    pub kind: EventKind,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EventKind::Read(address, val) => {
+                write!(f, "read  ${:04X} ({}) = ${:02X}", address, register_name(address), val)
+            },
+            EventKind::Write(address, val) => {
+                write!(f, "write ${:04X} ({}) = ${:02X}", address, register_name(address), val)
+            },
+            EventKind::OAMDMA(page) => {
+                write!(f, "write $4014 (OAMDMA) = ${:02X}", page)
+            },
+            EventKind::MapperIRQ => {
+                write!(f, "mapper IRQ")
+            },
+        }
+    }
+}
+
+// One line of the text export, with columns that are easy to pull apart with
+// a script:
+//
+//     <scanline> <dot> <read|write|irq> <address> <value>
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:3} {:3} ", self.scanline, self.dot)?;
+
+        match self.kind {
+            EventKind::Read(address, val)  => write!(f, "read  {:04X} {:02X}", address, val),
+            EventKind::Write(address, val) => write!(f, "write {:04X} {:02X}", address, val),
+            EventKind::OAMDMA(page)        => write!(f, "write 4014 {:02X}", page),
+            EventKind::MapperIRQ           => write!(f, "irq   -    -"),
+        }
+    }
+}
+
+pub fn register_name(address: u16) -> &'static str {
+    match address {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        _      => "?",
+    }
+}
+
+impl PPU {
+    pub fn set_event_recording(&mut self, enabled: bool) {
+        self.record_events = enabled;
+        self.events.clear();
+        self.frame_events.clear();
+    }
+
+    // The events from the last complete frame
+    pub fn frame_events(&self) -> &Vec<Event> {
+        &self.frame_events
+    }
+
+    pub(super) fn record_event(&mut self, kind: EventKind) {
+        if !self.record_events {
+            return;
+        }
+
+        self.events.push(Event {
+            scanline: self.scanline,
+            dot: self.dot,
+            kind: kind,
+        });
+    }
+
+    // Called at the start of each frame
+    pub(super) fn finish_event_frame(&mut self) {
+        if !self.record_events {
+            return;
+        }
+
+        std::mem::swap(&mut self.events, &mut self.frame_events);
+        self.events.clear();
+    }
+
+    // Called after every dot, to catch the mapper raising its IRQ line, no
+    // matter whether it was because of something on the PPU bus or a CPU
+    // cycle counter
+    pub(super) fn watch_mapper_irq(&mut self) {
+        if !self.record_events {
+            return;
+        }
+
+        let irq = self.data.mapper.borrow().irq_flag();
+
+        if irq && !self.mapper_irq {
+            self.record_event(EventKind::MapperIRQ);
+        }
+
+        self.mapper_irq = irq;
+    }
+}
@@ -28,6 +28,12 @@ impl Memory for PPUData {
         match address {
             0x0000 ..= 0x1fff => self.mapper.borrow_mut().read(address),
             0x2000 ..= 0x3eff => {
+                let (page, offset) = Self::nametable_page(address);
+
+                if let Some(val) = self.mapper.borrow_mut().read_nametable(page, offset) {
+                    return val;
+                }
+
                 let mirrored_address = self.nametable_mirror_address(address);
                 self.nametables[mirrored_address]
             }
@@ -53,6 +59,12 @@ impl Memory for PPUData {
             0x0000 ..= 0x1fff => self.mapper.borrow_mut().write(address, val),
             0x2000 ..= 0x3eff => {
                 debug!("writing 0x{:02X} to nametable 0x{:04X}", val, address);
+                let (page, offset) = Self::nametable_page(address);
+
+                if self.mapper.borrow_mut().write_nametable(page, offset, val) {
+                    return;
+                }
+
                 let mirrored_address = self.nametable_mirror_address(address);
                 self.nametables[mirrored_address] = val;
             },
@@ -109,18 +121,122 @@ impl PPUData {
         }
     }
 
+    // Splits a nametable address into the logical nametable (0 to 3) and the
+    // offset within that nametable. $3000-$3EFF mirrors $2000-$2EFF.
+    fn nametable_page(address: u16) -> (usize, u16) {
+        let address = (address - 0x2000) % 0x1000;
+        let table = address / 0x400;
+        let offset = address % 0x400;
+
+        (table as usize, offset)
+    }
+
     fn nametable_mirror_address(&self, address: u16) -> usize {
         // Calculates the mirrored nametable address (as an index into the
         // nametable array)
         // https://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+        //
+        // The console only has 2KB of VRAM, so every mode except four-screen
+        // only ever touches the first half of the array. Four-screen carts
+        // provide the other 2KB themselves, and get all 4KB.
 
-        let address = (address - 0x2000) % 0x1000;
-        let table = address / 0x400;
-        let offset = address % 0x400;
-        let index = 0x2000
-            + self.mapper.borrow().mirror_mode().coefficients()[table as usize] * 0x400
-            + offset as usize;
+        let (table, offset) = Self::nametable_page(address);
+
+        self.mapper.borrow().mirror_mode().coefficients()[table] * 0x400
+            + offset as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{Mapper0, MirrorMode};
+
+    fn new_ppu_data(mapper: Box<dyn Mapper>) -> PPUData {
+        PPUData::new_ppu_data(Rc::new(RefCell::new(mapper)))
+    }
+
+    // Fills each logical nametable with its own value, and then reads the
+    // first and last byte of each back
+    fn fill_and_read(data: &mut PPUData) -> Vec<u8> {
+        for table in 0 .. 4 {
+            for offset in 0 .. 0x400 {
+                data.write(0x2000 + table * 0x400 + offset, table as u8 + 1);
+            }
+        }
+
+        (0 .. 4)
+            .flat_map(|table| vec![0x2000 + table * 0x400, 0x23ff + table * 0x400])
+            .map(|address| data.read(address))
+            .collect()
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let modes = [
+            (MirrorMode::Horizontal, [2, 2, 2, 2, 4, 4, 4, 4]),
+            (MirrorMode::Vertical,   [3, 3, 4, 4, 3, 3, 4, 4]),
+            (MirrorMode::Single0,    [4; 8]),
+            (MirrorMode::Single1,    [4; 8]),
+        ];
+
+        for (mode, expected) in modes.iter() {
+            let mut mapper = Mapper0::new_mapper(vec![0; 0x4000], vec![0; 0x2000], 0);
+            mapper.mirror_mode = *mode;
+
+            let mut data = new_ppu_data(Box::new(mapper));
+            assert_eq!(fill_and_read(&mut data), expected);
+        }
+    }
+
+    #[test]
+    fn test_four_screen() {
+        let mut mapper = Mapper0::new_mapper(vec![0; 0x4000], vec![0; 0x2000], 0);
+        mapper.mirror_mode = MirrorMode::Four;
+
+        let mut data = new_ppu_data(Box::new(mapper));
+        assert_eq!(fill_and_read(&mut data), [1, 1, 2, 2, 3, 3, 4, 4]);
+
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(data.read(0x3000), 1);
+        assert_eq!(data.read(0x3eff), 4);
+
+        // All 4KB of the array is used
+        assert!(data.nametables[.. 0x400].iter().all(|val| *val == 1));
+        assert!(data.nametables[0xc00 ..].iter().all(|val| *val == 4));
+    }
+
+    // Supplies its own memory for the last nametable, and leaves the rest
+    // to the console
+    struct NametableMapper {
+        page: [u8; 0x400],
+    }
+
+    impl Mapper for NametableMapper {
+        fn mirror_mode(&self) -> &MirrorMode { &MirrorMode::Vertical }
+
+        fn read(&mut self, _address: u16) -> u8 { 0 }
+        fn write(&mut self, _address: u16, _val: u8) { }
+
+        fn read_nametable(&mut self, page: usize, offset: u16) -> Option<u8> {
+            if page == 3 { Some(self.page[offset as usize]) } else { None }
+        }
+
+        fn write_nametable(&mut self, page: usize, offset: u16, val: u8) -> bool {
+            if page == 3 { self.page[offset as usize] = val; }
+            page == 3
+        }
+
+        fn save(&self, _output: &mut File) -> io::Result<()> { Ok(()) }
+        fn load(&mut self, _input: &mut File) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_mapper_nametables() {
+        let mut data = new_ppu_data(Box::new(NametableMapper { page: [0; 0x400] }));
 
-        index % 2048
+        // $2400 isn't overwritten by $2C00, which it would otherwise mirror
+        assert_eq!(fill_and_read(&mut data), [3, 3, 2, 2, 3, 3, 4, 4]);
+        assert!(data.nametables[0x400 .. 0x800].iter().all(|val| *val == 2));
     }
 }
@@ -39,7 +39,6 @@ impl PPUMask {
         (val & 0x02) != 0
     }
 
-    #[allow(dead_code)]
     pub fn greyscale(&self) -> bool {
         let &PPUMask(val) = self;
         (val & 0x01) != 0
@@ -0,0 +1,549 @@
+// Sprite evaluation and sprite pattern fetching, timed to the dot.
+//
+// https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+//
+// On each visible scanline, the PPU works out which sprites will be drawn on
+// the *next* scanline, in three phases:
+//
+//   Dots 1 to 64    Secondary OAM (32 bytes, room for 8 sprites) is cleared to
+//                   $FF, one byte every two dots.
+//   Dots 65 to 256  Primary OAM is scanned for sprites that are in range of
+//                   the scanline, and they're copied into secondary OAM. Odd
+//                   dots read from primary OAM, even dots write to secondary
+//                   OAM.
+//   Dots 257 to 320 The patterns for the sprites in secondary OAM are fetched,
+//                   8 dots per sprite.
+
+use crate::mem::Memory;
+use crate::ppu::PPU;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpriteEvaluation {
+    // Copying in-range sprites into secondary OAM
+    Copying,
+
+    // Secondary OAM is full, so we're only looking for the sprite overflow
+    Overflow,
+
+    // Every sprite has been looked at
+    Done,
+}
+
+impl SpriteEvaluation {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            SpriteEvaluation::Copying  => 0,
+            SpriteEvaluation::Overflow => 1,
+            SpriteEvaluation::Done     => 2,
+        }
+    }
+
+    pub fn from_u8(val: u8) -> Self {
+        match val {
+            0 => SpriteEvaluation::Copying,
+            1 => SpriteEvaluation::Overflow,
+            _ => SpriteEvaluation::Done,
+        }
+    }
+}
+
+impl PPU {
+    // Calculates the address of the low byte of a single row of a sprite's
+    // pattern. The high byte lives 8 bytes after it.
+    pub(super) fn sprite_pattern_address(&self, tile: u8, attributes: u8, row: i16) -> u16 {
+        let mut tile = tile as u16;
+        let mut row = row;
+
+        if self.ctrl.sprite_size() == 8 {
+            if attributes & 0x80 == 0x80 {
+                row = 7 - row;
+            }
+
+            self.ctrl.sprite_pattern_table_addr()
+                + (tile * 16)
+                + row as u16
+        } else {
+            if attributes & 0x80 == 0x80 {
+                row = 15 - row;
+            }
+
+            let table = tile & 1;
+            tile &= 0xfe;
+
+            if row > 7 {
+                tile += 1;
+                row -= 8;
+            }
+
+            0x1000 * table
+                + (tile * 16)
+                + row as u16
+        }
+    }
+
+    // Sprite slots that aren't filled during evaluation still fetch a pattern,
+    // using tile $FF, which is visible to the mapper on the PPU bus.
+    pub(super) fn dummy_sprite_pattern_address(&self) -> u16 {
+        self.sprite_pattern_address(0xff, 0, 0)
+    }
+
+    // Decodes the sprite pattern for a single row of a tile, given the address
+    // of the low byte of that row.
+    pub(super) fn fetch_sprite_pattern(&mut self, address: u16, attributes: u8) -> u32 {
+        let a = ((attributes & 3) << 2) as u32;
+        let mut low_tile_byte = self.data.read(address) as u32;
+        let mut high_tile_byte = self.data.read(address + 8) as u32;
+
+        // Now we need to return a 32-bit unsigned value, representing the 8
+        // pixels of this row of the sprite. This means we have 4 bits per
+        // pixel. The 4 bits are made up of 2 bits of attribute data, and 2 bits
+        // of palette data.
+        //
+        // The attribute data contains the palette offset that this pixel
+        // selects.
+        //
+        // The low tile byte contains one bit of the pattern table data. And the
+        // high tile byte contains the other bit of pattern table data. These
+        // two bits are OR'd together and values 0, 1, and 2 map to an index
+        // within the palette.
+
+        (0 .. 8).fold(0, |acc, _| {
+            let p1;
+            let p2;
+
+            // Flip the sprite vertically, so we read from the other end of the
+            // low and high tile bytes
+            if attributes & 0x40 == 0x40 {
+                p1 = low_tile_byte & 1;
+                p2 = (high_tile_byte & 1) << 1;
+                low_tile_byte >>= 1;
+                high_tile_byte >>= 1;
+            } else {
+                p1 = (low_tile_byte & 0x80) >> 7;
+                p2 = (high_tile_byte & 0x80) >> 6;
+                low_tile_byte <<= 1;
+                high_tile_byte <<= 1;
+            }
+
+            (acc << 4) | (a | p1 | p2)
+        } )
+    }
+
+    // Whether a sprite with the given Y coordinate is drawn on the next
+    // scanline
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let sz = self.ctrl.sprite_size() as i16;
+        let row = (self.scanline as i16) - (y as i16);
+
+        row >= 0 && row < sz
+    }
+
+    // Dots 1 to 64
+    pub(super) fn clear_secondary_oam(&mut self) {
+        if self.dot & 1 == 0 {
+            let i = (self.dot as usize - 1) / 2;
+            self.secondary_oam[i] = 0xff;
+        }
+    }
+
+    fn next_sprite(&mut self) {
+        self.eval_n += 1;
+
+        if self.eval_n == 64 {
+            self.eval_n = 0;
+            self.eval_state = SpriteEvaluation::Done;
+        }
+    }
+
+    // Dots 65 to 256
+    pub(super) fn evaluate_sprites(&mut self) {
+        if self.dot == 65 {
+            self.eval_n = 0;
+            self.eval_m = 0;
+            self.eval_state = SpriteEvaluation::Copying;
+            self.secondary_addr = 0;
+            self.sprites_found = 0;
+        }
+
+        // Odd dots read a byte from primary OAM
+        if self.dot & 1 == 1 {
+            let address = (self.eval_n * 4 + self.eval_m) as u16;
+            self.eval_data = self.oam.read(address);
+            return;
+        }
+
+        // Even dots do something with it
+        match self.eval_state {
+            SpriteEvaluation::Copying => {
+                // The Y coordinate is always copied, but it's only kept (by
+                // moving along to the next byte of secondary OAM) if the
+                // sprite is in range.
+                self.secondary_oam[self.secondary_addr] = self.eval_data;
+
+                if self.eval_m == 0 {
+                    if !self.sprite_in_range(self.eval_data) {
+                        self.next_sprite();
+                        return;
+                    }
+
+                    self.secondary_indexes[self.secondary_addr / 4] = self.eval_n;
+                }
+
+                self.secondary_addr += 1;
+                self.eval_m += 1;
+
+                if self.eval_m == 4 {
+                    self.eval_m = 0;
+                    self.sprites_found += 1;
+                    self.next_sprite();
+
+                    if self.sprites_found == 8 && self.eval_state == SpriteEvaluation::Copying {
+                        self.eval_state = SpriteEvaluation::Overflow;
+                    }
+                }
+            },
+            SpriteEvaluation::Overflow => {
+                // Once secondary OAM is full, the PPU keeps looking for a
+                // ninth sprite to set the overflow flag, but it has a bug.
+                // Instead of only looking at the Y coordinate of each sprite,
+                // it increments both n and m when a sprite isn't in range, so
+                // it ends up treating tile numbers, attributes and X
+                // coordinates as Y coordinates, in a diagonal pattern through
+                // OAM. This results in both false positives and false
+                // negatives.
+                if self.sprite_in_range(self.eval_data) {
+                    self.status.set_sprite_overflow();
+                    self.eval_state = SpriteEvaluation::Done;
+                } else {
+                    self.eval_m = (self.eval_m + 1) % 4;
+                    self.next_sprite();
+                }
+            },
+            SpriteEvaluation::Done => { },
+        }
+    }
+
+    // Dots 257 to 320
+    //
+    // Each of the eight sprite slots takes 8 dots: two garbage nametable
+    // fetches, then the low and high pattern bytes. Slots that weren't filled
+    // during evaluation still go through the motions, fetching tile $FF.
+    pub(super) fn fetch_sprites(&mut self, pre_line: bool) {
+        if self.dot == 257 {
+            // Nothing is evaluated on the pre-render line, so there are no
+            // sprites to draw on the first visible line
+            self.sprite_count = if pre_line { 0 } else { self.sprites_found };
+            self.extra_sprite_count = 0;
+
+            if !pre_line {
+                self.mark_dropped_sprites();
+            }
+        }
+
+        if self.dot == 320 && !pre_line && !self.sprite_limit {
+            self.fetch_extra_sprites();
+        }
+
+        let slot = ((self.dot - 257) / 8) as usize;
+        let base = slot * 4;
+
+        let y          = self.secondary_oam[base];
+        let tile       = self.secondary_oam[base + 1];
+        let attributes = self.secondary_oam[base + 2];
+        let x          = self.secondary_oam[base + 3];
+
+        match (self.dot - 257) % 8 {
+            0 | 2 => {
+                self.set_bus_address(0x2000 | (self.ppu_addr & 0x0fff));
+            },
+            4 => {
+                let address = if slot < self.sprite_count {
+                    let row = (self.scanline as i16) - (y as i16);
+                    self.sprite_pattern_address(tile, attributes, row)
+                } else {
+                    self.dummy_sprite_pattern_address()
+                };
+
+                self.sprite_fetch_addresses[slot] = address;
+                self.set_bus_address(address);
+            },
+            6 => {
+                self.set_bus_address(self.sprite_fetch_addresses[slot] + 8);
+            },
+            7 if slot < self.sprite_count => {
+                let address = self.sprite_fetch_addresses[slot];
+                self.sprite_patterns[slot] = self.fetch_sprite_pattern(address, attributes);
+                self.sprite_positions[slot] = x;
+                self.sprite_priorities[slot] = (attributes >> 5) & 1;
+                self.sprite_indexes[slot] = self.secondary_indexes[slot];
+            },
+            _ => { },
+        }
+    }
+
+    // Remembers which sprites would have been on the next scanline, if it
+    // weren't for the sprite limit, for the sprite viewer
+    fn mark_dropped_sprites(&mut self) {
+        if self.sprites_found < 8 {
+            return;
+        }
+
+        for n in self.secondary_indexes[7] + 1 .. 64 {
+            let y = self.oam.read(n as u16 * 4);
+
+            if self.sprite_in_range(y) {
+                self.dropped_sprites |= 1 << n;
+            }
+        }
+    }
+
+    // When the sprite limit is removed, every sprite after the 8 that the
+    // hardware found is also drawn on the next scanline. This happens behind
+    // the game's back: nothing is put on the PPU bus and none of the flags
+    // the game can see are touched.
+    fn fetch_extra_sprites(&mut self) {
+        if self.sprites_found < 8 {
+            return;
+        }
+
+        let first = self.secondary_indexes[7] + 1;
+
+        for n in first .. 64 {
+            let base = n as u16 * 4;
+            let y = self.oam.read(base);
+
+            if !self.sprite_in_range(y) {
+                continue;
+            }
+
+            let tile       = self.oam.read(base + 1);
+            let attributes = self.oam.read(base + 2);
+            let x          = self.oam.read(base + 3);
+
+            let row = (self.scanline as i16) - (y as i16);
+            let address = self.sprite_pattern_address(tile, attributes, row);
+
+            let slot = self.sprite_count + self.extra_sprite_count;
+            self.sprite_patterns[slot] = self.fetch_sprite_pattern(address, attributes);
+            self.sprite_positions[slot] = x;
+            self.sprite_priorities[slot] = (attributes >> 5) & 1;
+            self.sprite_indexes[slot] = n;
+
+            self.extra_sprite_count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io;
+    use std::rc::Rc;
+
+    use crate::mapper::{Mapper, Mapper0};
+    use crate::mem::Memory;
+    use crate::region::Region;
+
+    const SCANLINE: u16 = 100;
+
+    fn new_ppu(sprites: &[[u8; 4]]) -> PPU {
+        let mapper: Box<dyn Mapper> = Box::new(Mapper0::new_mapper(vec![0; 0x4000], vec![0; 0x2000], 0));
+        let mut ppu = PPU::new_nes_ppu(Rc::new(RefCell::new(mapper)), Region::NTSC);
+
+        // Everything not given is well out of range
+        for address in 0 .. 0x100 {
+            ppu.oam.write(address, 0xf0);
+        }
+
+        for (n, sprite) in sprites.iter().enumerate() {
+            for (m, val) in sprite.iter().enumerate() {
+                ppu.oam.write((n * 4 + m) as u16, *val);
+            }
+        }
+
+        ppu.scanline = SCANLINE;
+        ppu
+    }
+
+    // Runs sprite evaluation and fetching from `first' up to and including
+    // `last'
+    fn run_to(ppu: &mut PPU, first: u16, last: u16) {
+        for dot in first ..= last {
+            ppu.dot = dot;
+
+            match dot {
+                1 ..= 64    => ppu.clear_secondary_oam(),
+                65 ..= 256  => ppu.evaluate_sprites(),
+                257 ..= 320 => ppu.fetch_sprites(false),
+                _           => { },
+            }
+        }
+    }
+
+    // A sprite whose top row is `rows_above' rows above the scanline
+    fn sprite(rows_above: u8, tile: u8, x: u8) -> [u8; 4] {
+        [SCANLINE as u8 - rows_above, tile, 0x01, x]
+    }
+
+    #[test]
+    fn test_clear_secondary_oam() {
+        let mut ppu = new_ppu(&[]);
+        ppu.secondary_oam = [0x12; 32];
+
+        run_to(&mut ppu, 1, 63);
+        assert_eq!(ppu.secondary_oam[31], 0x12);
+
+        run_to(&mut ppu, 64, 64);
+        assert_eq!(ppu.secondary_oam, [0xff; 32]);
+    }
+
+    #[test]
+    fn test_copies_sprites_in_range() {
+        let mut ppu = new_ppu(&[
+            [0xf0, 0, 0, 0],
+            sprite(0, 0x11, 10),
+            [0xf0, 0, 0, 0],
+            sprite(7, 0x22, 20),
+            [SCANLINE as u8 - 8, 0, 0, 0],
+            [SCANLINE as u8 + 1, 0, 0, 0],
+            sprite(3, 0x33, 30),
+        ]);
+
+        run_to(&mut ppu, 1, 256);
+
+        assert_eq!(ppu.sprites_found, 3);
+        assert!(!ppu.status.sprite_overflow());
+        assert_eq!(ppu.secondary_indexes[.. 3], [1, 3, 6]);
+
+        assert_eq!(ppu.secondary_oam[0 .. 12], [
+            SCANLINE as u8,     0x11, 0x01, 10,
+            SCANLINE as u8 - 7, 0x22, 0x01, 20,
+            SCANLINE as u8 - 3, 0x33, 0x01, 30,
+        ]);
+
+        // The Y coordinate of the last sprite looked at is written into the
+        // next free slot, but the rest is left cleared
+        assert_eq!(ppu.secondary_oam[12], 0xf0);
+        assert_eq!(ppu.secondary_oam[13 ..], [0xff; 19]);
+    }
+
+    #[test]
+    fn test_ninth_sprite_sets_overflow() {
+        let sprites: Vec<[u8; 4]> = (0 .. 9).map(|i| sprite(0, i, i)).collect();
+        let mut ppu = new_ppu(&sprites);
+
+        run_to(&mut ppu, 1, 256);
+
+        assert_eq!(ppu.sprites_found, 8);
+        assert!(ppu.status.sprite_overflow());
+        assert_eq!(ppu.secondary_indexes, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(ppu.secondary_oam[28 .. 32], [SCANLINE as u8, 7, 0x01, 7]);
+    }
+
+    #[test]
+    fn test_eight_sprites_dont_overflow() {
+        let sprites: Vec<[u8; 4]> = (0 .. 8).map(|i| sprite(0, i, i)).collect();
+        let mut ppu = new_ppu(&sprites);
+
+        run_to(&mut ppu, 1, 256);
+
+        assert_eq!(ppu.sprites_found, 8);
+        assert!(!ppu.status.sprite_overflow());
+    }
+
+    // After an out of range sprite, the overflow search looks at byte 1 of
+    // the next sprite instead of its Y coordinate, so a ninth sprite in range
+    // is missed...
+    #[test]
+    fn test_overflow_bug_false_negative() {
+        let mut sprites: Vec<[u8; 4]> = (0 .. 8).map(|i| sprite(0, i, i)).collect();
+        sprites.push([0xf0, 0, 0, 0]);
+        sprites.push(sprite(0, 0xf0, 0));
+
+        let mut ppu = new_ppu(&sprites);
+        run_to(&mut ppu, 1, 256);
+
+        assert_eq!(ppu.sprites_found, 8);
+        assert!(!ppu.status.sprite_overflow());
+    }
+
+    // ...and a sprite out of range can set the flag, if its tile number
+    // looks like a Y coordinate in range
+    #[test]
+    fn test_overflow_bug_false_positive() {
+        let mut sprites: Vec<[u8; 4]> = (0 .. 8).map(|i| sprite(0, i, i)).collect();
+        sprites.push([0xf0, 0, 0, 0]);
+        sprites.push([0xf0, SCANLINE as u8, 0, 0]);
+
+        let mut ppu = new_ppu(&sprites);
+        run_to(&mut ppu, 1, 256);
+
+        assert_eq!(ppu.sprites_found, 8);
+        assert!(ppu.status.sprite_overflow());
+    }
+
+    fn save_state(ppu: &PPU, name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("nes-{}-{}.state", name, std::process::id()));
+        ppu.save(&mut File::create(&path).unwrap()).unwrap();
+
+        let state = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        state
+    }
+
+    fn load_state(ppu: &mut PPU, name: &str, state: &[u8]) -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("nes-{}-{}.state", name, std::process::id()));
+        std::fs::write(&path, state).unwrap();
+
+        let result = ppu.load(&mut File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_save_extra_sprites() {
+        let sprites: Vec<[u8; 4]> = (0 .. 10).map(|i| sprite(0, i, i * 10)).collect();
+        let mut ppu = new_ppu(&sprites);
+        ppu.set_sprite_limit(false);
+        run_to(&mut ppu, 1, 320);
+
+        assert_eq!((ppu.sprite_count, ppu.extra_sprite_count), (8, 2));
+
+        let state = save_state(&ppu, "extra-sprites");
+        let mut loaded = new_ppu(&[]);
+        load_state(&mut loaded, "extra-sprites", &state).unwrap();
+
+        assert_eq!((loaded.sprite_count, loaded.extra_sprite_count), (8, 2));
+        assert_eq!(loaded.sprite_positions[.. 10], ppu.sprite_positions[.. 10]);
+        assert_eq!(loaded.sprite_indexes[.. 10], [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_load_invalid_sprite_counts() {
+        let mut ppu = new_ppu(&[sprite(0, 0, 0)]);
+        run_to(&mut ppu, 1, 320);
+
+        // Finds where the counts are, by changing one of them
+        let state = save_state(&ppu, "sprite-counts");
+        ppu.sprite_count = 0;
+        let changed = save_state(&ppu, "sprite-counts");
+        let offset = state.iter().zip(changed.iter()).position(|(a, b)| a != b).unwrap();
+
+        let with_counts = |sprites: usize, extra: usize| {
+            let mut state = state.clone();
+            state[offset .. offset + 8].copy_from_slice(&sprites.to_le_bytes());
+            state[offset + 8 .. offset + 16].copy_from_slice(&extra.to_le_bytes());
+            state
+        };
+
+        let mut loaded = new_ppu(&[]);
+        assert!(load_state(&mut loaded, "sprite-counts", &with_counts(1, 0)).is_ok());
+        assert!(load_state(&mut loaded, "sprite-counts", &with_counts(9, 0)).is_err());
+        assert!(load_state(&mut loaded, "sprite-counts", &with_counts(8, 57)).is_err());
+        assert!(load_state(&mut loaded, "sprite-counts", &with_counts(usize::MAX, 2)).is_err());
+        assert!(load_state(&mut loaded, "sprite-counts", &with_counts(8, usize::MAX)).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+// Records every emulated frame and audio sample to uncompressed files, for
+// capturing gameplay.
+//
+// Recording happens as frames and samples come out of the PPU and APU, rather
+// than from what ends up on the screen and speakers, so recordings are
+// frame-perfect and the audio stays in sync with the video no matter how fast
+// or slow the emulator is running.
+//
+// Video is written as either Y4M, which most video tools (ffmpeg, mpv, etc.)
+// understand, or raw 24-bit RGB frames, one after the other. Audio is written
+// as a stereo 16-bit WAV file.
+//
+// https://wiki.multimedia.cx/index.php/YUV4MPEG2
+//
+// There's also a GIF recorder, without audio, for short clips that can be
+// shown anywhere.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::io;
+
+use crate::gif::GIFWriter;
+use crate::palette::PALETTE;
+
+use sdl2::pixels::Color;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    Y4M,
+    RGB,
+}
+
+impl VideoFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "y4m" => Some(VideoFormat::Y4M),
+            "rgb" => Some(VideoFormat::RGB),
+            _     => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::Y4M => "y4m",
+            VideoFormat::RGB => "rgb",
+        }
+    }
+}
+
+pub struct Recorder {
+    video: BufWriter<File>,
+    audio: WAVWriter,
+
+    format: VideoFormat,
+
+    frames: u64,
+}
+
+// Writes stereo 16-bit samples to a WAV file, filling in the header's sizes
+// when it's finished
+pub struct WAVWriter {
+    w: BufWriter<File>,
+    sample_rate: u32,
+
+    // How many samples have been written, to fill in the header with
+    samples: u32,
+}
+
+// Converts a colour to Y'CbCr, using the BT.601 coefficients and studio
+// swing, which is what Y4M files are assumed to contain
+fn to_ycbcr(color: &Color) -> (u8, u8, u8) {
+    let r = color.r as f32;
+    let g = color.g as f32;
+    let b = color.b as f32;
+
+    let y  =  16.0 + ( 65.738 * r + 129.057 * g +  25.064 * b) / 256.0;
+    let cb = 128.0 + (-37.945 * r -  74.494 * g + 112.439 * b) / 256.0;
+    let cr = 128.0 + (112.439 * r -  94.154 * g -  18.285 * b) / 256.0;
+
+    (y.round() as u8, cb.round() as u8, cr.round() as u8)
+}
+
+fn write_wav_header<W: Write>(w: &mut W, sample_rate: u32, samples: u32) -> io::Result<()> {
+    let data_size = samples * 4;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_size).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?;               // PCM
+    w.write_all(&2u16.to_le_bytes())?;               // stereo
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * 4).to_le_bytes())?;  // bytes per second
+    w.write_all(&4u16.to_le_bytes())?;               // bytes per sample
+    w.write_all(&16u16.to_le_bytes())?;              // bits per sample
+
+    w.write_all(b"data")?;
+    w.write_all(&data_size.to_le_bytes())?;
+
+    Ok(())
+}
+
+impl WAVWriter {
+    pub fn new_wav_writer(path: &str, sample_rate: u32) -> io::Result<Self> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        // The sizes in the header are filled in when the file is finished
+        write_wav_header(&mut w, sample_rate, 0)?;
+
+        Ok(Self {
+            w: w,
+            sample_rate: sample_rate,
+            samples: 0,
+        })
+    }
+
+    pub fn write_sample(&mut self, sample: (f32, f32)) -> io::Result<()> {
+        let (left, right) = sample;
+
+        for channel in [left, right].iter() {
+            let value = (channel.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.w.write_all(&value.to_le_bytes())?;
+        }
+
+        self.samples += 1;
+        Ok(())
+    }
+
+    // Fills in the header and flushes everything to disk
+    pub fn finish(mut self) -> io::Result<()> {
+        self.w.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.w, self.sample_rate, self.samples)?;
+        self.w.flush()
+    }
+}
+
+impl Recorder {
+    pub fn new_recorder(video_path: &str, audio_path: &str, format: VideoFormat, frame_rate: f64, sample_rate: u32) -> io::Result<Self> {
+        let mut video = BufWriter::new(File::create(video_path)?);
+        let audio = WAVWriter::new_wav_writer(audio_path, sample_rate)?;
+
+        if format == VideoFormat::Y4M {
+            // The frame rate is a fraction, and 1/1000th of a frame per
+            // second is close enough to keep in sync for hours
+            let frame_rate = (frame_rate * 1000.0).round() as u64;
+
+            writeln!(video, "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444", WIDTH, HEIGHT, frame_rate)?;
+        }
+
+        Ok(Self {
+            video: video,
+            audio: audio,
+            format: format,
+            frames: 0,
+        })
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn record_frame(&mut self, pixels: &[Vec<Color>]) -> io::Result<()> {
+        match self.format {
+            VideoFormat::Y4M => {
+                let mut planes = vec![0; WIDTH * HEIGHT * 3];
+
+                for (i, color) in pixels.iter().flat_map(|row| row.iter()).enumerate() {
+                    let (y, cb, cr) = to_ycbcr(color);
+
+                    planes[i] = y;
+                    planes[WIDTH * HEIGHT + i] = cb;
+                    planes[2 * WIDTH * HEIGHT + i] = cr;
+                }
+
+                self.video.write_all(b"FRAME\n")?;
+                self.video.write_all(&planes)?;
+            },
+            VideoFormat::RGB => {
+                let mut rgb = Vec::with_capacity(WIDTH * HEIGHT * 3);
+
+                for color in pixels.iter().flat_map(|row| row.iter()) {
+                    rgb.extend_from_slice(&[color.r, color.g, color.b]);
+                }
+
+                self.video.write_all(&rgb)?;
+            },
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn record_sample(&mut self, sample: (f32, f32)) -> io::Result<()> {
+        self.audio.write_sample(sample)
+    }
+
+    // Fills in the WAV header and flushes everything to disk
+    pub fn finish(mut self) -> io::Result<()> {
+        self.video.flush()?;
+        self.audio.finish()
+    }
+}
+
+// Browsers and most viewers show frames with a delay of less than 2
+// hundredths of a second for much longer, which slows the whole animation
+// down, so frames are never shown for less than this
+const MIN_GIF_DELAY: u64 = 2;
+
+pub struct GIFRecorder {
+    gif: GIFWriter<BufWriter<File>>,
+
+    // Maps each of the NES's colours back to its index in the palette
+    colors: HashMap<(u8, u8, u8), u8>,
+
+    frame_rate: f64,
+
+    // Only every `skip'th frame is recorded
+    skip: u64,
+
+    // Every frame seen since recording started, including skipped ones
+    frames_seen: u64,
+
+    frames: u64,
+
+    // The total of the delays written so far, in hundredths of a second
+    elapsed: u64,
+}
+
+impl GIFRecorder {
+    // Starts a GIF, recording as many of the frames at `frame_rate' as
+    // MIN_GIF_DELAY allows, or fewer of them to get as close as possible to
+    // `fps'
+    pub fn new_gif_recorder(path: &str, frame_rate: f64, fps: Option<f64>) -> io::Result<Self> {
+        let palette: Vec<[u8; 3]> = PALETTE.iter().map(|c| [c.r, c.g, c.b]).collect();
+        let gif = GIFWriter::new_gif_writer(BufWriter::new(File::create(path)?),
+                                            WIDTH as u16,
+                                            HEIGHT as u16,
+                                            &palette)?;
+
+        // The palette has a few duplicates (there's more than one black), so
+        // the first one wins
+        let mut colors = HashMap::new();
+        for (i, c) in PALETTE.iter().enumerate().rev() {
+            colors.insert((c.r, c.g, c.b), i as u8);
+        }
+
+        // PAL runs at a hair over 50 frames per second, which is close
+        // enough to record every frame of
+        let min_skip = (frame_rate * MIN_GIF_DELAY as f64 / 100.0 - 0.01).ceil().max(1.0) as u64;
+
+        let skip = match fps {
+            Some(fps) => ((frame_rate / fps).round() as u64).max(min_skip),
+            None      => min_skip,
+        };
+
+        Ok(Self {
+            gif: gif,
+            colors: colors,
+            frame_rate: frame_rate,
+            skip: skip,
+            frames_seen: 0,
+            frames: 0,
+            elapsed: 0,
+        })
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // GIF delays are in hundredths of a second, which frames don't line up
+    // with, so each frame's delay is rounded so that the total time is right,
+    // instead of the rounding errors adding up. This is how far into the
+    // animation the given frame ends.
+    fn centiseconds(&self, frames: u64) -> u64 {
+        (frames as f64 * self.skip as f64 * 100.0 / self.frame_rate).round() as u64
+    }
+
+    pub fn record_frame(&mut self, pixels: &[Vec<Color>]) -> io::Result<()> {
+        let skipped = !self.frames_seen.is_multiple_of(self.skip);
+        self.frames_seen += 1;
+
+        if skipped {
+            return Ok(());
+        }
+
+        let indices: Vec<u8> = pixels.iter()
+            .flat_map(|row| row.iter())
+            .map(|c| *self.colors.get(&(c.r, c.g, c.b)).unwrap_or(&0))
+            .collect();
+
+        // Frames that would be too short are stretched, and the ones after
+        // them catch up
+        let delay = self.centiseconds(self.frames + 1)
+            .saturating_sub(self.elapsed)
+            .max(MIN_GIF_DELAY);
+
+        self.gif.write_frame(&indices, delay as u16)?;
+        self.frames += 1;
+        self.elapsed += delay;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.gif.finish()
+    }
+}
@@ -0,0 +1,177 @@
+// https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+//
+// The console was sold in a few different versions, which all run from a
+// master clock, but divide it down differently for the CPU and PPU, and have
+// different numbers of scanlines per frame.
+//
+//                       NTSC          PAL           Dendy
+//   Master clock        21.477272MHz  26.601712MHz  26.601712MHz
+//   CPU divider         12            16            15
+//   PPU divider         4             5             5
+//   PPU dots per CPU    3             3.2           3
+//   Scanlines           262           312           312
+//   Vblank scanlines    20            70            20
+//   Frame rate          60.0988Hz     50.0070Hz     50.0070Hz
+//
+// The Dendy is a Famiclone that was popular in Russia. It has PAL-like timing,
+// but its vblank starts 50 scanlines after the end of the picture, and it
+// runs the APU just like an NTSC console, so that NTSC games sound (and play)
+// about right.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    Dendy,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = match *self {
+            Region::NTSC  => "NTSC",
+            Region::PAL   => "PAL",
+            Region::Dendy => "Dendy",
+        };
+
+        write!(f, "{}", v)
+    }
+}
+
+// The noise channel's timer periods, in CPU cycles
+const NTSC_NOISE_TABLE: [u16; 16] = [
+    0x004, 0x008, 0x010, 0x020,
+    0x040, 0x060, 0x080, 0x0a0,
+    0x0ca, 0x0fe, 0x17c, 0x1fc,
+    0x2fa, 0x3f8, 0x7f2, 0xfe4,
+];
+
+const PAL_NOISE_TABLE: [u16; 16] = [
+    0x004, 0x008, 0x00e, 0x01e,
+    0x03c, 0x058, 0x076, 0x094,
+    0x0bc, 0x0ec, 0x162, 0x1d8,
+    0x2c4, 0x3b0, 0x762, 0xec2,
+];
+
+// The CPU cycles, counted from the start of the sequence, that the frame
+// counter clocks the envelopes and length counters on. The first four are
+// the steps of both sequences, and the last is the fifth step of the 5-step
+// sequence.
+//
+// https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+const NTSC_FRAME_COUNTER_STEPS: [u64; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_COUNTER_STEPS: [u64; 5] = [8313, 16627, 24939, 33253, 41565];
+
+// The DMC's timer periods, in CPU cycles
+const NTSC_DMC_TABLE: [u16; 16] = [
+    0x01AC, 0x017C, 0x0154, 0x0140,
+    0x011E, 0x00FE, 0x00E2, 0x00D6,
+    0x00BE, 0x00A0, 0x008E, 0x0080,
+    0x006A, 0x0054, 0x0048, 0x0036
+];
+
+const PAL_DMC_TABLE: [u16; 16] = [
+    0x018E, 0x0162, 0x013C, 0x012A,
+    0x0114, 0x00EC, 0x00D2, 0x00C6,
+    0x00B0, 0x0094, 0x0084, 0x0076,
+    0x0062, 0x004E, 0x0042, 0x0032
+];
+
+impl Region {
+    // Parses a region name, as given in the NES_REGION environment variable
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ntsc"  => Some(Region::NTSC),
+            "pal"   => Some(Region::PAL),
+            "dendy" => Some(Region::Dendy),
+            _       => None,
+        }
+    }
+
+    fn master_clock_rate(self) -> f64 {
+        match self {
+            Region::NTSC               => 21_477_272.0,
+            Region::PAL | Region::Dendy => 26_601_712.0,
+        }
+    }
+
+    // How many master clock cycles make up one CPU cycle
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::NTSC  => 12,
+            Region::PAL   => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    // How many master clock cycles make up one PPU dot
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::NTSC               => 4,
+            Region::PAL | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_rate(self) -> f64 {
+        self.master_clock_rate() / self.cpu_divider() as f64
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        let ppu_clock_rate = self.master_clock_rate() / self.ppu_divider() as f64;
+
+        // NTSC skips a dot on every other frame, so a frame is half a dot
+        // shorter on average.
+        let dots = match self {
+            Region::NTSC => 341.0 * 262.0 - 0.5,
+            _            => 341.0 * self.scanlines() as f64,
+        };
+
+        ppu_clock_rate / dots
+    }
+
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            _            => 312,
+        }
+    }
+
+    // The scanline that the vblank flag is set on
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy              => 291,
+        }
+    }
+
+    // Only the NTSC PPU skips the first dot of the pre-render scanline on odd
+    // frames.
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::NTSC
+    }
+
+    pub fn noise_table(self) -> &'static [u16; 16] {
+        match self {
+            Region::PAL => &PAL_NOISE_TABLE,
+            _           => &NTSC_NOISE_TABLE,
+        }
+    }
+
+    pub fn dmc_table(self) -> &'static [u16; 16] {
+        match self {
+            Region::PAL => &PAL_DMC_TABLE,
+            _           => &NTSC_DMC_TABLE,
+        }
+    }
+
+    // When the frame counter steps. NTSC steps at roughly 240Hz and PAL at
+    // 200Hz, and the Dendy uses the NTSC timing, counted in its own CPU
+    // cycles.
+    pub fn frame_counter_steps(self) -> &'static [u64; 5] {
+        match self {
+            Region::PAL => &PAL_FRAME_COUNTER_STEPS,
+            _           => &NTSC_FRAME_COUNTER_STEPS,
+        }
+    }
+}
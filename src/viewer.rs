@@ -0,0 +1,14 @@
+// Debug windows, which can be opened alongside the main window to see what's
+// going on inside the PPU and APU.
+
+mod apu;
+mod events;
+mod font;
+mod nametables;
+mod sprites;
+
+pub use crate::viewer::apu::APUViewer;
+pub use crate::viewer::font::draw_text;
+pub use crate::viewer::events::EventViewer;
+pub use crate::viewer::nametables::NametableViewer;
+pub use crate::viewer::sprites::SpriteViewer;
@@ -0,0 +1,152 @@
+// A debug window showing what each of the APU's channels did during the last
+// frame, one row per channel.
+//
+// On the left of each row is an oscilloscope of the channel's output, with
+// every CPU cycle of the frame squeezed into the width of the window, so each
+// column shows the lowest and highest level the channel output during that
+// slice of the frame. On the right is the channel's current state: its
+// period, volume and envelope, duty, length counter, sweep and so on.
+//
+// Muted channels are drawn dimmed.
+
+use crate::apu::{APU, CHANNELS};
+use crate::viewer::font;
+
+use sdl2::VideoSubsystem;
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const SCOPE_WIDTH: u32 = 512;
+const TABLE_WIDTH: u32 = 256;
+const ROW_HEIGHT: u32 = 80;
+
+// Space around the scope and the text in each row
+const MARGIN: u32 = 4;
+
+const TEXT_SCALE: u32 = 2;
+const LINE_HEIGHT: u32 = (font::GLYPH_HEIGHT + 1) * TEXT_SCALE;
+
+const TITLE: &str = "APU";
+
+const BACKGROUND: Color = Color { r: 20, g: 20, b: 20, a: 0xff };
+const GRID: Color = Color { r: 50, g: 50, b: 50, a: 0xff };
+const TEXT: Color = Color { r: 200, g: 200, b: 200, a: 0xff };
+
+// The colour of each channel's scope, in the same order as CHANNELS
+const COLORS: [Color; 5] = [
+    Color { r: 255, g: 80,  b: 80,  a: 0xff },
+    Color { r: 255, g: 160, b: 60,  a: 0xff },
+    Color { r: 80,  g: 200, b: 255, a: 0xff },
+    Color { r: 200, g: 200, b: 200, a: 0xff },
+    Color { r: 120, g: 255, b: 120, a: 0xff },
+];
+
+// The highest level each channel can output. The DMC's output is 7 bits, and
+// the others are 4.
+const MAX_LEVELS: [u8; 5] = [15, 15, 15, 15, 127];
+
+pub struct APUViewer {
+    canvas: Canvas<Window>,
+}
+
+fn dim(color: Color) -> Color {
+    Color::RGB(color.r / 3, color.g / 3, color.b / 3)
+}
+
+impl APUViewer {
+    pub fn new_apu_viewer(video_subsystem: &VideoSubsystem) -> Self {
+        let width = SCOPE_WIDTH + TABLE_WIDTH;
+        let height = ROW_HEIGHT * CHANNELS.len() as u32;
+
+        let window = video_subsystem.window(TITLE, width, height)
+            .build()
+            .unwrap();
+
+        let canvas = window.into_canvas()
+            .build()
+            .unwrap();
+
+        Self {
+            canvas: canvas,
+        }
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    fn render_scope(&mut self, row: u32, levels: &[u8], max_level: u8, color: Color) {
+        let top = (row * ROW_HEIGHT + MARGIN) as i32;
+        let height = ROW_HEIGHT - 2 * MARGIN;
+
+        // The middle of the channel's range, to make it easier to see where
+        // the waves sit
+        self.canvas.set_draw_color(GRID);
+        self.canvas.draw_line(Point::new(0, top + height as i32 / 2),
+                              Point::new(SCOPE_WIDTH as i32, top + height as i32 / 2)).unwrap();
+
+        if levels.is_empty() {
+            return;
+        }
+
+        let y = |level: u8| {
+            top + ((max_level - level.min(max_level)) as u32 * (height - 1) / max_level as u32) as i32
+        };
+
+        self.canvas.set_draw_color(color);
+
+        for x in 0 .. SCOPE_WIDTH as usize {
+            let start = x * levels.len() / SCOPE_WIDTH as usize;
+            let end = ((x + 1) * levels.len() / SCOPE_WIDTH as usize).max(start + 1);
+            let slice = &levels[start .. end.min(levels.len())];
+
+            let low = *slice.iter().min().unwrap();
+            let high = *slice.iter().max().unwrap();
+
+            self.canvas.draw_line(Point::new(x as i32, y(high)),
+                                  Point::new(x as i32, y(low))).unwrap();
+        }
+    }
+
+    fn render_table(&mut self, row: u32, heading: &str, lines: &[String], color: Color) {
+        let left = (SCOPE_WIDTH + MARGIN) as i32;
+        let mut y = (row * ROW_HEIGHT + MARGIN) as i32;
+
+        self.canvas.set_draw_color(color);
+        font::draw_text(&mut self.canvas, heading, left, y, TEXT_SCALE);
+
+        self.canvas.set_draw_color(TEXT);
+        for line in lines.iter() {
+            y += LINE_HEIGHT as i32;
+            font::draw_text(&mut self.canvas, line, left, y, TEXT_SCALE);
+        }
+    }
+
+    pub fn render(&mut self, apu: &mut APU) {
+        let scope = apu.take_scope();
+        let registers = apu.channel_registers();
+
+        self.canvas.set_draw_color(BACKGROUND);
+        self.canvas.clear();
+
+        for (i, channel) in CHANNELS.iter().enumerate() {
+            let row = i as u32;
+            let muted = apu.mixer().muted(*channel);
+            let color = if muted { dim(COLORS[i]) } else { COLORS[i] };
+
+            // Rows are separated by a line, and the scope from the table
+            self.canvas.set_draw_color(GRID);
+            self.canvas.fill_rect(Rect::new(0, (row * ROW_HEIGHT) as i32, SCOPE_WIDTH + TABLE_WIDTH, 1)).unwrap();
+            self.canvas.fill_rect(Rect::new(SCOPE_WIDTH as i32, (row * ROW_HEIGHT) as i32, 1, ROW_HEIGHT)).unwrap();
+
+            self.render_scope(row, &scope[i], MAX_LEVELS[i], color);
+
+            let heading = if muted { format!("{} (muted)", channel) } else { channel.to_string() };
+            self.render_table(row, &heading, &registers[i], color);
+        }
+
+        self.canvas.present();
+    }
+}
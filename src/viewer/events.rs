@@ -0,0 +1,169 @@
+// A debug window plotting everything recorded by the PPU during the last
+// frame on a grid of scanlines and dots, one pixel block per dot.
+//
+// The grid is shaded to show where the visible picture, horizontal blanking
+// and vertical blanking are, and each event is coloured by what it was:
+//
+//   PPUCTRL    red          PPUSCROLL  cyan
+//   PPUMASK    orange       PPUADDR    blue
+//   PPUSTATUS  yellow       PPUDATA    magenta
+//   OAMADDR/   green        OAM DMA    white
+//   OAMDATA                 Mapper IRQ pink
+//
+// Reads are drawn in a darker shade than writes. Hovering over an event shows
+// what it was in the window's title bar.
+
+use crate::ppu::{Event, EventKind, PPU};
+use crate::region::Region;
+
+use sdl2::VideoSubsystem;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const DOTS: u32 = 341;
+const SCALE: u32 = 2;
+
+const TITLE: &str = "events";
+
+// How far away from an event, in dots, the mouse can be for it to be picked
+const HOVER_DISTANCE: i32 = 3;
+
+pub struct EventViewer {
+    canvas: Canvas<Window>,
+    region: Region,
+
+    // The dot and scanline under the mouse
+    cursor: Option<(i32, i32)>,
+}
+
+fn event_color(kind: &EventKind) -> Color {
+    let (address, read) = match *kind {
+        EventKind::Read(address, _)  => (address, true),
+        EventKind::Write(address, _) => (address, false),
+        EventKind::OAMDMA(_)         => return Color::RGB(255, 255, 255),
+        EventKind::MapperIRQ         => return Color::RGB(255, 128, 192),
+    };
+
+    let color = match address {
+        0x2000          => Color::RGB(255, 0, 0),
+        0x2001          => Color::RGB(255, 128, 0),
+        0x2002          => Color::RGB(255, 255, 0),
+        0x2003 | 0x2004 => Color::RGB(0, 255, 0),
+        0x2005          => Color::RGB(0, 255, 255),
+        0x2006          => Color::RGB(64, 64, 255),
+        _               => Color::RGB(255, 0, 255),
+    };
+
+    if read {
+        Color::RGB(color.r / 2, color.g / 2, color.b / 2)
+    } else {
+        color
+    }
+}
+
+impl EventViewer {
+    pub fn new_event_viewer(video_subsystem: &VideoSubsystem, region: Region) -> Self {
+        let width = DOTS * SCALE;
+        let height = region.scanlines() as u32 * SCALE;
+
+        let window = video_subsystem.window(TITLE, width, height)
+            .build()
+            .unwrap();
+
+        let canvas = window.into_canvas()
+            .build()
+            .unwrap();
+
+        Self {
+            canvas: canvas,
+            region: region,
+            cursor: None,
+        }
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn mouse_moved(&mut self, x: i32, y: i32) {
+        self.cursor = Some((x / SCALE as i32, y / SCALE as i32));
+    }
+
+    pub fn mouse_left(&mut self) {
+        self.cursor = None;
+        self.canvas.window_mut().set_title(TITLE).unwrap();
+    }
+
+    fn fill_area(&mut self, color: Color, dot: u32, scanline: u32, dots: u32, scanlines: u32) {
+        let rect = Rect::new((dot * SCALE) as i32,
+                             (scanline * SCALE) as i32,
+                             dots * SCALE,
+                             scanlines * SCALE);
+
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rect(rect).unwrap();
+    }
+
+    // The event closest to the mouse, if there is one close enough
+    fn hovered_event(&self, events: &[Event]) -> Option<Event> {
+        let (x, y) = self.cursor?;
+
+        events.iter()
+            .map(|e| {
+                let dx = (e.dot as i32 - x).abs();
+                let dy = (e.scanline as i32 - y).abs();
+                (dx.max(dy), e)
+            })
+            .filter(|(distance, _)| *distance <= HOVER_DISTANCE)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, e)| *e)
+    }
+
+    pub fn render(&mut self, ppu: &mut PPU) {
+        let scanlines = self.region.scanlines() as u32;
+        let vblank = self.region.vblank_scanline() as u32;
+
+        // Blanking, then the visible picture, then vertical blanking, then the
+        // pre-render scanline
+        self.fill_area(Color::RGB(30, 30, 30), 0, 0, DOTS, scanlines);
+        self.fill_area(Color::RGB(60, 60, 60), 1, 0, 256, 240);
+        self.fill_area(Color::RGB(10, 10, 10), 0, vblank, DOTS, scanlines - 1 - vblank);
+        self.fill_area(Color::RGB(45, 45, 45), 0, scanlines - 1, DOTS, 1);
+
+        let events = ppu.frame_events();
+
+        for event in events.iter() {
+            let rect = Rect::new((event.dot as u32 * SCALE) as i32,
+                                 (event.scanline as u32 * SCALE) as i32,
+                                 2 * SCALE,
+                                 2 * SCALE);
+
+            self.canvas.set_draw_color(event_color(&event.kind));
+            self.canvas.fill_rect(rect).unwrap();
+        }
+
+        if let Some((x, y)) = self.cursor {
+            let title = match self.hovered_event(events) {
+                Some(event) => {
+                    let rect = Rect::new((event.dot as u32 * SCALE) as i32 - 2,
+                                         (event.scanline as u32 * SCALE) as i32 - 2,
+                                         2 * SCALE + 4,
+                                         2 * SCALE + 4);
+
+                    self.canvas.set_draw_color(Color::RGB(255, 255, 255));
+                    self.canvas.draw_rect(rect).unwrap();
+
+                    format!("{} - scanline {}, dot {}: {}",
+                            TITLE, event.scanline, event.dot, event.kind)
+                },
+                None => format!("{} - scanline {}, dot {}", TITLE, y, x),
+            };
+
+            self.canvas.window_mut().set_title(&title).unwrap();
+        }
+
+        self.canvas.present();
+    }
+}
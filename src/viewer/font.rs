@@ -0,0 +1,89 @@
+// A tiny 3x5 pixel font, for drawing text in the debug windows without
+// needing a font file. Only upper case letters are drawn, so lower case
+// letters are drawn as upper case, and anything without a glyph is left as a
+// space.
+
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+
+// Each row is 3 bits, with the most significant being the leftmost pixel
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [7, 1, 7, 4, 7],
+        '3' => [7, 1, 3, 1, 7],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 7, 1, 7],
+        '6' => [7, 4, 7, 5, 7],
+        '7' => [7, 1, 1, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 7],
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '+' => [0, 2, 7, 2, 0],
+        '=' => [0, 7, 0, 7, 0],
+        '/' => [1, 1, 2, 4, 4],
+        '%' => [5, 1, 2, 4, 5],
+        '(' => [1, 2, 2, 2, 1],
+        ')' => [4, 2, 2, 2, 4],
+        _   => [0, 0, 0, 0, 0],
+    }
+}
+
+// Draws `text' in the canvas's current draw colour, with its top left corner
+// at (x, y) and each pixel of the font drawn `scale' pixels square
+pub fn draw_text(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, scale: u32) {
+    let advance = ((GLYPH_WIDTH + 1) * scale) as i32;
+
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i as i32 * advance;
+
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0 .. GLYPH_WIDTH {
+                if bits & (4 >> col) == 0 {
+                    continue;
+                }
+
+                let rect = Rect::new(left + (col * scale) as i32,
+                                     y + (row as u32 * scale) as i32,
+                                     scale,
+                                     scale);
+
+                canvas.fill_rect(rect).unwrap();
+            }
+        }
+    }
+}
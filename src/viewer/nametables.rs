@@ -0,0 +1,113 @@
+// A debug window showing all four logical nametables, with the part of them
+// that's currently on screen outlined.
+//
+// Hovering over a tile shows everything about it in the window's title bar.
+
+use crate::ppu::PPU;
+
+use sdl2::VideoSubsystem;
+use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const WIDTH: u32 = 512;
+const HEIGHT: u32 = 480;
+
+const TITLE: &str = "nametables";
+
+pub struct NametableViewer {
+    canvas: Canvas<Window>,
+
+    // The position of the mouse, when it's over the window
+    cursor: Option<(i32, i32)>,
+}
+
+impl NametableViewer {
+    pub fn new_nametable_viewer(video_subsystem: &VideoSubsystem) -> Self {
+        let window = video_subsystem.window(TITLE, WIDTH, HEIGHT)
+            .build()
+            .unwrap();
+
+        let canvas = window.into_canvas()
+            .build()
+            .unwrap();
+
+        Self {
+            canvas: canvas,
+            cursor: None,
+        }
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn mouse_moved(&mut self, x: i32, y: i32) {
+        self.cursor = Some((x, y));
+    }
+
+    pub fn mouse_left(&mut self) {
+        self.cursor = None;
+        self.canvas.window_mut().set_title(TITLE).unwrap();
+    }
+
+    pub fn render(&mut self, ppu: &mut PPU) {
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, WIDTH, HEIGHT)
+            .unwrap();
+
+        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            ppu.render_nametables(buffer, pitch);
+        }).unwrap();
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).unwrap();
+
+        // The screen wraps around the edges of the nametables, so the
+        // viewport is drawn again one nametable width and/or height over, to
+        // show the part of it that has wrapped.
+        let (x, y) = ppu.scroll_position();
+        let (x, y) = (x as i32, y as i32);
+        let (w, h) = (WIDTH as i32, HEIGHT as i32);
+
+        self.canvas.set_draw_color(Color::RGB(255, 0, 255));
+        for (dx, dy) in [(0, 0), (-w, 0), (0, -h), (-w, -h)].iter() {
+            let rect = Rect::new(x + dx, y + dy, 256, 240);
+            self.canvas.draw_rect(rect).unwrap();
+        }
+
+        if let Some((x, y)) = self.cursor {
+            if x >= 0 && x < w && y >= 0 && y < h {
+                let tile = ppu.nametable_tile(x as usize, y as usize);
+
+                let rect = Rect::new(
+                    (tile.nametable % 2 * 256 + tile.x * 8) as i32,
+                    (tile.nametable / 2 * 240 + tile.y * 8) as i32,
+                    8, 8
+                );
+                self.canvas.set_draw_color(Color::RGB(255, 255, 0));
+                self.canvas.draw_rect(rect).unwrap();
+
+                let title = format!(
+                    "{} - nametable {} ({}, {}) at ${:04X}: tile ${:02X}, \
+                     attribute ${:02X} at ${:04X}, palette {}, pattern ${:04X}",
+                    TITLE,
+                    tile.nametable,
+                    tile.x,
+                    tile.y,
+                    tile.address,
+                    tile.tile,
+                    tile.attribute,
+                    tile.attribute_address,
+                    tile.palette,
+                    tile.pattern_address,
+                );
+                self.canvas.window_mut().set_title(&title).unwrap();
+            }
+        }
+
+        self.canvas.present();
+    }
+}
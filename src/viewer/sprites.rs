@@ -0,0 +1,175 @@
+// A debug window showing all 64 sprites in OAM, in a grid of 8x8, in order.
+//
+// Sprites that were dropped from a scanline because of the sprite limit are
+// outlined in red. Hovering over a sprite shows everything about it in the
+// window's title bar, and while the window is open, sprites are outlined on
+// the game screen too.
+
+use crate::ppu::PPU;
+
+use sdl2::VideoSubsystem;
+use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+// Each cell has room for an 8x16 sprite, with a pixel of space around it
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 18;
+
+const COLUMNS: usize = 8;
+const ROWS: usize = 8;
+
+const SCALE: u32 = 4;
+
+const TITLE: &str = "sprites";
+
+const BACKGROUND: Color = Color { r: 40, g: 40, b: 40, a: 0xff };
+
+pub struct SpriteViewer {
+    canvas: Canvas<Window>,
+
+    // The sprite under the mouse
+    hovered: Option<usize>,
+}
+
+impl SpriteViewer {
+    pub fn new_sprite_viewer(video_subsystem: &VideoSubsystem) -> Self {
+        let width = (COLUMNS * CELL_WIDTH) as u32 * SCALE;
+        let height = (ROWS * CELL_HEIGHT) as u32 * SCALE;
+
+        let window = video_subsystem.window(TITLE, width, height)
+            .build()
+            .unwrap();
+
+        let canvas = window.into_canvas()
+            .build()
+            .unwrap();
+
+        Self {
+            canvas: canvas,
+            hovered: None,
+        }
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn mouse_moved(&mut self, x: i32, y: i32) {
+        let col = x as usize / (CELL_WIDTH * SCALE as usize);
+        let row = y as usize / (CELL_HEIGHT * SCALE as usize);
+
+        self.hovered = if x >= 0 && y >= 0 && col < COLUMNS && row < ROWS {
+            Some(row * COLUMNS + col)
+        } else {
+            None
+        };
+    }
+
+    pub fn mouse_left(&mut self) {
+        self.hovered = None;
+        self.canvas.window_mut().set_title(TITLE).unwrap();
+    }
+
+    fn cell_rect(index: usize) -> Rect {
+        let x = (index % COLUMNS * CELL_WIDTH) as i32;
+        let y = (index / COLUMNS * CELL_HEIGHT) as i32;
+
+        Rect::new(x * SCALE as i32,
+                  y * SCALE as i32,
+                  CELL_WIDTH as u32 * SCALE,
+                  CELL_HEIGHT as u32 * SCALE)
+    }
+
+    pub fn render(&mut self, ppu: &mut PPU) {
+        let width = (COLUMNS * CELL_WIDTH) as u32;
+        let height = (ROWS * CELL_HEIGHT) as u32;
+
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+            .unwrap();
+
+        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for offset in (0 .. buffer.len()).step_by(3) {
+                buffer[offset]     = BACKGROUND.r;
+                buffer[offset + 1] = BACKGROUND.g;
+                buffer[offset + 2] = BACKGROUND.b;
+            }
+
+            for index in 0 .. 64 {
+                let x = index % COLUMNS * CELL_WIDTH + 1;
+                let y = index / COLUMNS * CELL_HEIGHT + 1;
+                ppu.render_sprite(index, buffer, pitch, x, y);
+            }
+        }).unwrap();
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).unwrap();
+
+        self.canvas.set_draw_color(Color::RGB(255, 0, 0));
+        for index in 0 .. 64 {
+            if ppu.sprite(index).dropped {
+                self.canvas.draw_rect(Self::cell_rect(index)).unwrap();
+            }
+        }
+
+        if let Some(index) = self.hovered {
+            self.canvas.set_draw_color(Color::RGB(255, 255, 0));
+            self.canvas.draw_rect(Self::cell_rect(index)).unwrap();
+
+            let sprite = ppu.sprite(index);
+            let title = format!(
+                "{} - sprite {} at ${:02X}: ({}, {}), tile ${:02X}, \
+                 attributes ${:02X}, palette {}{}{}{}{}",
+                TITLE,
+                sprite.index,
+                sprite.index * 4,
+                sprite.x,
+                sprite.y,
+                sprite.tile,
+                sprite.attributes,
+                sprite.palette,
+                if sprite.flip_horizontal { ", flipped horizontally" } else { "" },
+                if sprite.flip_vertical { ", flipped vertically" } else { "" },
+                if sprite.behind_background { ", behind background" } else { "" },
+                if sprite.dropped { ", dropped" } else { "" },
+            );
+            self.canvas.window_mut().set_title(&title).unwrap();
+        }
+
+        self.canvas.present();
+    }
+
+    // Outlines every sprite that's on screen in the main window, which is
+    // drawn at `scale' times the size of the NES screen
+    pub fn highlight_sprites(&self, canvas: &mut Canvas<Window>, ppu: &mut PPU, scale: u32) {
+        let height = ppu.sprite_height() as u32;
+
+        for index in 0 .. 64 {
+            let sprite = ppu.sprite(index);
+
+            // Sprites are hidden by moving them below the bottom of the screen
+            if sprite.y >= 240 {
+                continue;
+            }
+
+            let color = if self.hovered == Some(index) {
+                Color::RGB(255, 255, 0)
+            } else if sprite.dropped {
+                Color::RGB(255, 0, 0)
+            } else {
+                Color::RGB(0, 255, 255)
+            };
+
+            let rect = Rect::new(sprite.x as i32 * scale as i32,
+                                 sprite.y as i32 * scale as i32,
+                                 8 * scale,
+                                 height * scale);
+
+            canvas.set_draw_color(color);
+            canvas.draw_rect(rect).unwrap();
+        }
+    }
+}